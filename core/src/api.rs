@@ -0,0 +1,473 @@
+use log::info;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// dashboard page, compiled into flash
+const INDEX_HTML: &str = include_str!("../../web/index.html");
+
+#[derive(Deserialize, Debug)]
+pub struct ApiConfig {
+    #[serde(default = "ApiConfig::default_port")]
+    pub port: u16,
+    /// bearer tokens, every request to /api needs one
+    pub tokens: Vec<ApiToken>,
+}
+
+impl ApiConfig {
+    fn default_port() -> u16 {
+        80
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApiToken {
+    /// who holds the token, for the log
+    pub name: String,
+    pub token: String,
+    /// same rights as a Telegram admin, e.g. "force"
+    #[serde(default)]
+    pub admin: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiMethod {
+    Get,
+    Post,
+    Other,
+}
+
+#[derive(Debug)]
+pub struct ApiRequest {
+    pub method: ApiMethod,
+    pub path: String,
+    /// value of the Authorization header
+    pub authorization: Option<String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct ApiResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl ApiResponse {
+    fn json(status: u16, value: Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        Self::json(status, json!({ "error": message }))
+    }
+}
+
+/// what the API can see and do on the device
+pub trait ApiBackend {
+    /// state of every relay
+    fn relays(&self) -> Value;
+    /// latest reading of every sensor by name
+    fn sensors(&self) -> Value;
+    /// messages waiting in the queue
+    fn queue_len(&self) -> usize;
+    /// uptime, sensors and queue
+    fn status(&self) -> Value;
+    /// run `args` as in `/relay [name] [args]`, returns the relay status
+    fn command(&mut self, relay: &str, args: &str, is_admin: bool) -> anyhow::Result<String>;
+}
+
+/// body of `POST /api/relays/{name}`
+#[derive(Deserialize, Debug)]
+struct RelayCommand {
+    /// "on" or "off"
+    state: String,
+    /// e.g. "30m", or "500l" on a relay with a flow meter
+    #[serde(default)]
+    duration: Option<String>,
+    #[serde(default)]
+    force: bool,
+}
+
+impl RelayCommand {
+    /// arguments of the matching `/relay` command
+    fn args(&self) -> Result<String, &'static str> {
+        let mut args = match self.state.as_str() {
+            "on" => String::from("on"),
+            "off" if self.duration.is_none() => String::from("off"),
+            "off" => return Err("duration only applies to \"on\""),
+            _ => return Err("state must be \"on\" or \"off\""),
+        };
+        if let Some(duration) = &self.duration {
+            if duration.split_whitespace().count() != 1 {
+                return Err("invalid duration");
+            }
+            args.push_str(" for ");
+            args.push_str(duration);
+        }
+        if self.force {
+            args.push_str(" force");
+        }
+        Ok(args)
+    }
+}
+
+/// the page needs no token, every /api path does
+fn authorize<'t>(tokens: &'t [ApiToken], authorization: Option<&str>) -> Option<&'t ApiToken> {
+    find_token(tokens, authorization?.strip_prefix("Bearer ")?)
+}
+
+pub fn find_token<'t>(tokens: &'t [ApiToken], token: &str) -> Option<&'t ApiToken> {
+    let token = token.trim();
+    tokens
+        .iter()
+        .find(|t| !t.token.is_empty() && t.token == token)
+}
+
+/// Messages of the live stream. A snapshot of relays, sensors and queue
+/// goes out when it changed, or every `HEARTBEAT` seconds so clients can
+/// resync the countdowns to the end of running orders.
+#[derive(Default)]
+pub struct LiveStream {
+    last: Option<Value>,
+    sent_at: u64,
+}
+
+impl LiveStream {
+    const HEARTBEAT: u64 = 10;
+
+    /// send the next snapshot even when nothing changed
+    pub fn resync(&mut self) {
+        self.last = None;
+    }
+
+    pub fn update(&mut self, backend: &dyn ApiBackend, now: u64) -> Option<String> {
+        let snapshot = json!({
+            "relays": backend.relays(),
+            "sensors": backend.sensors(),
+            "queue": backend.queue_len(),
+        });
+        if self.last.as_ref() == Some(&snapshot) && now < self.sent_at + Self::HEARTBEAT {
+            return None;
+        }
+
+        let mut message = snapshot.clone();
+        message["now"] = json!(now);
+        if let Some(relays) = message["relays"].as_array_mut() {
+            for relay in relays {
+                if let Some(end_at) = relay["end_at"].as_u64() {
+                    relay["remaining"] = json!(end_at.saturating_sub(now));
+                }
+            }
+        }
+        self.last = Some(snapshot);
+        self.sent_at = now;
+        Some(message.to_string())
+    }
+}
+
+/// Route a request of the local API. Nothing here touches the hardware,
+/// the server hands requests in and the main loop implements
+/// [`ApiBackend`], so routing and authorization run on any host.
+pub fn handle(req: &ApiRequest, tokens: &[ApiToken], backend: &mut dyn ApiBackend) -> ApiResponse {
+    let path = req.path.split('?').next().unwrap_or_default();
+    if path == "/" || path == "/index.html" {
+        return match req.method {
+            ApiMethod::Get => ApiResponse {
+                status: 200,
+                content_type: "text/html",
+                body: INDEX_HTML.as_bytes().to_vec(),
+            },
+            _ => ApiResponse::error(405, "method not allowed"),
+        };
+    }
+
+    let Some(route) = path.strip_prefix("/api/") else {
+        return ApiResponse::error(404, "not found");
+    };
+    let Some(token) = authorize(tokens, req.authorization.as_deref()) else {
+        return ApiResponse::error(401, "missing or unknown token");
+    };
+
+    let segments: Vec<&str> = route.trim_end_matches('/').split('/').collect();
+    match (req.method, segments.as_slice()) {
+        (ApiMethod::Get, ["status"]) => ApiResponse::json(200, backend.status()),
+        (ApiMethod::Get, ["relays"]) => ApiResponse::json(200, backend.relays()),
+        (ApiMethod::Post, ["relays", name]) => {
+            let command: RelayCommand = match serde_json::from_slice(&req.body) {
+                Ok(c) => c,
+                Err(err) => return ApiResponse::error(400, &err.to_string()),
+            };
+            if command.force && !token.admin {
+                return ApiResponse::error(403, "force is only for admins");
+            }
+            let args = match command.args() {
+                Ok(args) => args,
+                Err(msg) => return ApiResponse::error(400, msg),
+            };
+
+            info!("api {} relay {} {}", token.name, name, args);
+            match backend.command(name, &args, token.admin) {
+                Ok(status) => ApiResponse::json(200, json!({ "status": status })),
+                Err(err) => ApiResponse::error(409, &err.to_string()),
+            }
+        }
+        (_, ["status"] | ["relays"] | ["relays", _]) => {
+            ApiResponse::error(405, "method not allowed")
+        }
+        _ => ApiResponse::error(404, "not found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// backend recording the commands it was handed
+    #[derive(Default)]
+    struct FakeBackend {
+        commands: Vec<(String, String, bool)>,
+        reject: bool,
+    }
+
+    impl ApiBackend for FakeBackend {
+        fn relays(&self) -> Value {
+            json!([{ "name": "pompa_air", "on": false }])
+        }
+
+        fn sensors(&self) -> Value {
+            json!({ "kolam": 27.5 })
+        }
+
+        fn queue_len(&self) -> usize {
+            2
+        }
+
+        fn status(&self) -> Value {
+            json!({ "uptime": 60 })
+        }
+
+        fn command(&mut self, relay: &str, args: &str, is_admin: bool) -> anyhow::Result<String> {
+            self.commands
+                .push((relay.to_owned(), args.to_owned(), is_admin));
+            match self.reject {
+                true => Err(anyhow::Error::msg("relay pompa_air is resting")),
+                false => Ok(format!("{} {}", relay, args)),
+            }
+        }
+    }
+
+    fn tokens() -> Vec<ApiToken> {
+        vec![
+            ApiToken {
+                name: String::from("staff"),
+                token: String::from("staff-token"),
+                admin: false,
+            },
+            ApiToken {
+                name: String::from("owner"),
+                token: String::from("owner-token"),
+                admin: true,
+            },
+        ]
+    }
+
+    fn request(method: ApiMethod, path: &str, token: Option<&str>, body: &str) -> ApiRequest {
+        ApiRequest {
+            method,
+            path: path.to_owned(),
+            authorization: token.map(|t| format!("Bearer {}", t)),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn body(res: &ApiResponse) -> Value {
+        serde_json::from_slice(&res.body).unwrap()
+    }
+
+    #[test]
+    fn authorize_tokens() {
+        let tokens = tokens();
+        let found = |header: Option<&str>| authorize(&tokens, header).map(|t| t.name.as_str());
+        assert_eq!(found(Some("Bearer staff-token")), Some("staff"));
+        assert_eq!(found(Some("Bearer owner-token ")), Some("owner"));
+        assert_eq!(found(Some("staff-token")), None);
+        assert_eq!(found(Some("Bearer other")), None);
+        assert_eq!(found(Some("Bearer ")), None);
+        assert_eq!(found(None), None);
+
+        let empty = [ApiToken {
+            name: String::from("empty"),
+            token: String::new(),
+            admin: true,
+        }];
+        assert!(authorize(&empty, Some("Bearer ")).is_none());
+    }
+
+    #[test]
+    fn page_needs_no_token() {
+        let mut backend = FakeBackend::default();
+        let res = handle(
+            &request(ApiMethod::Get, "/", None, ""),
+            &tokens(),
+            &mut backend,
+        );
+        assert_eq!((res.status, res.content_type), (200, "text/html"));
+
+        let res = handle(
+            &request(ApiMethod::Post, "/", None, ""),
+            &tokens(),
+            &mut backend,
+        );
+        assert_eq!(res.status, 405);
+    }
+
+    #[test]
+    fn api_needs_token() {
+        let mut backend = FakeBackend::default();
+        for token in [None, Some("wrong")] {
+            let req = request(ApiMethod::Get, "/api/status", token, "");
+            assert_eq!(handle(&req, &tokens(), &mut backend).status, 401);
+        }
+        let req = request(
+            ApiMethod::Post,
+            "/api/relays/pompa_air",
+            None,
+            r#"{"state":"on"}"#,
+        );
+        assert_eq!(handle(&req, &tokens(), &mut backend).status, 401);
+        assert!(backend.commands.is_empty());
+    }
+
+    #[test]
+    fn get_routes() {
+        let mut backend = FakeBackend::default();
+        let token = Some("staff-token");
+
+        let res = handle(
+            &request(ApiMethod::Get, "/api/status", token, ""),
+            &tokens(),
+            &mut backend,
+        );
+        assert_eq!(res.status, 200);
+        assert_eq!(body(&res), json!({ "uptime": 60 }));
+
+        let req = request(ApiMethod::Get, "/api/relays/?all", token, "");
+        let res = handle(&req, &tokens(), &mut backend);
+        assert_eq!(res.status, 200);
+        assert_eq!(body(&res)[0]["name"], "pompa_air");
+
+        let res = handle(
+            &request(ApiMethod::Get, "/api/other", token, ""),
+            &tokens(),
+            &mut backend,
+        );
+        assert_eq!(res.status, 404);
+        let res = handle(
+            &request(ApiMethod::Post, "/api/status", token, ""),
+            &tokens(),
+            &mut backend,
+        );
+        assert_eq!(res.status, 405);
+        let res = handle(
+            &request(ApiMethod::Get, "/other", token, ""),
+            &tokens(),
+            &mut backend,
+        );
+        assert_eq!(res.status, 404);
+    }
+
+    #[test]
+    fn relay_commands() {
+        let mut backend = FakeBackend::default();
+        let post = |backend: &mut FakeBackend, token: &str, body: &str| {
+            let req = request(ApiMethod::Post, "/api/relays/pompa_air", Some(token), body);
+            handle(&req, &tokens(), backend)
+        };
+
+        let res = post(
+            &mut backend,
+            "staff-token",
+            r#"{"state":"on","duration":"30m"}"#,
+        );
+        assert_eq!(res.status, 200);
+        assert_eq!(body(&res)["status"], "pompa_air on for 30m");
+
+        let res = post(
+            &mut backend,
+            "owner-token",
+            r#"{"state":"off","force":true}"#,
+        );
+        assert_eq!(res.status, 200);
+        assert_eq!(
+            backend.commands,
+            [
+                (String::from("pompa_air"), String::from("on for 30m"), false),
+                (String::from("pompa_air"), String::from("off force"), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn relay_command_errors() {
+        let mut backend = FakeBackend::default();
+        let post = |backend: &mut FakeBackend, token: &str, body: &str| {
+            let req = request(ApiMethod::Post, "/api/relays/pompa_air", Some(token), body);
+            handle(&req, &tokens(), backend).status
+        };
+
+        assert_eq!(
+            post(
+                &mut backend,
+                "staff-token",
+                r#"{"state":"on","force":true}"#
+            ),
+            403
+        );
+        assert_eq!(post(&mut backend, "staff-token", "not json"), 400);
+        assert_eq!(
+            post(&mut backend, "staff-token", r#"{"state":"toggle"}"#),
+            400
+        );
+        assert_eq!(
+            post(
+                &mut backend,
+                "staff-token",
+                r#"{"state":"off","duration":"5m"}"#
+            ),
+            400
+        );
+        assert_eq!(
+            post(
+                &mut backend,
+                "staff-token",
+                r#"{"state":"on","duration":"5m force"}"#
+            ),
+            400
+        );
+        assert!(backend.commands.is_empty());
+
+        backend.reject = true;
+        assert_eq!(post(&mut backend, "staff-token", r#"{"state":"on"}"#), 409);
+    }
+
+    #[test]
+    fn live_stream() {
+        let backend = FakeBackend::default();
+        let mut stream = LiveStream::default();
+
+        let first: Value = serde_json::from_str(&stream.update(&backend, 100).unwrap()).unwrap();
+        assert_eq!(first["queue"], 2);
+        assert_eq!(first["now"], 100);
+
+        // unchanged until the heartbeat
+        assert!(stream.update(&backend, 105).is_none());
+        assert!(stream.update(&backend, 110).is_some());
+
+        stream.resync();
+        assert!(stream.update(&backend, 111).is_some());
+    }
+}
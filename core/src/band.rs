@@ -0,0 +1,79 @@
+/// alert band of a sensor reading
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Band {
+    Normal,
+    Low,
+    High,
+}
+
+/// Band of `value`, leaving a tripped band needs `hysteresis` degrees
+/// back inside so a reading around a threshold does not flood alerts.
+pub fn classify(
+    value: f32,
+    previous: Band,
+    low: Option<f32>,
+    high: Option<f32>,
+    hysteresis: f32,
+) -> Band {
+    let above = |limit: Option<f32>, margin: f32| limit.is_some_and(|l| value > l - margin);
+    let below = |limit: Option<f32>, margin: f32| limit.is_some_and(|l| value < l + margin);
+
+    match previous {
+        Band::High if above(high, hysteresis) => Band::High,
+        Band::Low if below(low, hysteresis) => Band::Low,
+        _ if above(high, 0.0) => Band::High,
+        _ if below(low, 0.0) => Band::Low,
+        _ => Band::Normal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// bands of consecutive readings against 20 - 30 with 0.5 hysteresis
+    fn run(readings: &[f32]) -> Vec<Band> {
+        let mut band = Band::Normal;
+        readings
+            .iter()
+            .map(|v| {
+                band = classify(*v, band, Some(20.0), Some(30.0), 0.5);
+                band
+            })
+            .collect()
+    }
+
+    #[test]
+    fn thresholds() {
+        use Band::*;
+        assert_eq!(run(&[25.0, 30.0, 30.1, 19.9]), [Normal, Normal, High, Low]);
+    }
+
+    #[test]
+    fn hysteresis_around_high() {
+        use Band::*;
+        assert_eq!(
+            run(&[30.2, 29.8, 30.1, 29.6, 29.4, 29.9]),
+            [High, High, High, High, Normal, Normal]
+        );
+    }
+
+    #[test]
+    fn hysteresis_around_low() {
+        use Band::*;
+        assert_eq!(run(&[19.5, 20.3, 20.6, 20.1]), [Low, Low, Normal, Normal]);
+    }
+
+    #[test]
+    fn missing_limits() {
+        assert_eq!(
+            classify(99.0, Band::Normal, Some(20.0), None, 0.5),
+            Band::Normal
+        );
+        assert_eq!(
+            classify(-5.0, Band::Normal, None, Some(30.0), 0.5),
+            Band::Normal
+        );
+        assert_eq!(classify(-5.0, Band::Normal, None, None, 0.5), Band::Normal);
+    }
+}
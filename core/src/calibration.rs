@@ -0,0 +1,257 @@
+use anyhow::Error;
+use serde::Deserialize;
+use std::collections::VecDeque;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum FilterConfig {
+    #[default]
+    None,
+    /// exponential moving average, smaller alpha is smoother
+    Ema { alpha: f32 },
+    /// median of the last `window` samples, drops spikes
+    Median { window: usize },
+}
+
+pub struct Filter {
+    config: FilterConfig,
+    window: VecDeque<f32>,
+    ema: Option<f32>,
+}
+
+impl Filter {
+    pub fn new(config: FilterConfig) -> Self {
+        Self {
+            config,
+            window: VecDeque::new(),
+            ema: None,
+        }
+    }
+
+    /// feed a sample, returns the filtered value
+    pub fn push(&mut self, sample: f32) -> f32 {
+        match self.config {
+            FilterConfig::None => sample,
+            FilterConfig::Ema { alpha } => {
+                let alpha = alpha.clamp(0.0, 1.0);
+                let value = self
+                    .ema
+                    .map_or(sample, |prev| prev + alpha * (sample - prev));
+                *self.ema.insert(value)
+            }
+            FilterConfig::Median { window } => {
+                if self.window.len() >= window.max(1) {
+                    self.window.pop_front();
+                }
+                self.window.push_back(sample);
+
+                let mut sorted: Vec<f32> = self.window.iter().copied().collect();
+                sorted.sort_by(|a, b| a.total_cmp(b));
+                let mid = sorted.len() / 2;
+                if mid * 2 == sorted.len() {
+                    (sorted[mid - 1] + sorted[mid]) / 2.0
+                } else {
+                    sorted[mid]
+                }
+            }
+        }
+    }
+}
+
+/// Millivolt to unit conversion. Without points the configured linear
+/// `scale`/`offset` applies, one point shifts it, two or more points are
+/// interpolated piecewise and extrapolated from the outer segments.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Calibration {
+    /// (mv, value) sorted by mv
+    points: Vec<(f32, f32)>,
+}
+
+impl Calibration {
+    pub const MAX_POINTS: usize = 5;
+    const VERSION: u8 = 1;
+
+    pub fn points(&self) -> &[(f32, f32)] {
+        &self.points
+    }
+
+    /// add a reference point, replacing one with the same value
+    pub fn add_point(&mut self, mv: f32, value: f32) -> anyhow::Result<()> {
+        self.points.retain(|(_, v)| *v != value);
+        if self.points.len() >= Self::MAX_POINTS {
+            return Err(Error::msg("too many calibration points, reset first"));
+        }
+        if self.points.iter().any(|(m, _)| (m - mv).abs() < 1.0) {
+            return Err(Error::msg(
+                "reading equals another calibration point, check the probe",
+            ));
+        }
+
+        self.points.push((mv, value));
+        self.points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(())
+    }
+
+    pub fn apply(&self, mv: f32, scale: f32, offset: f32) -> f32 {
+        match self.points.as_slice() {
+            [] => mv * scale + offset,
+            [(m, v)] => v + (mv - m) * scale,
+            points => {
+                // segment containing mv, the outer ones extend to infinity
+                let i = points
+                    .windows(2)
+                    .position(|w| mv < w[1].0)
+                    .unwrap_or(points.len() - 2);
+                let ((m0, v0), (m1, v1)) = (points[i], points[i + 1]);
+                v0 + (mv - m0) * (v1 - v0) / (m1 - m0)
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 + self.points.len() * 8);
+        bytes.push(Self::VERSION);
+        bytes.push(self.points.len() as u8);
+        for (mv, value) in &self.points {
+            bytes.extend_from_slice(&mv.to_be_bytes());
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (&[version, count], rest) = bytes.split_first_chunk::<2>()?;
+        if version != Self::VERSION || rest.len() != count as usize * 8 {
+            return None;
+        }
+
+        let f = |b: &[u8]| f32::from_be_bytes(b.try_into().unwrap());
+        let points = rest
+            .chunks_exact(8)
+            .map(|c| (f(&c[..4]), f(&c[4..])))
+            .collect();
+        Some(Self { points })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn approx(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    fn calibration(points: &[(f32, f32)]) -> Calibration {
+        let mut cal = Calibration::default();
+        for (mv, value) in points {
+            cal.add_point(*mv, *value).unwrap();
+        }
+        cal
+    }
+
+    #[test]
+    fn apply_linear() {
+        let cal = Calibration::default();
+        assert!(approx(cal.apply(1000.0, 0.01, 2.0), 12.0));
+    }
+
+    #[test]
+    fn apply_one_point_shifts() {
+        let cal = calibration(&[(1500.0, 7.0)]);
+        assert!(approx(cal.apply(1500.0, -0.0059, 0.0), 7.0));
+        assert!(approx(cal.apply(1600.0, -0.01, 0.0), 6.0));
+    }
+
+    #[test]
+    fn apply_two_points() {
+        // pH 4 at 2000 mV, pH 7 at 1500 mV
+        let cal = calibration(&[(2000.0, 4.0), (1500.0, 7.0)]);
+        assert!(approx(cal.apply(1750.0, 1.0, 0.0), 5.5));
+        // extrapolated from the only segment
+        assert!(approx(cal.apply(1000.0, 1.0, 0.0), 10.0));
+        assert!(approx(cal.apply(2500.0, 1.0, 0.0), 1.0));
+    }
+
+    #[test]
+    fn apply_piecewise() {
+        let cal = calibration(&[(0.0, 0.0), (1000.0, 10.0), (2000.0, 30.0)]);
+        assert!(approx(cal.apply(500.0, 1.0, 0.0), 5.0));
+        assert!(approx(cal.apply(1500.0, 1.0, 0.0), 20.0));
+        assert!(approx(cal.apply(2500.0, 1.0, 0.0), 40.0));
+        assert!(approx(cal.apply(-500.0, 1.0, 0.0), -5.0));
+    }
+
+    #[test]
+    fn add_point_sorts_and_replaces() {
+        let mut cal = calibration(&[(2000.0, 4.0), (1500.0, 7.0)]);
+        assert_eq!(cal.points(), [(1500.0, 7.0), (2000.0, 4.0)]);
+
+        cal.add_point(1480.0, 7.0).unwrap();
+        assert_eq!(cal.points(), [(1480.0, 7.0), (2000.0, 4.0)]);
+    }
+
+    #[test]
+    fn add_point_rejects() {
+        let mut cal = calibration(&[(1500.0, 7.0)]);
+        assert!(cal.add_point(1500.5, 4.0).is_err());
+
+        let mut cal = calibration(&[
+            (100.0, 1.0),
+            (200.0, 2.0),
+            (300.0, 3.0),
+            (400.0, 4.0),
+            (500.0, 5.0),
+        ]);
+        assert!(cal.add_point(600.0, 6.0).is_err());
+        // replacing a value stays within the limit
+        cal.add_point(550.0, 5.0).unwrap();
+        assert_eq!(cal.points().len(), Calibration::MAX_POINTS);
+    }
+
+    #[test]
+    fn bytes_roundtrip() {
+        let cal = calibration(&[(1500.0, 7.0), (2000.0, 4.0)]);
+        assert_eq!(Calibration::from_bytes(&cal.to_bytes()), Some(cal));
+        assert_eq!(
+            Calibration::from_bytes(&Calibration::default().to_bytes()),
+            Some(Calibration::default())
+        );
+
+        let mut bytes = calibration(&[(1500.0, 7.0)]).to_bytes();
+        bytes.pop();
+        assert_eq!(Calibration::from_bytes(&bytes), None);
+        assert_eq!(Calibration::from_bytes(&[2, 0]), None);
+        assert_eq!(Calibration::from_bytes(&[]), None);
+    }
+
+    #[test]
+    fn filter_none() {
+        let mut filter = Filter::new(FilterConfig::None);
+        assert_eq!(filter.push(3.0), 3.0);
+        assert_eq!(filter.push(-1.0), -1.0);
+    }
+
+    #[test]
+    fn filter_ema() {
+        let mut filter = Filter::new(FilterConfig::Ema { alpha: 0.5 });
+        assert!(approx(filter.push(10.0), 10.0));
+        assert!(approx(filter.push(20.0), 15.0));
+        assert!(approx(filter.push(20.0), 17.5));
+
+        let mut raw = Filter::new(FilterConfig::Ema { alpha: 1.0 });
+        raw.push(10.0);
+        assert!(approx(raw.push(20.0), 20.0));
+    }
+
+    #[test]
+    fn filter_median() {
+        let mut filter = Filter::new(FilterConfig::Median { window: 3 });
+        assert_eq!(filter.push(7.0), 7.0);
+        assert_eq!(filter.push(9.0), 8.0);
+        // the spike is dropped
+        assert_eq!(filter.push(100.0), 9.0);
+        assert_eq!(filter.push(7.5), 9.0);
+        assert_eq!(filter.push(7.2), 7.5);
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::Error;
+
+/// Minimal 1-Wire master. One probe per bus, so ROM addressing is skipped.
+pub trait OneWireBus {
+    /// reset pulse, true when a device answers with presence
+    fn reset(&mut self) -> anyhow::Result<bool>;
+    fn write_byte(&mut self, byte: u8) -> anyhow::Result<()>;
+    fn read_byte(&mut self) -> anyhow::Result<u8>;
+}
+
+const SKIP_ROM: u8 = 0xCC;
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xBE;
+/// temperature register after power on, no conversion has run
+const POWER_ON_RAW: i16 = 0x0550;
+
+/// Dallas/Maxim CRC-8 as used by the DS18B20 scratchpad
+pub fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |mut crc, &byte| {
+        let mut b = byte;
+        for _ in 0..8 {
+            let mix = (crc ^ b) & 0x01;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8C;
+            }
+            b >>= 1;
+        }
+        crc
+    })
+}
+
+/// Celsius from the 9 scratchpad bytes of a DS18B20 at 12 bit resolution.
+pub fn decode_scratchpad(data: &[u8; 9]) -> anyhow::Result<f32> {
+    if data.iter().all(|b| *b == 0xFF) {
+        return Err(Error::msg("no answer on the bus"));
+    }
+    if crc8(&data[..8]) != data[8] {
+        return Err(Error::msg("scratchpad crc mismatch"));
+    }
+
+    let raw = i16::from_le_bytes([data[0], data[1]]);
+    if raw == POWER_ON_RAW {
+        return Err(Error::msg("conversion did not run"));
+    }
+    Ok(raw as f32 / 16.0)
+}
+
+/// DS18B20 alone on its bus
+pub struct Ds18b20<B: OneWireBus> {
+    bus: B,
+}
+
+impl<B: OneWireBus> Ds18b20<B> {
+    pub fn new(bus: B) -> Self {
+        Self { bus }
+    }
+
+    fn command(&mut self, cmd: u8) -> anyhow::Result<()> {
+        if !self.bus.reset()? {
+            return Err(Error::msg("no presence pulse"));
+        }
+        self.bus.write_byte(SKIP_ROM)?;
+        self.bus.write_byte(cmd)
+    }
+
+    /// start a conversion, the result is ready after 750 ms
+    pub fn start_conversion(&mut self) -> anyhow::Result<()> {
+        self.command(CONVERT_T)
+    }
+
+    pub fn read(&mut self) -> anyhow::Result<f32> {
+        self.command(READ_SCRATCHPAD)?;
+        let mut data = [0u8; 9];
+        for byte in data.iter_mut() {
+            *byte = self.bus.read_byte()?;
+        }
+        decode_scratchpad(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// scratchpad of `raw` with a valid crc
+    fn scratchpad(raw: i16) -> [u8; 9] {
+        let [lsb, msb] = raw.to_le_bytes();
+        let mut data = [lsb, msb, 0x4B, 0x46, 0x7F, 0xFF, 0x0C, 0x10, 0];
+        data[8] = crc8(&data[..8]);
+        data
+    }
+
+    /// bus answering with queued bytes and recording the written ones
+    struct SimulatedBus {
+        present: bool,
+        written: Vec<u8>,
+        replies: VecDeque<u8>,
+    }
+
+    impl OneWireBus for SimulatedBus {
+        fn reset(&mut self) -> anyhow::Result<bool> {
+            Ok(self.present)
+        }
+
+        fn write_byte(&mut self, byte: u8) -> anyhow::Result<()> {
+            self.written.push(byte);
+            Ok(())
+        }
+
+        fn read_byte(&mut self) -> anyhow::Result<u8> {
+            Ok(self.replies.pop_front().unwrap_or(0xFF))
+        }
+    }
+
+    #[test]
+    fn crc8_check_value() {
+        assert_eq!(crc8(b"123456789"), 0xA1);
+        assert_eq!(crc8(&[]), 0);
+    }
+
+    #[test]
+    fn decode_readings() {
+        assert_eq!(decode_scratchpad(&scratchpad(0x0191)).unwrap(), 25.0625);
+        assert_eq!(decode_scratchpad(&scratchpad(0x0000)).unwrap(), 0.0);
+        assert_eq!(decode_scratchpad(&scratchpad(-0x00A2)).unwrap(), -10.125);
+        assert_eq!(decode_scratchpad(&scratchpad(0x07D0)).unwrap(), 125.0);
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert!(decode_scratchpad(&[0xFF; 9]).is_err());
+        assert!(decode_scratchpad(&scratchpad(POWER_ON_RAW)).is_err());
+
+        let mut corrupted = scratchpad(0x0191);
+        corrupted[0] ^= 0x01;
+        assert!(decode_scratchpad(&corrupted).is_err());
+    }
+
+    #[test]
+    fn read_over_bus() {
+        let mut sensor = Ds18b20::new(SimulatedBus {
+            present: true,
+            written: Vec::new(),
+            replies: scratchpad(0x0191).into_iter().collect(),
+        });
+        sensor.start_conversion().unwrap();
+        assert_eq!(sensor.read().unwrap(), 25.0625);
+        assert_eq!(
+            sensor.bus.written,
+            [SKIP_ROM, CONVERT_T, SKIP_ROM, READ_SCRATCHPAD]
+        );
+    }
+
+    #[test]
+    fn missing_probe() {
+        let mut sensor = Ds18b20::new(SimulatedBus {
+            present: false,
+            written: Vec::new(),
+            replies: VecDeque::new(),
+        });
+        assert!(sensor.start_conversion().is_err());
+        assert!(sensor.read().is_err());
+    }
+}
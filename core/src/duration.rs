@@ -0,0 +1,44 @@
+use anyhow::Error;
+
+pub const INVALID_UNIT: &str = "Invalid unit, example: 1h (one hours) or 500l (litres)";
+
+/// parse `5s`, `30m`, `2h` or `7d` into seconds
+pub fn parse_duration(dur_str: &str) -> anyhow::Result<u32> {
+    if dur_str.len() < 2 {
+        return Err(Error::msg(INVALID_UNIT));
+    }
+
+    // an ASCII unit, so the number ends on a character boundary
+    let mul = match dur_str.as_bytes()[dur_str.len() - 1] {
+        b's' => 1,
+        b'm' => 60,
+        b'h' => 3600,
+        b'd' => 86400,
+        _ => return Err(Error::msg(INVALID_UNIT)),
+    };
+    let dur = &dur_str[..dur_str.len() - 1];
+
+    let duration = dur.parse::<u32>().map_err(|_| Error::msg(INVALID_UNIT))?;
+
+    duration.checked_mul(mul).ok_or(Error::msg(INVALID_UNIT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units() {
+        assert_eq!(parse_duration("5s").unwrap(), 5);
+        assert_eq!(parse_duration("30m").unwrap(), 1800);
+        assert_eq!(parse_duration("2h").unwrap(), 7200);
+        assert_eq!(parse_duration("7d").unwrap(), 604800);
+    }
+
+    #[test]
+    fn invalid() {
+        for s in ["", "5", "h", "5x", "-5m", "1.5h", "50000d", "éh", "5é"] {
+            assert!(parse_duration(s).is_err(), "{}", s);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use serde::Deserialize;
+use std::fmt::Display;
+
+/// Reads whether the load behind a relay is actually powered.
+pub trait FeedbackProbe {
+    fn is_on(&mut self) -> anyhow::Result<bool>;
+}
+
+/// Current sense output compared against a threshold. Generic over the
+/// sampling function so the comparison runs without an ADC.
+pub struct ThresholdProbe<F>
+where
+    F: FnMut() -> anyhow::Result<u16>,
+{
+    sample: F,
+    threshold_mv: u16,
+}
+
+impl<F> ThresholdProbe<F>
+where
+    F: FnMut() -> anyhow::Result<u16>,
+{
+    pub fn new(sample: F, threshold_mv: u16) -> Self {
+        Self {
+            sample,
+            threshold_mv,
+        }
+    }
+}
+
+impl<F> FeedbackProbe for ThresholdProbe<F>
+where
+    F: FnMut() -> anyhow::Result<u16>,
+{
+    fn is_on(&mut self) -> anyhow::Result<bool> {
+        Ok((self.sample)()? >= self.threshold_mv)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// relay on but the load is not running
+    LoadOff,
+    /// relay off but the load is still running
+    LoadOn,
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Fault::LoadOff => "load is off while relay on (blown fuse or no power?)",
+            Fault::LoadOn => "load is on while relay off (welded contact?)",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum FeedbackConfig {
+    /// auxiliary contact on a digital input
+    Input {
+        pin: u8,
+        #[serde(default)]
+        active_low: bool,
+        #[serde(default = "FeedbackConfig::default_settle")]
+        settle: u32,
+    },
+    /// current sense voltage on an ADC1 pin
+    Current {
+        pin: u8,
+        threshold_mv: u16,
+        #[serde(default = "FeedbackConfig::default_settle")]
+        settle: u32,
+    },
+}
+
+impl FeedbackConfig {
+    fn default_settle() -> u32 {
+        3
+    }
+}
+
+/// Feedback of a relay, checked once the load had `settle` seconds
+/// after the last switch.
+pub struct Feedback<'d> {
+    probe: Box<dyn FeedbackProbe + 'd>,
+    settle: u64,
+}
+
+impl<'d> Feedback<'d> {
+    pub fn new(probe: Box<dyn FeedbackProbe + 'd>, settle: u32) -> Self {
+        Self {
+            probe,
+            settle: settle as u64,
+        }
+    }
+
+    /// the load had time to follow the last switch
+    #[inline]
+    pub fn settled(&self, since_switch: u64) -> bool {
+        since_switch >= self.settle
+    }
+
+    /// compare the load against the relay
+    pub fn verify(&mut self, energized: bool) -> anyhow::Result<Option<Fault>> {
+        let load_on = self.probe.is_on()?;
+        Ok(evaluate(energized, load_on))
+    }
+}
+
+/// fault of a relay driving `energized` while the load reads `load_on`
+pub fn evaluate(energized: bool, load_on: bool) -> Option<Fault> {
+    match (energized, load_on) {
+        (true, false) => Some(Fault::LoadOff),
+        (false, true) => Some(Fault::LoadOn),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Error;
+
+    /// probe replaying a fixed load state
+    struct FakeProbe(anyhow::Result<bool>);
+
+    impl FeedbackProbe for FakeProbe {
+        fn is_on(&mut self) -> anyhow::Result<bool> {
+            match &self.0 {
+                Ok(on) => Ok(*on),
+                Err(err) => Err(Error::msg(err.to_string())),
+            }
+        }
+    }
+
+    #[test]
+    fn evaluate_mismatch() {
+        assert_eq!(evaluate(true, true), None);
+        assert_eq!(evaluate(false, false), None);
+        assert_eq!(evaluate(true, false), Some(Fault::LoadOff));
+        assert_eq!(evaluate(false, true), Some(Fault::LoadOn));
+    }
+
+    #[test]
+    fn threshold_probe() {
+        let mut samples = [150, 200, 250].into_iter();
+        let mut probe = ThresholdProbe::new(move || Ok(samples.next().unwrap()), 200);
+        assert!(!probe.is_on().unwrap());
+        assert!(probe.is_on().unwrap());
+        assert!(probe.is_on().unwrap());
+    }
+
+    #[test]
+    fn threshold_probe_error() {
+        let mut probe = ThresholdProbe::new(|| Err(Error::msg("adc timeout")), 200);
+        assert!(probe.is_on().is_err());
+    }
+
+    #[test]
+    fn settle_time() {
+        let feedback = Feedback::new(Box::new(FakeProbe(Ok(true))), 3);
+        assert!(!feedback.settled(2));
+        assert!(feedback.settled(3));
+    }
+
+    #[test]
+    fn verify_with_sampler() {
+        let mut current = Feedback::new(Box::new(ThresholdProbe::new(|| Ok(50), 200)), 3);
+        assert_eq!(current.verify(true).unwrap(), Some(Fault::LoadOff));
+        assert_eq!(current.verify(false).unwrap(), None);
+
+        let mut welded = Feedback::new(Box::new(FakeProbe(Ok(true))), 3);
+        assert_eq!(welded.verify(false).unwrap(), Some(Fault::LoadOn));
+
+        let mut broken = Feedback::new(Box::new(FakeProbe(Err(Error::msg("gpio")))), 3);
+        assert!(broken.verify(true).is_err());
+    }
+}
@@ -0,0 +1,12 @@
+//! Hardware-free parts of the firmware: parsers, checks and the config
+//! schema. They build on the host, where `cargo test` runs their tests.
+
+pub mod api;
+pub mod band;
+pub mod calibration;
+pub mod ds18b20;
+pub mod duration;
+pub mod feedback;
+pub mod rule;
+pub mod schema;
+pub mod text;
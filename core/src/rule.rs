@@ -0,0 +1,346 @@
+use anyhow::Error;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::duration::parse_duration;
+
+const INVALID_RULE: &str = "expected \"if [condition] [and ...] then [relay] on|off [for 30m] [cooldown 1h]\", conditions: \"kolam > 30\", \"time between 10:00-16:00\", \"pompa_air is on\"";
+
+/// NVS keys are at most 15 characters, one is kept for the "!" of the
+/// enable toggle
+pub const MAX_NAME: usize = 14;
+
+/// Rule names are NVS keys: 1 to `MAX_NAME` ASCII letters, digits or
+/// underscores, never the key of the stored rule index.
+pub fn check_name(name: &str) -> anyhow::Result<()> {
+    match !name.is_empty()
+        && name.len() <= MAX_NAME
+        && name != "index"
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+    {
+        true => Ok(()),
+        false => Err(Error::msg(format!(
+            "rule name {} must be 1-14 letters, digits or underscores",
+            name
+        ))),
+    }
+}
+
+/// Sensor values and relay states a rule looks at.
+pub trait RuleInputs {
+    fn sensor(&self, name: &str) -> Option<f32>;
+    /// whether relay `name` has a running order
+    fn relay_running(&self, name: &str) -> Option<bool>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Above(String, f32),
+    Below(String, f32),
+    /// seconds since local midnight, wraps over midnight when start > end
+    Between(u32, u32),
+    RelayIs(String, bool),
+}
+
+impl Condition {
+    /// `time_of_day` is None while the clock is not set, time windows
+    /// never hold then
+    fn holds(&self, inputs: &dyn RuleInputs, time_of_day: Option<u32>) -> bool {
+        match self {
+            Condition::Above(sensor, limit) => inputs.sensor(sensor).is_some_and(|v| v > *limit),
+            Condition::Below(sensor, limit) => inputs.sensor(sensor).is_some_and(|v| v < *limit),
+            Condition::Between(start, end) => time_of_day.is_some_and(|t| match start <= end {
+                true => (*start..*end).contains(&t),
+                false => t >= *start || t < *end,
+            }),
+            Condition::RelayIs(relay, on) => inputs.relay_running(relay) == Some(*on),
+        }
+    }
+}
+
+/// parse `10:00` into seconds since midnight
+fn parse_clock(s: &str) -> Option<u32> {
+    let (h, m) = s.split_once(':')?;
+    let (h, m) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?);
+    (h < 24 && m < 60).then_some(h * 3600 + m * 60)
+}
+
+fn fmt_clock(secs: u32) -> String {
+    format!("{:02}:{:02}", secs / 3600, secs % 3600 / 60)
+}
+
+/// shortest unit that keeps a duration exact
+fn fmt_duration(secs: u32) -> String {
+    match secs {
+        s if s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Condition::Above(sensor, limit) => write!(f, "{} > {}", sensor, limit),
+            Condition::Below(sensor, limit) => write!(f, "{} < {}", sensor, limit),
+            Condition::Between(start, end) => {
+                write!(f, "time between {}-{}", fmt_clock(*start), fmt_clock(*end))
+            }
+            Condition::RelayIs(relay, on) => {
+                write!(f, "{} is {}", relay, if *on { "on" } else { "off" })
+            }
+        }
+    }
+}
+
+/// `if kolam > 30 and time between 10:00-16:00 then lain_lain on for 30m cooldown 1h`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    conditions: Vec<Condition>,
+    pub relay: String,
+    pub on: bool,
+    pub duration: Option<u32>,
+    /// seconds after firing before the rule may fire again
+    cooldown: u32,
+}
+
+impl Rule {
+    fn parse_condition(tokens: &[&str]) -> anyhow::Result<Condition> {
+        let invalid = || Error::msg(INVALID_RULE);
+        match tokens {
+            ["time", "between", range] => {
+                let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+                Ok(Condition::Between(
+                    parse_clock(start).ok_or_else(invalid)?,
+                    parse_clock(end).ok_or_else(invalid)?,
+                ))
+            }
+            [relay, "is", "on"] => Ok(Condition::RelayIs(relay.to_string(), true)),
+            [relay, "is", "off"] => Ok(Condition::RelayIs(relay.to_string(), false)),
+            [sensor, op, value] => {
+                let value = value.parse::<f32>().map_err(|_| invalid())?;
+                match *op {
+                    ">" => Ok(Condition::Above(sensor.to_string(), value)),
+                    "<" => Ok(Condition::Below(sensor.to_string(), value)),
+                    _ => Err(invalid()),
+                }
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    /// all conditions hold at `time_of_day`
+    pub fn matches(&self, inputs: &dyn RuleInputs, time_of_day: Option<u32>) -> bool {
+        self.conditions.iter().all(|c| c.holds(inputs, time_of_day))
+    }
+
+    /// the cooldown after firing at `last_fire` passed at `now`
+    pub fn cooled(&self, last_fire: Option<u64>, now: u64) -> bool {
+        match last_fire {
+            Some(t) => now >= t + self.cooldown as u64,
+            None => true,
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens: Vec<&str> = s.split_whitespace().collect();
+        let then = tokens.iter().position(|t| *t == "then");
+        let (Some(&"if"), Some(then)) = (tokens.first(), then) else {
+            return Err(Error::msg(INVALID_RULE));
+        };
+
+        let conditions = tokens[1..then]
+            .split(|t| *t == "and")
+            .map(Self::parse_condition)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut action = tokens[then + 1..].iter();
+        let relay = action.next().ok_or(Error::msg(INVALID_RULE))?.to_string();
+        let on = match action.next() {
+            Some(&"on") => true,
+            Some(&"off") => false,
+            _ => return Err(Error::msg(INVALID_RULE)),
+        };
+
+        let mut rule = Rule {
+            conditions,
+            relay,
+            on,
+            duration: None,
+            cooldown: 0,
+        };
+        while let Some(pred) = action.next() {
+            let value = action.next().ok_or(Error::msg(INVALID_RULE))?;
+            match *pred {
+                "for" if on => rule.duration = Some(parse_duration(value)?),
+                "cooldown" => rule.cooldown = parse_duration(value)?,
+                _ => return Err(Error::msg(INVALID_RULE)),
+            }
+        }
+        Ok(rule)
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "if ")?;
+        for (i, c) in self.conditions.iter().enumerate() {
+            if i > 0 {
+                write!(f, " and ")?;
+            }
+            write!(f, "{}", c)?;
+        }
+        write!(
+            f,
+            " then {} {}",
+            self.relay,
+            if self.on { "on" } else { "off" }
+        )?;
+        if let Some(duration) = self.duration {
+            write!(f, " for {}", fmt_duration(duration))?;
+        }
+        if self.cooldown > 0 {
+            write!(f, " cooldown {}", fmt_duration(self.cooldown))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct Inputs {
+        sensors: HashMap<&'static str, f32>,
+        relays: HashMap<&'static str, bool>,
+    }
+
+    impl RuleInputs for Inputs {
+        fn sensor(&self, name: &str) -> Option<f32> {
+            self.sensors.get(name).copied()
+        }
+
+        fn relay_running(&self, name: &str) -> Option<bool> {
+            self.relays.get(name).copied()
+        }
+    }
+
+    fn clock(h: u32, m: u32) -> u32 {
+        h * 3600 + m * 60
+    }
+
+    #[test]
+    fn parse_full_rule() {
+        let text =
+            "if kolam > 30 and time between 10:00-16:00 then lain_lain on for 30m cooldown 1h";
+        let rule: Rule = text.parse().unwrap();
+        assert_eq!(
+            rule.conditions,
+            [
+                Condition::Above("kolam".into(), 30.0),
+                Condition::Between(clock(10, 0), clock(16, 0)),
+            ]
+        );
+        assert_eq!((rule.relay.as_str(), rule.on), ("lain_lain", true));
+        assert_eq!((rule.duration, rule.cooldown), (Some(1800), 3600));
+        assert_eq!(rule.to_string(), text);
+    }
+
+    #[test]
+    fn parse_relay_condition() {
+        let rule: Rule = "if ph < 6.5 and pompa_air is off then lain_lain off"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            rule.conditions,
+            [
+                Condition::Below("ph".into(), 6.5),
+                Condition::RelayIs("pompa_air".into(), false),
+            ]
+        );
+        assert!(!rule.on);
+        assert_eq!(rule.duration, None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for text in [
+            "",
+            "kolam > 30 then lain_lain on",
+            "if kolam > 30 lain_lain on",
+            "if kolam = 30 then lain_lain on",
+            "if kolam > warm then lain_lain on",
+            "if time between 10:00 then lain_lain on",
+            "if time between 24:00-10:00 then lain_lain on",
+            "if kolam > 30 then lain_lain",
+            "if kolam > 30 then lain_lain off for 30m",
+            "if kolam > 30 then lain_lain on for",
+            "if kolam > 30 then lain_lain on every 1h",
+        ] {
+            assert!(text.parse::<Rule>().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn time_between() {
+        let day = Condition::Between(clock(10, 0), clock(16, 0));
+        let inputs = Inputs::default();
+        assert!(!day.holds(&inputs, Some(clock(9, 59))));
+        assert!(day.holds(&inputs, Some(clock(10, 0))));
+        assert!(!day.holds(&inputs, Some(clock(16, 0))));
+        assert!(!day.holds(&inputs, None), "clock not set");
+    }
+
+    #[test]
+    fn time_between_wraps_midnight() {
+        let night = Condition::Between(clock(22, 0), clock(4, 0));
+        let inputs = Inputs::default();
+        assert!(night.holds(&inputs, Some(clock(23, 30))));
+        assert!(night.holds(&inputs, Some(0)));
+        assert!(night.holds(&inputs, Some(clock(3, 59))));
+        assert!(!night.holds(&inputs, Some(clock(4, 0))));
+        assert!(!night.holds(&inputs, Some(clock(12, 0))));
+    }
+
+    #[test]
+    fn matches_all_conditions() {
+        let rule: Rule = "if kolam > 30 and pompa_air is on then lain_lain on"
+            .parse()
+            .unwrap();
+        let mut inputs = Inputs::default();
+        assert!(!rule.matches(&inputs, Some(0)), "missing sensor");
+        inputs.sensors.insert("kolam", 31.0);
+        assert!(!rule.matches(&inputs, Some(0)));
+        inputs.relays.insert("pompa_air", true);
+        assert!(rule.matches(&inputs, Some(0)));
+        inputs.sensors.insert("kolam", 30.0);
+        assert!(!rule.matches(&inputs, Some(0)));
+    }
+
+    #[test]
+    fn cooldown() {
+        let rule: Rule = "if kolam > 30 then lain_lain on cooldown 1h"
+            .parse()
+            .unwrap();
+        assert!(rule.cooled(None, 0));
+        assert!(!rule.cooled(Some(1000), 1000 + 3599));
+        assert!(rule.cooled(Some(1000), 1000 + 3600));
+
+        let rule: Rule = "if kolam > 30 then lain_lain on".parse().unwrap();
+        assert!(rule.cooled(Some(1000), 1000));
+    }
+
+    #[test]
+    fn names() {
+        assert!(check_name("aerasi_siang").is_ok());
+        for name in ["", "aerasi_siang_ab", "filter_pompa_é", "a-b", "index"] {
+            assert!(check_name(name).is_err(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use anyhow::Error;
+use log::info;
+use toml::{Table, Value};
+
+/// Upgrades of the stored table, `MIGRATIONS[n]` turns schema version
+/// n + 1 into n + 2. Append one when a config change is not backward
+/// compatible, the version follows the length of the list. The defaults
+/// run through all of them, so a migration must leave a table already in
+/// the newer shape unchanged.
+const MIGRATIONS: &[fn(&mut Table)] = &[wifi_networks];
+pub const VERSION: u8 = MIGRATIONS.len() as u8 + 1;
+
+/// Values never shown or changed by `/config`, with everything below
+/// them. `*` matches any key or index. Besides the secrets themselves this
+/// covers where they are sent: a changed `api_base` or broker `url` would
+/// hand the bot token or the MQTT password to another host.
+const SECRETS: [&str; 7] = [
+    "wifi.networks.*.password",
+    "wifi.ap_password",
+    "telegram.bot_token",
+    "telegram.api_base",
+    "mqtt.password",
+    "mqtt.url",
+    "api.tokens",
+];
+
+/// Bring a table of schema `version` to the current one.
+pub fn migrate(table: &mut Table, version: u8) {
+    for (from, migrate) in MIGRATIONS
+        .iter()
+        .enumerate()
+        .skip((version as usize).saturating_sub(1))
+    {
+        info!("migrating config schema {} to {}", from + 1, from + 2);
+        migrate(table);
+    }
+}
+
+/// Copy of `table` with `path` set to `raw`, read as TOML and anything
+/// else as a string. New keys are allowed in existing tables for optional
+/// fields left out.
+pub fn assign(table: &Table, path: &str, raw: &str) -> anyhow::Result<(Table, Value)> {
+    let value = toml::from_str::<Table>(&format!("v = {}", raw))
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| Value::String(raw.to_owned()));
+
+    let mut root = Value::Table(table.clone());
+    let (parent, leaf) = match path.rsplit_once('.') {
+        Some((parent, leaf)) => (Some(parent), leaf),
+        None => (None, path),
+    };
+    let mut target = &mut root;
+    for key in parent.into_iter().flat_map(|p| p.split('.')) {
+        target = child(target, key).ok_or(Error::msg(format!("{} not found", key)))?;
+    }
+    match target {
+        Value::Table(t) => {
+            t.insert(leaf.to_owned(), value.clone());
+        }
+        target => {
+            *child(target, leaf).ok_or(Error::msg(format!("{} not found", path)))? = value.clone()
+        }
+    }
+
+    let Value::Table(table) = root else {
+        unreachable!()
+    };
+    Ok((table, value))
+}
+
+/// Copy of `table` with the network on top of the known ones, replacing
+/// one of the same name, and the bot token when given.
+pub fn with_network(
+    table: &Table,
+    ssid: &str,
+    password: &str,
+    bot_token: Option<&str>,
+) -> anyhow::Result<Table> {
+    let mut table = table.clone();
+    let wifi = section(&mut table, "wifi")?;
+    let Value::Array(networks) = wifi
+        .entry("networks")
+        .or_insert_with(|| Value::Array(Vec::new()))
+    else {
+        return Err(Error::msg("wifi.networks is not a list"));
+    };
+    networks.retain(|n| n.get("ssid").and_then(Value::as_str) != Some(ssid));
+    let top = networks
+        .iter()
+        .filter_map(|n| n.get("priority")?.as_integer())
+        .max()
+        .unwrap_or(0);
+
+    let mut network = Table::new();
+    network.insert("ssid".to_owned(), Value::from(ssid));
+    network.insert("password".to_owned(), Value::from(password));
+    network.insert("priority".to_owned(), Value::from((top + 1).min(255)));
+    if password.is_empty() {
+        network.insert("auth".to_owned(), Value::from("open"));
+    }
+    networks.insert(0, Value::Table(network));
+
+    if let Some(token) = bot_token {
+        section(&mut table, "telegram")?.insert("bot_token".to_owned(), Value::from(token));
+    }
+    Ok(table)
+}
+
+/// schema 2: `[wifi]` ssid and password became the first of `networks`
+fn wifi_networks(table: &mut Table) {
+    let Some(Value::Table(wifi)) = table.get_mut("wifi") else {
+        return;
+    };
+    let Some(ssid) = wifi.remove("ssid") else {
+        return;
+    };
+    let password = wifi.remove("password").unwrap_or_else(|| Value::from(""));
+
+    let mut network = Table::new();
+    if password.as_str() == Some("") {
+        network.insert("auth".to_owned(), Value::from("open"));
+    }
+    network.insert("ssid".to_owned(), ssid);
+    network.insert("password".to_owned(), password);
+    if let Value::Array(networks) = wifi
+        .entry("networks")
+        .or_insert_with(|| Value::Array(Vec::new()))
+    {
+        networks.insert(0, Value::Table(network));
+    }
+}
+
+pub fn section<'t>(table: &'t mut Table, name: &str) -> anyhow::Result<&'t mut Table> {
+    match table.get_mut(name) {
+        Some(Value::Table(t)) => Ok(t),
+        _ => Err(Error::msg(format!("{} not found", name))),
+    }
+}
+
+/// entry of a table by key or of an array by index
+pub fn child<'v>(value: &'v mut Value, key: &str) -> Option<&'v mut Value> {
+    match value {
+        Value::Table(t) => t.get_mut(key),
+        Value::Array(a) => a.get_mut(key.parse::<usize>().ok()?),
+        _ => None,
+    }
+}
+
+/// `path` is a secret or lies below one
+pub fn is_secret(path: &str) -> bool {
+    SECRETS.iter().any(|secret| {
+        let mut keys = path.split('.');
+        secret
+            .split('.')
+            .all(|s| keys.next().is_some_and(|k| s == "*" || s == k))
+    })
+}
+
+/// a secret lies below `path`, setting it would overwrite the secret
+pub fn holds_secret(path: &str) -> bool {
+    SECRETS.iter().any(|secret| {
+        let mut parts = secret.split('.');
+        path.split('.')
+            .all(|k| parts.next().is_some_and(|s| s == "*" || s == k))
+    })
+}
+
+/// replace the secrets inside `value` found at `path`
+pub fn mask(value: &mut Value, path: &str) {
+    if is_secret(path) {
+        *value = Value::String(String::from("***"));
+        return;
+    }
+    match value {
+        Value::Table(t) => t
+            .iter_mut()
+            .for_each(|(k, v)| mask(v, &format!("{}.{}", path, k))),
+        Value::Array(a) => a
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, v)| mask(v, &format!("{}.{}", path, i))),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(text: &str) -> Table {
+        toml::from_str(text).unwrap()
+    }
+
+    const V1: &str = r#"
+        [wifi]
+        ssid = "kolam"
+        password = "rahasia123"
+
+        [telegram]
+        bot_token = "123:abc"
+        api_base = "https://api.telegram.org"
+    "#;
+
+    #[test]
+    fn migrate_v1_wifi() {
+        let mut config = table(V1);
+        migrate(&mut config, 1);
+        assert_eq!(
+            config["wifi"],
+            Value::Table(table(
+                r#"networks = [{ ssid = "kolam", password = "rahasia123" }]"#
+            ))
+        );
+        assert_eq!(config["telegram"]["bot_token"].as_str(), Some("123:abc"));
+    }
+
+    #[test]
+    fn migrate_v1_open_wifi_keeps_networks() {
+        let mut config = table(
+            r#"
+            [wifi]
+            ssid = "tamu"
+            networks = [{ ssid = "kolam", password = "rahasia123" }]
+            "#,
+        );
+        migrate(&mut config, 1);
+        let networks = config["wifi"]["networks"].as_array().unwrap();
+        assert_eq!(networks.len(), 2);
+        assert_eq!(networks[0]["ssid"].as_str(), Some("tamu"));
+        assert_eq!(networks[0]["auth"].as_str(), Some("open"));
+        assert_eq!(networks[1]["ssid"].as_str(), Some("kolam"));
+    }
+
+    #[test]
+    fn migrate_current_unchanged() {
+        let mut config = table(V1);
+        migrate(&mut config, 1);
+        let migrated = config.clone();
+
+        // the defaults run through every migration again
+        migrate(&mut config, 1);
+        assert_eq!(config, migrated);
+        migrate(&mut config, VERSION);
+        assert_eq!(config, migrated);
+    }
+
+    #[test]
+    fn secret_paths() {
+        assert!(is_secret("telegram.bot_token"));
+        assert!(is_secret("telegram.api_base"));
+        assert!(is_secret("wifi.networks.3.password"));
+        assert!(is_secret("api.tokens.0.token"));
+        assert!(is_secret("mqtt.url"));
+        assert!(!is_secret("wifi.networks.3.ssid"));
+        assert!(!is_secret("telegram"));
+        assert!(!is_secret("relay.channels.pompa_air.min_off"));
+
+        assert!(holds_secret("telegram"));
+        assert!(holds_secret("wifi"));
+        assert!(holds_secret("wifi.networks.0"));
+        assert!(holds_secret("api"));
+        assert!(!holds_secret("relay"));
+        assert!(!holds_secret("wifi.provision_after"));
+        assert!(!holds_secret("telegram.admins"));
+    }
+
+    #[test]
+    fn mask_secrets() {
+        let mut config = Value::Table(table(
+            r#"
+            [wifi]
+            provision_after = 10
+            networks = [{ ssid = "kolam", password = "rahasia123" }]
+
+            [api]
+            port = 80
+            tokens = [{ name = "staff", token = "staff-token" }]
+            "#,
+        ));
+        mask(&mut config["wifi"], "wifi");
+        mask(&mut config["api"], "api");
+        assert_eq!(
+            config["wifi"]["networks"][0]["password"].as_str(),
+            Some("***")
+        );
+        assert_eq!(
+            config["wifi"]["networks"][0]["ssid"].as_str(),
+            Some("kolam")
+        );
+        assert_eq!(config["wifi"]["provision_after"].as_integer(), Some(10));
+        assert_eq!(config["api"]["tokens"].as_str(), Some("***"));
+        assert_eq!(config["api"]["port"].as_integer(), Some(80));
+    }
+
+    #[test]
+    fn assign_paths() {
+        let config = table(
+            r#"
+            [relay.channels.pompa_air]
+            min_off = 300
+            [[rule]]
+            name = "aerasi"
+            "#,
+        );
+
+        let (changed, value) = assign(&config, "relay.channels.pompa_air.min_off", "600").unwrap();
+        assert_eq!(value.as_integer(), Some(600));
+        assert_eq!(
+            changed["relay"]["channels"]["pompa_air"]["min_off"].as_integer(),
+            Some(600)
+        );
+
+        // optional fields left out can be added, bare words are strings
+        let (changed, _) = assign(&config, "relay.channels.pompa_air.mode", "truncate").unwrap();
+        assert_eq!(
+            changed["relay"]["channels"]["pompa_air"]["mode"].as_str(),
+            Some("truncate")
+        );
+
+        let (changed, _) = assign(&config, "rule.0.name", "\"malam\"").unwrap();
+        assert_eq!(changed["rule"][0]["name"].as_str(), Some("malam"));
+
+        assert!(assign(&config, "relay.pumps.pompa_air.min_off", "1").is_err());
+        assert!(assign(&config, "rule.1.name", "x").is_err());
+        assert!(assign(&config, "relay.channels.pompa_air.min_off.x", "1").is_err());
+        // the source table is left as it was
+        assert_eq!(
+            config["relay"]["channels"]["pompa_air"]["min_off"].as_integer(),
+            Some(300)
+        );
+    }
+
+    #[test]
+    fn provisioned_network_on_top() {
+        let mut config = table(V1);
+        migrate(&mut config, 1);
+        let config = with_network(&config, "gudang", "", Some("456:def")).unwrap();
+        let networks = config["wifi"]["networks"].as_array().unwrap();
+        assert_eq!(networks[0]["ssid"].as_str(), Some("gudang"));
+        assert_eq!(networks[0]["auth"].as_str(), Some("open"));
+        assert_eq!(networks[0]["priority"].as_integer(), Some(1));
+        assert_eq!(config["telegram"]["bot_token"].as_str(), Some("456:def"));
+
+        // the same network again replaces it above the others
+        let config = with_network(&config, "kolam", "baru12345", None).unwrap();
+        let networks = config["wifi"]["networks"].as_array().unwrap();
+        assert_eq!(networks.len(), 2);
+        assert_eq!(networks[0]["password"].as_str(), Some("baru12345"));
+        assert_eq!(networks[0]["priority"].as_integer(), Some(2));
+        assert_eq!(config["telegram"]["bot_token"].as_str(), Some("456:def"));
+    }
+}
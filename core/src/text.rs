@@ -0,0 +1,46 @@
+/// Split `text` into pieces of at most `max` bytes, at the last line
+/// break of a piece where there is one, otherwise at a character
+/// boundary. `max` is at least 4, the longest character.
+pub fn split(text: &str, max: usize) -> Vec<&str> {
+    assert!(max >= 4);
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while rest.len() > max {
+        let mut end = max;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let cut = rest[..end].rfind('\n').filter(|i| *i > 0).unwrap_or(end);
+        pieces.push(&rest[..cut]);
+        rest = rest[cut..].strip_prefix('\n').unwrap_or(&rest[cut..]);
+    }
+    pieces.push(rest);
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_text_kept() {
+        assert_eq!(split("", 10), [""]);
+        assert_eq!(split("a\nb", 10), ["a\nb"]);
+    }
+
+    #[test]
+    fn split_at_line_breaks() {
+        assert_eq!(split("aaaa\nbbbb\ncccc", 10), ["aaaa\nbbbb", "cccc"]);
+        assert_eq!(split("aaaa\nbbbb\ncccc", 4), ["aaaa", "bbbb", "cccc"]);
+    }
+
+    #[test]
+    fn split_long_lines_at_characters() {
+        assert_eq!(split("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        // 'é' takes two bytes and is never cut
+        assert_eq!(split("aééé", 4), ["aé", "éé"]);
+        for piece in split(&"é°".repeat(50), 7) {
+            assert!(piece.len() <= 7);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use anyhow::Error;
+use core::ptr;
+use esp_idf_svc::sys::{self, esp};
+
+use crate::util::claim_pin;
+
+/// ADC1 in oneshot mode. Channels are created from configured GPIO
+/// numbers, so the unit is driven through esp-idf directly instead of
+/// the typed pins of the hal.
+pub struct Adc {
+    unit: sys::adc_oneshot_unit_handle_t,
+}
+
+/// calibrated ADC1 channel of a GPIO
+#[derive(Clone, Copy)]
+pub struct AdcChannel {
+    gpio: u8,
+    chan: sys::adc_channel_t,
+    cali: sys::adc_cali_handle_t,
+}
+
+impl Adc {
+    /// GPIOs of ADC1 on the ESP32-C3
+    pub const PINS: core::ops::RangeInclusive<u8> = 0..=4;
+    const ATTEN: sys::adc_atten_t = sys::adc_atten_t_ADC_ATTEN_DB_12;
+    const BITWIDTH: sys::adc_bitwidth_t = sys::adc_bitwidth_t_ADC_BITWIDTH_DEFAULT;
+
+    pub fn new() -> anyhow::Result<Self> {
+        let mut unit = ptr::null_mut();
+        let config = sys::adc_oneshot_unit_init_cfg_t {
+            unit_id: sys::adc_unit_t_ADC_UNIT_1,
+            ..Default::default()
+        };
+        esp!(unsafe { sys::adc_oneshot_new_unit(&config, &mut unit) })?;
+        Ok(Self { unit })
+    }
+
+    /// configure `gpio` as a channel with 0 - 2.5V range
+    pub fn channel(&self, gpio: u8) -> anyhow::Result<AdcChannel> {
+        let mut unit_id: sys::adc_unit_t = 0;
+        let mut chan: sys::adc_channel_t = 0;
+        esp!(unsafe { sys::adc_oneshot_io_to_channel(gpio as i32, &mut unit_id, &mut chan) })
+            .map_err(|_| Error::msg(format!("gpio{} has no ADC channel", gpio)))?;
+        if unit_id != sys::adc_unit_t_ADC_UNIT_1 {
+            return Err(Error::msg(format!("gpio{} is not an ADC1 pin", gpio)));
+        }
+        claim_pin(gpio)?;
+
+        let chan_config = sys::adc_oneshot_chan_cfg_t {
+            atten: Self::ATTEN,
+            bitwidth: Self::BITWIDTH,
+        };
+        esp!(unsafe { sys::adc_oneshot_config_channel(self.unit, chan, &chan_config) })?;
+
+        let mut cali = ptr::null_mut();
+        let cali_config = sys::adc_cali_curve_fitting_config_t {
+            unit_id,
+            chan,
+            atten: Self::ATTEN,
+            bitwidth: Self::BITWIDTH,
+        };
+        esp!(unsafe { sys::adc_cali_create_scheme_curve_fitting(&cali_config, &mut cali) })?;
+
+        Ok(AdcChannel { gpio, chan, cali })
+    }
+
+    /// calibrated voltage in millivolt
+    pub fn read_mv(&self, channel: &AdcChannel) -> anyhow::Result<u16> {
+        let mut raw = 0;
+        esp!(unsafe { sys::adc_oneshot_read(self.unit, channel.chan, &mut raw) })
+            .map_err(|e| Error::msg(format!("read gpio{}: {}", channel.gpio, e)))?;
+
+        let mut mv = 0;
+        esp!(unsafe { sys::adc_cali_raw_to_voltage(channel.cali, raw, &mut mv) })?;
+        Ok(mv.max(0) as u16)
+    }
+}
+
+impl Drop for Adc {
+    fn drop(&mut self) {
+        unsafe { sys::adc_oneshot_del_unit(self.unit) };
+    }
+}
@@ -0,0 +1,284 @@
+use anyhow::Error;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use serde::Deserialize;
+use std::fmt::{Display, Write};
+use std::rc::Rc;
+
+use crate::adc::{Adc, AdcChannel};
+use crate::band::{classify, Band};
+use crate::calibration::{Calibration, Filter, FilterConfig};
+use crate::history::HistoryLog;
+
+/// Raw voltage of an analog sensor.
+pub trait AnalogSource {
+    fn read_mv(&mut self) -> anyhow::Result<u16>;
+}
+
+/// channel of the shared ADC1 unit
+pub struct AdcSource {
+    adc: Rc<Adc>,
+    channel: AdcChannel,
+}
+
+impl AnalogSource for AdcSource {
+    fn read_mv(&mut self) -> anyhow::Result<u16> {
+        self.adc.read_mv(&self.channel)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AnalogConfig {
+    /// channel name, also the NVS key of its calibration and the
+    /// history key
+    pub name: String,
+    /// ADC1 pin
+    pub pin: u8,
+    #[serde(default)]
+    unit: String,
+    /// uncalibrated conversion, value = mv * scale + offset
+    #[serde(default = "AnalogConfig::default_scale")]
+    scale: f32,
+    #[serde(default)]
+    offset: f32,
+    #[serde(default)]
+    filter: FilterConfig,
+    /// alert below, in `unit`
+    #[serde(default)]
+    low: Option<f32>,
+    /// alert above, in `unit`
+    #[serde(default)]
+    high: Option<f32>,
+    #[serde(default)]
+    hysteresis: f32,
+}
+
+impl AnalogConfig {
+    fn default_scale() -> f32 {
+        1.0
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        crate::config::check_name(&self.name, HistoryLog::MAX_NAME)?;
+        crate::config::check_band(self.low, self.high, self.hysteresis)?;
+        match self.filter {
+            FilterConfig::Ema { alpha } if !(alpha > 0.0 && alpha <= 1.0) => {
+                Err(Error::msg("ema alpha must be in (0, 1]"))
+            }
+            FilterConfig::Median { window: 0 } => Err(Error::msg("median window must not be 0")),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AnalogEvent<'cfg> {
+    config: &'cfg AnalogConfig,
+    band: Band,
+    value: f32,
+}
+
+impl<'cfg> Display for AnalogEvent<'cfg> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let c = self.config;
+        let state = match self.band {
+            Band::High => "high",
+            Band::Low => "low",
+            Band::Normal => "back to normal",
+        };
+        write!(f, "{} {}: {:.2} {}", c.name, state, self.value, c.unit)
+    }
+}
+
+struct Channel<'cfg> {
+    config: &'cfg AnalogConfig,
+    source: Box<dyn AnalogSource>,
+    filter: Filter,
+    calibration: Calibration,
+    /// filtered millivolt of the last sample
+    mv: Option<f32>,
+    band: Band,
+}
+
+impl<'cfg> Channel<'cfg> {
+    /// averaged over a few reads to reduce ADC noise
+    const OVERSAMPLE: u32 = 8;
+
+    fn sample(&mut self) -> anyhow::Result<f32> {
+        let mut sum = 0u32;
+        for _ in 0..Self::OVERSAMPLE {
+            sum += self.source.read_mv()? as u32;
+        }
+        let mv = self.filter.push(sum as f32 / Self::OVERSAMPLE as f32);
+        self.mv = Some(mv);
+        Ok(mv)
+    }
+
+    fn value(&self) -> Option<f32> {
+        let c = self.config;
+        self.mv
+            .map(|mv| self.calibration.apply(mv, c.scale, c.offset))
+    }
+}
+
+/// Named analog sensors on ADC1, sampled every tick. Calibration points
+/// are kept in NVS per channel name.
+pub struct AnalogSensors<'cfg> {
+    channels: Vec<Channel<'cfg>>,
+    storage: EspNvs<NvsDefault>,
+}
+
+impl<'cfg> AnalogSensors<'cfg> {
+    pub fn new(
+        partition: EspDefaultNvsPartition,
+        config: &'cfg [AnalogConfig],
+        adc: &mut Option<Rc<Adc>>,
+    ) -> anyhow::Result<Self> {
+        let storage = EspNvs::new(partition, "calibration", true)?;
+        let mut channels = Vec::with_capacity(config.len());
+        for analog in config {
+            let adc = match adc {
+                Some(adc) => adc.clone(),
+                None => adc.insert(Rc::new(Adc::new()?)).clone(),
+            };
+            let channel = adc.channel(analog.pin)?;
+
+            let mut buf = [0u8; 64];
+            let calibration = storage
+                .get_blob(&analog.name, &mut buf)?
+                .and_then(Calibration::from_bytes)
+                .unwrap_or_default();
+            info!(
+                "analog {} on gpio{}, {} calibration points",
+                analog.name,
+                analog.pin,
+                calibration.points().len()
+            );
+
+            channels.push(Channel {
+                config: analog,
+                source: Box::new(AdcSource { adc, channel }),
+                filter: Filter::new(analog.filter),
+                calibration,
+                mv: None,
+                band: Band::Normal,
+            });
+        }
+        Ok(Self { channels, storage })
+    }
+
+    /// sample every channel, returns threshold crossings
+    pub fn tick(&mut self) -> Vec<AnalogEvent<'cfg>> {
+        let mut events = Vec::new();
+        for ch in self.channels.iter_mut() {
+            if let Err(err) = ch.sample() {
+                warn!("analog {} read error: {}", ch.config.name, err);
+                continue;
+            }
+
+            let Some(value) = ch.value() else {
+                continue;
+            };
+            let c = ch.config;
+            let band = classify(value, ch.band, c.low, c.high, c.hysteresis);
+            if band != ch.band {
+                ch.band = band;
+                events.push(AnalogEvent {
+                    config: c,
+                    band,
+                    value,
+                });
+            }
+        }
+        events
+    }
+
+    pub fn names(&self) -> Vec<&'cfg str> {
+        self.channels
+            .iter()
+            .map(|c| c.config.name.as_str())
+            .collect()
+    }
+
+    /// unit of a channel by name
+    pub fn unit(&self, name: &str) -> Option<&'cfg str> {
+        self.channels
+            .iter()
+            .find(|c| c.config.name == name)
+            .map(|c| c.config.unit.as_str())
+    }
+
+    /// calibrated reading of a channel by name
+    pub fn value(&self, name: &str) -> Option<f32> {
+        self.channels
+            .iter()
+            .find(|c| c.config.name == name)
+            .and_then(Channel::value)
+    }
+
+    /// Handle `/calibrate [name] [reference|reset]`, a reference value
+    /// adds the current reading as calibration point.
+    pub fn calibrate(&mut self, name: &str, arg: Option<&str>) -> anyhow::Result<String> {
+        let ch = self
+            .channels
+            .iter_mut()
+            .find(|c| c.config.name == name)
+            .ok_or(Error::msg("unknown analog sensor"))?;
+
+        match arg {
+            None => {}
+            Some("reset") => {
+                ch.calibration = Calibration::default();
+                self.storage.remove(&ch.config.name)?;
+            }
+            Some(reference) => {
+                let reference = reference
+                    .parse::<f32>()
+                    .map_err(|_| Error::msg("expected \"/calibrate [name] [reference value]\""))?;
+                let mv = ch
+                    .mv
+                    .ok_or(Error::msg("no reading yet, try again shortly"))?;
+                ch.calibration.add_point(mv, reference)?;
+                self.storage
+                    .set_blob(&ch.config.name, &ch.calibration.to_bytes())?;
+                info!("analog {} calibrated {} mV = {}", name, mv, reference);
+            }
+        }
+
+        let mut out = format!("Calibration of {}:", name);
+        if ch.calibration.points().is_empty() {
+            let _ = write!(
+                out,
+                "\nnone, value = mV * {} + {}",
+                ch.config.scale, ch.config.offset
+            );
+        }
+        for (mv, value) in ch.calibration.points() {
+            let _ = write!(out, "\n{:.0} mV = {} {}", mv, value, ch.config.unit);
+        }
+        Ok(out)
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        for ch in &self.channels {
+            match ch.value() {
+                Some(value) => {
+                    let _ = writeln!(
+                        out,
+                        "{}: {:.2} {} ({:?}, {:.0} mV)",
+                        ch.config.name,
+                        value,
+                        ch.config.unit,
+                        ch.band,
+                        ch.mv.unwrap_or_default()
+                    );
+                }
+                None => {
+                    let _ = writeln!(out, "{}: no reading", ch.config.name);
+                }
+            }
+        }
+        out.trim_end().to_owned()
+    }
+}
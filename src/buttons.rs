@@ -0,0 +1,150 @@
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::gpio::{AnyInputPin, Input, PinDriver, Pull};
+use log::{info, warn};
+use serde::Deserialize;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use crate::util::input_pin;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ButtonAction {
+    /// turn off when on, otherwise start for `duration`
+    Toggle,
+    /// start for `duration`
+    Start,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ButtonConfig {
+    pub pin: u8,
+    pub relay: String,
+    pub action: ButtonAction,
+    /// run seconds when the button starts the relay
+    #[serde(default = "ButtonConfig::default_duration")]
+    pub duration: u32,
+    /// pressed pulls the pin low, the default for buttons to ground
+    #[serde(default = "ButtonConfig::default_active_low")]
+    active_low: bool,
+}
+
+impl ButtonConfig {
+    fn default_duration() -> u32 {
+        3600
+    }
+
+    fn default_active_low() -> bool {
+        true
+    }
+}
+
+/// Debounce a sampled input, the level must hold `stable_ms` before it
+/// is accepted.
+pub struct Debounce {
+    stable: bool,
+    candidate: bool,
+    held_ms: u32,
+    stable_ms: u32,
+}
+
+impl Debounce {
+    pub fn new(stable_ms: u32) -> Self {
+        Self {
+            stable: false,
+            candidate: false,
+            held_ms: 0,
+            stable_ms,
+        }
+    }
+
+    /// feed a sample taken `elapsed_ms` after the previous one,
+    /// returns true on an accepted press
+    pub fn update(&mut self, pressed: bool, elapsed_ms: u32) -> bool {
+        if pressed != self.candidate {
+            self.candidate = pressed;
+            self.held_ms = 0;
+            return false;
+        }
+
+        if self.candidate == self.stable {
+            return false;
+        }
+
+        self.held_ms += elapsed_ms;
+        if self.held_ms < self.stable_ms {
+            return false;
+        }
+
+        self.stable = self.candidate;
+        self.stable
+    }
+}
+
+/// Local push buttons sampled on their own thread. Presses are
+/// delivered as indexes into the button config.
+pub struct Buttons {
+    rx: Option<Receiver<usize>>,
+}
+
+impl Buttons {
+    const SAMPLE_MS: u32 = 10;
+    const STABLE_MS: u32 = 50;
+
+    pub fn start(config: &[ButtonConfig]) -> anyhow::Result<Self> {
+        if config.is_empty() {
+            return Ok(Self { rx: None });
+        }
+
+        let mut pins: Vec<(PinDriver<'static, AnyInputPin, Input>, bool, Debounce)> = Vec::new();
+        for button in config {
+            let mut pin = PinDriver::input(input_pin(button.pin)?)?;
+            pin.set_pull(if button.active_low {
+                Pull::Up
+            } else {
+                Pull::Down
+            })?;
+            pins.push((pin, button.active_low, Debounce::new(Self::STABLE_MS)));
+            info!(
+                "button gpio{} {:?} {}",
+                button.pin, button.action, button.relay
+            );
+        }
+
+        let (tx, rx) = mpsc::channel();
+        std::thread::Builder::new()
+            .stack_size(4096)
+            .spawn(move || loop {
+                for (idx, (pin, active_low, debounce)) in pins.iter_mut().enumerate() {
+                    let pressed = pin.is_high() != *active_low;
+                    if debounce.update(pressed, Self::SAMPLE_MS) && tx.send(idx).is_err() {
+                        warn!("button receiver dropped");
+                        return;
+                    }
+                }
+                FreeRtos::delay_ms(Self::SAMPLE_MS);
+            })?;
+
+        Ok(Self { rx: Some(rx) })
+    }
+
+    /// Wait up to `timeout`, returning early with a button press.
+    pub fn wait(&self, timeout: Duration) -> Option<usize> {
+        let rx = match &self.rx {
+            Some(rx) => rx,
+            None => {
+                FreeRtos::delay_ms(timeout.as_millis() as u32);
+                return None;
+            }
+        };
+
+        match rx.recv_timeout(timeout) {
+            Ok(idx) => Some(idx),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                FreeRtos::delay_ms(timeout.as_millis() as u32);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,178 @@
+/// Minimal PNG line chart: a 1 bit indexed image with a frame and the
+/// series drawn as a polyline, encoded with stored (uncompressed)
+/// deflate blocks so no compression library is needed.
+pub struct LineChart {
+    width: u32,
+    height: u32,
+    /// packed rows of 1 bit pixels, 1 is ink
+    pixels: Vec<u8>,
+}
+
+impl LineChart {
+    const MARGIN: u32 = 4;
+
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; (Self::row_bytes(width) * height) as usize],
+        }
+    }
+
+    #[inline]
+    fn row_bytes(width: u32) -> u32 {
+        width.div_ceil(8)
+    }
+
+    fn set(&mut self, x: i32, y: i32) {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return;
+        }
+        let idx = (y as u32 * Self::row_bytes(self.width) + x as u32 / 8) as usize;
+        self.pixels[idx] |= 0x80 >> (x % 8);
+    }
+
+    /// Bresenham line
+    fn line(&mut self, (mut x0, mut y0): (i32, i32), (x1, y1): (i32, i32)) {
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (sx, sy) = (if x0 < x1 { 1 } else { -1 }, if y0 < y1 { 1 } else { -1 });
+        let mut err = dx + dy;
+        loop {
+            self.set(x0, y0);
+            if x0 == x1 && y0 == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x0 += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y0 += sy;
+            }
+        }
+    }
+
+    /// Draw `points` scaled to the frame. A gap longer than `max_gap`
+    /// seconds breaks the line.
+    pub fn plot(&mut self, points: &[(u64, f32)], max_gap: u64) {
+        let (w, h, m) = (self.width as i32, self.height as i32, Self::MARGIN as i32);
+        self.line((0, 0), (w - 1, 0));
+        self.line((0, h - 1), (w - 1, h - 1));
+        self.line((0, 0), (0, h - 1));
+        self.line((w - 1, 0), (w - 1, h - 1));
+
+        let (Some(first), Some(last)) = (points.first(), points.last()) else {
+            return;
+        };
+        let (min, max) = points
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(lo, hi), (_, v)| {
+                (lo.min(*v), hi.max(*v))
+            });
+        let span_t = (last.0 - first.0).max(1) as f32;
+        let span_v = if max - min > f32::EPSILON {
+            max - min
+        } else {
+            1.0
+        };
+
+        let to_xy = |(t, v): (u64, f32)| {
+            let x = m + ((t - first.0) as f32 / span_t * (w - 2 * m - 1) as f32) as i32;
+            let y = h - 1 - m - ((v - min) / span_v * (h - 2 * m - 1) as f32) as i32;
+            (x, y)
+        };
+
+        let mut prev: Option<(u64, f32)> = None;
+        for p in points {
+            match prev {
+                Some(pr) if p.0 - pr.0 <= max_gap => self.line(to_xy(pr), to_xy(*p)),
+                _ => {
+                    let (x, y) = to_xy(*p);
+                    self.set(x, y);
+                }
+            }
+            prev = Some(*p);
+        }
+    }
+
+    pub fn to_png(&self) -> Vec<u8> {
+        let row = Self::row_bytes(self.width) as usize;
+        // every scanline starts with filter type 0
+        let mut raw = Vec::with_capacity((row + 1) * self.height as usize);
+        for line in self.pixels.chunks(row) {
+            raw.push(0);
+            raw.extend_from_slice(line);
+        }
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&self.width.to_be_bytes());
+        ihdr.extend_from_slice(&self.height.to_be_bytes());
+        // bit depth 1, indexed color, deflate, no filter, no interlace
+        ihdr.extend_from_slice(&[1, 3, 0, 0, 0]);
+        // white background, dark blue ink
+        let plte = [0xFF, 0xFF, 0xFF, 0x1F, 0x3A, 0x93];
+
+        let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+        write_chunk(&mut png, b"IHDR", &ihdr);
+        write_chunk(&mut png, b"PLTE", &plte);
+        write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+        write_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// zlib stream of stored deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK: usize = 0xFFFF;
+    let mut out = Vec::with_capacity(data.len() + data.len() / MAX_BLOCK * 5 + 11);
+    out.extend_from_slice(&[0x78, 0x01]);
+
+    let mut blocks = data.chunks(MAX_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        out.push(blocks.peek().is_none() as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |mut crc, &byte| {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+        crc
+    })
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % MOD;
+        (a, (b + a) % MOD)
+    });
+    (b << 16) | a
+}
@@ -0,0 +1,394 @@
+use anyhow::Error;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use toml::{Table, Value};
+
+use crate::adc::Adc;
+use crate::feedback::FeedbackConfig;
+use crate::relay::RELAY_NAMES;
+use crate::schema::{self, holds_secret, is_secret, mask, VERSION};
+use crate::util::check_pin;
+use crate::AppConfig;
+
+/// Configuration kept in NVS. `cfg.toml` is compiled in as the defaults
+/// of the first boot and of a factory reset, afterwards the stored table
+/// is loaded, migrated to the current schema and validated.
+///
+/// A stored table that is invalid or failed the last start is kept, the
+/// defaults only run that boot so `/config set` can still repair it.
+pub struct ConfigStore {
+    storage: EspNvs<NvsDefault>,
+    table: Table,
+    fallback: bool,
+}
+
+impl ConfigStore {
+    const VERSION_KEY: &'static str = "version";
+    const TABLE_KEY: &'static str = "toml";
+    const AP_PASSWORD_KEY: &'static str = "ap_password";
+    const FALLBACK_KEY: &'static str = "fallback";
+    /// reply limit of the message queue
+    const MAX_REPLY: usize = 400;
+
+    pub fn load(
+        partition: EspDefaultNvsPartition,
+        defaults: &str,
+    ) -> anyhow::Result<(Self, AppConfig)> {
+        let mut storage = EspNvs::new(partition, "config", true)?;
+        let failed_start = storage.get_u8(Self::FALLBACK_KEY)?.is_some();
+        if failed_start {
+            storage.remove(Self::FALLBACK_KEY)?;
+        }
+        let mut store = Self {
+            storage,
+            table: Table::new(),
+            fallback: true,
+        };
+
+        // a stored table, even a broken one, is only replaced by a change
+        let mut stored = false;
+        match store.read() {
+            Ok(Some((version, table))) => {
+                stored = true;
+                match parse(&table) {
+                    Ok(_) if failed_start => {
+                        warn!("stored config failed the last start, using defaults");
+                        store.table = table;
+                    }
+                    Ok(cfg) => {
+                        info!("config loaded from nvs");
+                        store.table = table;
+                        store.fallback = false;
+                        if version < VERSION {
+                            store.save()?;
+                        }
+                        return Ok((store, cfg));
+                    }
+                    Err(err) => {
+                        warn!("stored config invalid, using defaults: {}", err);
+                        store.table = table;
+                    }
+                }
+            }
+            Ok(None) => info!("no stored config, using defaults"),
+            Err(err) => {
+                warn!("stored config unreadable, using defaults: {}", err);
+                stored = true;
+            }
+        }
+
+        let mut table: Table = toml::from_str(defaults)?;
+        // a cfg.toml kept from an older firmware
+        schema::migrate(&mut table, 1);
+        let cfg = parse(&table)?;
+        if !stored {
+            store.fallback = false;
+            store.table = table;
+            store.save()?;
+        } else if store.table.is_empty() {
+            store.table = table;
+        }
+        Ok((store, cfg))
+    }
+
+    /// The stored config is kept but this boot runs on the defaults.
+    pub fn is_fallback(&self) -> bool {
+        self.fallback
+    }
+
+    /// Start the next boot on the defaults after the stored config failed
+    /// to set up the device, e.g. a sensor that is not connected.
+    pub fn fall_back(&mut self) -> anyhow::Result<()> {
+        if !self.fallback {
+            self.storage.set_u8(Self::FALLBACK_KEY, 1)?;
+        }
+        Ok(())
+    }
+
+    /// stored schema version and table migrated to the current one
+    fn read(&self) -> anyhow::Result<Option<(u8, Table)>> {
+        let Some(version) = self.storage.get_u8(Self::VERSION_KEY)? else {
+            return Ok(None);
+        };
+        let Some(len) = self.storage.blob_len(Self::TABLE_KEY)? else {
+            return Ok(None);
+        };
+
+        let mut buf = vec![0u8; len];
+        let Some(bytes) = self.storage.get_blob(Self::TABLE_KEY, &mut buf)? else {
+            return Ok(None);
+        };
+        let mut table: Table = toml::from_str(std::str::from_utf8(bytes)?)?;
+
+        if version > VERSION {
+            // written by a newer firmware before a rollback, unknown
+            // fields are ignored so it likely still parses
+            warn!("config schema {} is newer than {}", version, VERSION);
+        }
+        schema::migrate(&mut table, version);
+        Ok(Some((version, table)))
+    }
+
+    fn save(&mut self) -> anyhow::Result<()> {
+        let text = toml::to_string(&self.table)?;
+        self.storage.set_blob(Self::TABLE_KEY, text.as_bytes())?;
+        self.storage.set_u8(Self::VERSION_KEY, VERSION)?;
+        Ok(())
+    }
+
+    /// Handle `/config get [path]`, e.g. `relay.channels.pompa_air.min_off`.
+    /// Secrets are masked.
+    pub fn get(&self, path: Option<&str>) -> anyhow::Result<String> {
+        let Some(path) = path else {
+            let keys: Vec<&str> = self.table.keys().map(String::as_str).collect();
+            return Ok(format!("Config sections: {}", keys.join(", ")));
+        };
+        if is_secret(path) {
+            return Err(Error::msg("secret values are not shown"));
+        }
+
+        let mut root = Value::Table(self.table.clone());
+        let mut value = &mut root;
+        for key in path.split('.') {
+            value = schema::child(value, key).ok_or(Error::msg(format!("{} not found", path)))?;
+        }
+        let mut value = value.clone();
+        mask(&mut value, path);
+
+        let mut text = match value {
+            Value::Table(t) => format!("[{}]\n{}", path, toml::to_string(&t)?),
+            v => format!("{} = {}", path, v),
+        };
+        if text.len() > Self::MAX_REPLY {
+            let mut end = Self::MAX_REPLY;
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            text.truncate(end);
+            text.push_str("\n...");
+        }
+        Ok(text)
+    }
+
+    /// Handle `/config set [path] [value]`. The value is read as TOML,
+    /// anything else as a string. The changed config must parse and pass
+    /// validation before it is stored, it applies after a restart.
+    pub fn set(&mut self, path: &str, raw: &str) -> anyhow::Result<String> {
+        if is_secret(path) || holds_secret(path) {
+            return Err(Error::msg("secret values cannot be changed over chat"));
+        }
+        let (table, value) = schema::assign(&self.table, path, raw)?;
+        parse(&table)?;
+        self.table = table;
+        self.save()?;
+        info!("config {} set to {}", path, value);
+        Ok(format!(
+            "{} = {}\nApplies after /config restart",
+            path, value
+        ))
+    }
+
+    /// Store the network chosen on the provisioning portal above the known
+    /// ones, and the bot token when one was entered. Unlike `/config` this
+    /// sets secrets.
+    pub fn provision(
+        &mut self,
+        ssid: &str,
+        password: &str,
+        bot_token: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let table = schema::with_network(&self.table, ssid, password, bot_token)?;
+        parse(&table)?;
+        self.table = table;
+        self.save()?;
+        info!("wifi credentials of {} stored", ssid);
+        Ok(())
+    }
+
+    /// Password of the setup access point without `ap_password`, made up
+    /// on the first boot. A factory reset keeps it, so a label written
+    /// from the boot log stays valid.
+    pub fn portal_password(&mut self) -> anyhow::Result<String> {
+        let mut buf = [0u8; 32];
+        if let Some(password) = self.storage.get_str(Self::AP_PASSWORD_KEY, &mut buf)? {
+            return Ok(password.to_owned());
+        }
+
+        // no look-alike characters, about 59 bits
+        const CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+        let password: String = (0..12)
+            .map(|_| {
+                let n = unsafe { esp_idf_svc::sys::esp_random() } as usize;
+                CHARS[n % CHARS.len()] as char
+            })
+            .collect();
+        self.storage.set_str(Self::AP_PASSWORD_KEY, &password)?;
+        info!("setup access point password generated");
+        Ok(password)
+    }
+
+    /// Forget the stored config, the next boot starts from the defaults.
+    pub fn reset(&mut self) -> anyhow::Result<()> {
+        self.storage.remove(Self::TABLE_KEY)?;
+        self.storage.remove(Self::VERSION_KEY)?;
+        warn!("config reset to defaults");
+        Ok(())
+    }
+}
+
+fn parse(table: &Table) -> anyhow::Result<AppConfig> {
+    let cfg: AppConfig = table.clone().try_into()?;
+    validate(&cfg)?;
+    Ok(cfg)
+}
+
+pub fn known_relay(name: &str) -> anyhow::Result<()> {
+    match RELAY_NAMES.contains(&name) || name == "both" {
+        true => Ok(()),
+        false => Err(Error::msg(format!("unknown relay {}", name))),
+    }
+}
+
+/// Names that become NVS keys: 1 to `max` ASCII letters, digits or
+/// underscores, so a key never has to be cut.
+pub fn check_name(name: &str, max: usize) -> anyhow::Result<()> {
+    match !name.is_empty()
+        && name.len() <= max
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+    {
+        true => Ok(()),
+        false => Err(Error::msg(format!(
+            "name {} must be 1 to {} letters, digits or underscores",
+            name, max
+        ))),
+    }
+}
+
+/// every name appears once
+fn check_unique<'a>(names: impl IntoIterator<Item = &'a str>) -> anyhow::Result<()> {
+    let mut seen = Vec::new();
+    for name in names {
+        if seen.contains(&name) {
+            return Err(Error::msg(format!("name {} used twice", name)));
+        }
+        seen.push(name);
+    }
+    Ok(())
+}
+
+/// alert thresholds shared by the sensor configs
+pub fn check_band(low: Option<f32>, high: Option<f32>, hysteresis: f32) -> anyhow::Result<()> {
+    if let (Some(low), Some(high)) = (low, high) {
+        if low >= high {
+            return Err(Error::msg("low must be below high"));
+        }
+    }
+    match hysteresis >= 0.0 {
+        true => Ok(()),
+        false => Err(Error::msg("hysteresis must not be negative")),
+    }
+}
+
+/// Configured GPIOs with their use, so a pin conflict or a pin without
+/// ADC is refused before it fails the next start.
+fn check_pins(cfg: &AppConfig) -> anyhow::Result<()> {
+    let mut pins: Vec<(u8, bool, String)> = Vec::new();
+    if let Some(pin) = cfg.wifi.provision_pin {
+        pins.push((pin, false, "wifi.provision_pin".to_owned()));
+    }
+    for (name, channel) in &cfg.relay.channels {
+        match channel.feedback {
+            Some(FeedbackConfig::Input { pin, .. }) => {
+                pins.push((pin, false, format!("{} feedback", name)))
+            }
+            Some(FeedbackConfig::Current { pin, .. }) => {
+                pins.push((pin, true, format!("{} feedback", name)))
+            }
+            None => {}
+        }
+        if let Some(flow) = &channel.flow {
+            pins.push((flow.pin, false, format!("{} flow", name)));
+        }
+    }
+    for (idx, button) in cfg.button.iter().enumerate() {
+        pins.push((button.pin, false, format!("button {}", idx)));
+    }
+    for (idx, level) in cfg.level.iter().enumerate() {
+        pins.push((level.pin, false, format!("level {}", idx)));
+    }
+    for temperature in &cfg.temperature {
+        pins.push((temperature.pin, false, temperature.name.clone()));
+    }
+    for analog in &cfg.analog {
+        pins.push((analog.pin, true, analog.name.clone()));
+    }
+
+    for (idx, (pin, adc, user)) in pins.iter().enumerate() {
+        check_pin(*pin).map_err(|e| Error::msg(format!("{}: {}", user, e)))?;
+        if *adc && !Adc::PINS.contains(pin) {
+            return Err(Error::msg(format!(
+                "{}: gpio{} is not an ADC1 pin",
+                user, pin
+            )));
+        }
+        if let Some((_, _, other)) = pins[..idx].iter().find(|(p, _, _)| p == pin) {
+            return Err(Error::msg(format!(
+                "gpio{} used by {} and {}",
+                pin, other, user
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn validate(cfg: &AppConfig) -> anyhow::Result<()> {
+    let in_section = |section: &str, err: Error| Error::msg(format!("{}: {}", section, err));
+
+    cfg.wifi.validate().map_err(|e| in_section("wifi", e))?;
+    if cfg.telegram.bot_token.is_empty() || !cfg.telegram.api_base.starts_with("https://") {
+        return Err(Error::msg(
+            "telegram: bot_token and an https api_base are required",
+        ));
+    }
+    for name in cfg.relay.channels.keys() {
+        known_relay(name).map_err(|e| in_section("relay", e))?;
+    }
+    for button in &cfg.button {
+        known_relay(&button.relay).map_err(|e| in_section("button", e))?;
+    }
+    for item in &cfg.maintenance {
+        item.validate().map_err(|e| in_section("maintenance", e))?;
+    }
+    check_unique(cfg.maintenance.iter().map(|m| m.name.as_str()))
+        .map_err(|e| in_section("maintenance", e))?;
+    for level in &cfg.level {
+        level.validate().map_err(|e| in_section("level", e))?;
+    }
+    for temperature in &cfg.temperature {
+        temperature
+            .validate()
+            .map_err(|e| in_section("temperature", e))?;
+    }
+    for analog in &cfg.analog {
+        analog.validate().map_err(|e| in_section("analog", e))?;
+    }
+    // temperature and analog sensors share the rule and history names
+    let sensors = cfg.temperature.iter().map(|t| t.name.as_str());
+    check_unique(sensors.chain(cfg.analog.iter().map(|a| a.name.as_str())))
+        .map_err(|e| in_section("sensors", e))?;
+    for rule in &cfg.rule {
+        rule.validate().map_err(|e| in_section("rule", e))?;
+    }
+    check_unique(cfg.rule.iter().map(|r| r.name.as_str())).map_err(|e| in_section("rule", e))?;
+    if let Some(mqtt) = &cfg.mqtt {
+        mqtt.validate().map_err(|e| in_section("mqtt", e))?;
+    }
+    if let Some(api) = &cfg.api {
+        if api.port == 0 || api.tokens.iter().any(|t| t.token.len() < 8) {
+            return Err(Error::msg(
+                "api: port and tokens of 8 characters are required",
+            ));
+        }
+    }
+    check_pins(cfg).map_err(|e| in_section("pins", e))
+}
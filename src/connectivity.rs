@@ -0,0 +1,152 @@
+use log::{info, warn};
+use std::fmt::Display;
+use std::net::ToSocketAddrs;
+
+use crate::util::uptime;
+
+/// How far the network reaches, worst first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkState {
+    NoWifi,
+    NoInternet,
+    TelegramDown,
+    Online,
+}
+
+impl Display for LinkState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            LinkState::NoWifi => "no Wi-Fi",
+            LinkState::NoInternet => "no internet",
+            LinkState::TelegramDown => "Telegram unreachable",
+            LinkState::Online => "online",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+/// Seconds spent in every offline state of one outage
+struct Outage {
+    started_at: u64,
+    seconds: [u64; 3],
+}
+
+impl Outage {
+    fn summary(&self, now: u64) -> String {
+        let states = [
+            LinkState::NoWifi,
+            LinkState::NoInternet,
+            LinkState::TelegramDown,
+        ];
+        let parts: Vec<String> = states
+            .iter()
+            .zip(self.seconds)
+            .filter(|(_, secs)| *secs > 0)
+            .map(|(state, secs)| format!("{} {}", state, fmt_span(secs)))
+            .collect();
+        format!(
+            "Back online after {} offline ({})",
+            fmt_span(now - self.started_at),
+            parts.join(", ")
+        )
+    }
+}
+
+/// Link state of the main loop. Relays, buttons, rules and the local API
+/// never wait on it, only Telegram traffic is gated while notifications
+/// stay in the flash queue until the link is back. Times are uptime, so
+/// they hold without NTP.
+pub struct Connectivity {
+    state: LinkState,
+    changed_at: u64,
+    /// host of the Telegram API, resolved to tell a dead uplink from
+    /// Telegram being unreachable
+    host: String,
+    outage: Option<Outage>,
+}
+
+impl Connectivity {
+    pub fn new(api_base: &str) -> Self {
+        let host = api_base
+            .split("://")
+            .last()
+            .and_then(|rest| rest.split(['/', ':']).next())
+            .unwrap_or_default()
+            .to_owned();
+        Self {
+            state: LinkState::Online,
+            changed_at: uptime(),
+            host,
+            outage: None,
+        }
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.state == LinkState::Online
+    }
+
+    /// A Telegram request failed, find out how far the network reaches.
+    pub fn telegram_failed(&mut self, wifi_connected: bool) {
+        let state = if !wifi_connected {
+            LinkState::NoWifi
+        } else if self.resolves() {
+            LinkState::TelegramDown
+        } else {
+            LinkState::NoInternet
+        };
+        self.set(state);
+    }
+
+    fn resolves(&self) -> bool {
+        (self.host.as_str(), 443)
+            .to_socket_addrs()
+            .map(|mut addrs| addrs.next().is_some())
+            .unwrap_or(false)
+    }
+
+    /// Move to `state`, returns the outage summary when the link is back.
+    pub fn set(&mut self, state: LinkState) -> Option<String> {
+        if state == self.state {
+            return None;
+        }
+
+        let now = uptime();
+        let previous = self.state;
+        if let Some(outage) = self.outage.as_mut() {
+            outage.seconds[previous as usize] += now - self.changed_at;
+        }
+        self.state = state;
+        self.changed_at = now;
+
+        match (previous, state) {
+            (LinkState::Online, _) => {
+                warn!("link down: {}", state);
+                self.outage = Some(Outage {
+                    started_at: now,
+                    seconds: [0; 3],
+                });
+                None
+            }
+            (_, LinkState::Online) => {
+                info!("link back online");
+                self.outage.take().map(|outage| outage.summary(now))
+            }
+            _ => {
+                warn!("link {} -> {}", previous, state);
+                None
+            }
+        }
+    }
+}
+
+fn fmt_span(secs: u64) -> String {
+    match secs {
+        s if s >= 3600 => format!("{}h {}m", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
@@ -0,0 +1,131 @@
+use core::ffi::c_void;
+use esp_idf_svc::hal::gpio::{AnyInputPin, Input, PinDriver, Pull};
+use esp_idf_svc::sys::{self, esp};
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::util::input_pin;
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct FlowConfig {
+    pub pin: u8,
+    /// sensor K factor, e.g. 450 for a YF-S201
+    pulses_per_litre: f32,
+    /// stop the relay when the flow stays below, 0 disables the check
+    #[serde(default)]
+    min_lpm: f32,
+    /// seconds after switching on before the minimum flow applies
+    #[serde(default = "FlowConfig::default_grace")]
+    grace: u32,
+}
+
+impl FlowConfig {
+    fn default_grace() -> u32 {
+        30
+    }
+}
+
+/// litres per minute of `pulses` counted over `secs`
+pub fn flow_rate(pulses: u32, secs: u64, pulses_per_litre: f32) -> f32 {
+    if secs == 0 || pulses_per_litre <= 0.0 {
+        return 0.0;
+    }
+    pulses as f32 / pulses_per_litre * 60.0 / secs as f32
+}
+
+/// Hall effect flow sensor counted from a GPIO interrupt. The ISR stays
+/// armed, unlike the hal subscription that disarms after every edge.
+pub struct FlowMeter {
+    _pin: PinDriver<'static, AnyInputPin, Input>,
+    pulses: &'static AtomicU32,
+    pulses_per_litre: f32,
+    min_lpm: f32,
+    grace: u64,
+    /// pulse count and time of the previous sample
+    last: (u32, u64),
+    lpm: f32,
+}
+
+unsafe extern "C" fn count_pulse(arg: *mut c_void) {
+    let pulses = &*(arg as *const AtomicU32);
+    pulses.fetch_add(1, Ordering::Relaxed);
+}
+
+impl FlowMeter {
+    pub fn from_config(config: &FlowConfig) -> anyhow::Result<Self> {
+        let mut pin = PinDriver::input(input_pin(config.pin)?)?;
+        pin.set_pull(Pull::Up)?;
+
+        // one counter per configured sensor, alive for the whole program
+        let pulses: &'static AtomicU32 = Box::leak(Box::new(AtomicU32::new(0)));
+        let gpio = config.pin as sys::gpio_num_t;
+        unsafe {
+            let installed = sys::gpio_install_isr_service(0);
+            if installed != sys::ESP_ERR_INVALID_STATE {
+                esp!(installed)?;
+            }
+            esp!(sys::gpio_set_intr_type(
+                gpio,
+                sys::gpio_int_type_t_GPIO_INTR_POSEDGE
+            ))?;
+            esp!(sys::gpio_isr_handler_add(
+                gpio,
+                Some(count_pulse),
+                pulses as *const AtomicU32 as *mut c_void
+            ))?;
+            esp!(sys::gpio_intr_enable(gpio))?;
+        }
+
+        Ok(Self {
+            _pin: pin,
+            pulses,
+            pulses_per_litre: config.pulses_per_litre,
+            min_lpm: config.min_lpm,
+            grace: config.grace as u64,
+            last: (0, 0),
+            lpm: 0.0,
+        })
+    }
+
+    #[inline]
+    pub fn count(&self) -> u32 {
+        self.pulses.load(Ordering::Relaxed)
+    }
+
+    /// follow the clock moved ahead by `secs`
+    pub fn shift_clock(&mut self, secs: u64) {
+        if self.last.1 != 0 {
+            self.last.1 += secs;
+        }
+    }
+
+    /// update the flow rate from the pulses since the previous sample
+    pub fn sample(&mut self, now: u64) -> f32 {
+        let count = self.count();
+        let (last_count, last_time) = self.last;
+        if last_time != 0 && now > last_time {
+            self.lpm = flow_rate(
+                count.wrapping_sub(last_count),
+                now - last_time,
+                self.pulses_per_litre,
+            );
+        }
+        self.last = (count, now);
+        self.lpm
+    }
+
+    #[inline]
+    pub fn lpm(&self) -> f32 {
+        self.lpm
+    }
+
+    /// litres passed since the pulse count `since`
+    pub fn litres(&self, since: u32) -> f32 {
+        self.count().wrapping_sub(since) as f32 / self.pulses_per_litre
+    }
+
+    /// flow below the minimum once the pump had its grace period
+    pub fn is_dry(&self, running_secs: u64) -> bool {
+        self.min_lpm > 0.0 && running_secs >= self.grace && self.lpm < self.min_lpm
+    }
+}
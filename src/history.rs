@@ -0,0 +1,394 @@
+use anyhow::Error;
+use esp_idf_svc::nvs::{EspCustomNvsPartition, EspNvs, NvsCustom};
+use log::{info, warn};
+use std::fmt::Write;
+
+use crate::chart::LineChart;
+use crate::rule::RuleInputs;
+use crate::telegram::SendFile;
+use crate::util::Time;
+
+/// Resolution of a time series. Samples are grouped in pages written as
+/// one blob, and pages sit in a ring addressed by their start time, so a
+/// page is rewritten only once per lap of the ring.
+#[derive(Clone, Copy)]
+struct Tier {
+    /// seconds per sample
+    step: u64,
+    /// samples per page
+    page_len: u64,
+    /// pages in the ring
+    pages: u64,
+    /// key tag of the tier
+    tag: char,
+}
+
+impl Tier {
+    #[inline]
+    fn span(&self) -> u64 {
+        self.step * self.page_len
+    }
+
+    #[inline]
+    fn coverage(&self) -> u64 {
+        self.span() * (self.pages - 1)
+    }
+}
+
+/// 1 minute for a day, 15 minutes for a month
+const TIERS: [Tier; 2] = [
+    Tier {
+        step: 60,
+        page_len: 60,
+        pages: 25,
+        tag: 'f',
+    },
+    Tier {
+        step: 900,
+        page_len: 96,
+        pages: 32,
+        tag: 'c',
+    },
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum SeriesKind {
+    Sensor,
+    /// 1 while the relay has a running order, averaged into a duty
+    Relay,
+}
+
+/// page of a tier, missing samples are NaN
+struct Page {
+    start: u64,
+    samples: Vec<f32>,
+}
+
+impl Page {
+    fn empty(start: u64, tier: &Tier) -> Self {
+        Self {
+            start,
+            samples: vec![f32::NAN; tier.page_len as usize],
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.samples.len() * 4);
+        bytes.extend_from_slice(&(self.start as u32).to_be_bytes());
+        for s in &self.samples {
+            bytes.extend_from_slice(&s.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8], tier: &Tier) -> Option<Self> {
+        let (start, rest) = bytes.split_first_chunk::<4>()?;
+        if rest.len() != tier.page_len as usize * 4 {
+            return None;
+        }
+
+        let samples = rest
+            .chunks_exact(4)
+            .map(|c| f32::from_be_bytes(c.try_into().unwrap()))
+            .collect();
+        Some(Self {
+            start: u32::from_be_bytes(*start) as u64,
+            samples,
+        })
+    }
+}
+
+struct TierLog {
+    tier: Tier,
+    page: Page,
+    /// slot being averaged: (slot start, sum, count)
+    acc: (u64, f32, u32),
+    /// the page has samples not yet written
+    dirty: bool,
+    flushed_at: u64,
+}
+
+struct Series {
+    name: String,
+    kind: SeriesKind,
+    tiers: Vec<TierLog>,
+}
+
+/// Sensor readings and relay states logged into a dedicated NVS
+/// partition, averaged per slot of every tier.
+pub struct HistoryLog {
+    storage: EspNvs<NvsCustom>,
+    series: Vec<Series>,
+}
+
+impl HistoryLog {
+    /// NVS keys are at most 15 characters: name, '.', tag and page number
+    pub const MAX_NAME: usize = 10;
+    /// seconds between writes of a partial page, bounds the loss on reboot
+    const FLUSH_INTERVAL: u64 = 900;
+    const COLUMNS: usize = 48;
+    const CHART_WIDTH: u32 = 480;
+    const CHART_HEIGHT: u32 = 240;
+
+    pub fn new(
+        partition: EspCustomNvsPartition,
+        sensors: &[&str],
+        relays: &[&str],
+    ) -> anyhow::Result<Self> {
+        let storage = EspNvs::new(partition, "history", true)?;
+        let mut log = Self {
+            storage,
+            series: Vec::new(),
+        };
+
+        let now = crate::util::sys_now();
+        let named = sensors
+            .iter()
+            .map(|n| (n, SeriesKind::Sensor))
+            .chain(relays.iter().map(|n| (n, SeriesKind::Relay)));
+        for (name, kind) in named {
+            let tiers = TIERS
+                .iter()
+                .map(|tier| {
+                    let start = now / tier.span() * tier.span();
+                    let page = log
+                        .load_page(name, tier, start)
+                        .unwrap_or_else(|| Page::empty(start, tier));
+                    TierLog {
+                        tier: *tier,
+                        page,
+                        acc: (now / tier.step * tier.step, 0.0, 0),
+                        dirty: false,
+                        flushed_at: now,
+                    }
+                })
+                .collect();
+            log.series.push(Series {
+                name: name.to_string(),
+                kind,
+                tiers,
+            });
+        }
+
+        info!("history of {} series", log.series.len());
+        Ok(log)
+    }
+
+    /// `name` is at most `MAX_NAME` bytes, checked with the config
+    fn page_key(name: &str, tier: &Tier, start: u64) -> String {
+        format!(
+            "{}.{}{:02}",
+            name,
+            tier.tag,
+            start / tier.span() % tier.pages
+        )
+    }
+
+    /// stored page starting at `start`, None when missing or overwritten
+    fn load_page(&self, name: &str, tier: &Tier, start: u64) -> Option<Page> {
+        let mut buf = vec![0u8; 4 + tier.page_len as usize * 4];
+        let key = Self::page_key(name, tier, start);
+        let bytes = self.storage.get_blob(&key, &mut buf).ok().flatten()?;
+        Page::from_bytes(bytes, tier).filter(|p| p.start == start)
+    }
+
+    fn store_page(storage: &mut EspNvs<NvsCustom>, name: &str, log: &mut TierLog, now: u64) {
+        let key = Self::page_key(name, &log.tier, log.page.start);
+        match storage.set_blob(&key, &log.page.to_bytes()) {
+            Ok(()) => log.dirty = false,
+            Err(err) => warn!("history write {} error: {}", key, err),
+        }
+        log.flushed_at = now;
+    }
+
+    /// add the current readings, called every tick
+    pub fn record(&mut self, now: u64, inputs: &dyn RuleInputs) {
+        for series in self.series.iter_mut() {
+            let value = match series.kind {
+                SeriesKind::Sensor => inputs.sensor(&series.name),
+                SeriesKind::Relay => inputs.relay_running(&series.name).map(|on| on as u8 as f32),
+            };
+
+            for log in series.tiers.iter_mut() {
+                let tier = log.tier;
+                let slot = now / tier.step * tier.step;
+                let (acc_slot, sum, count) = log.acc;
+                if slot != acc_slot {
+                    if count > 0 {
+                        let page_start = acc_slot / tier.span() * tier.span();
+                        if page_start != log.page.start {
+                            if log.dirty {
+                                Self::store_page(&mut self.storage, &series.name, log, now);
+                            }
+                            log.page = Page::empty(page_start, &tier);
+                        }
+
+                        let idx = ((acc_slot - page_start) / tier.step) as usize;
+                        log.page.samples[idx] = sum / count as f32;
+                        log.dirty = true;
+                    }
+                    log.acc = (slot, 0.0, 0);
+                }
+
+                if let Some(v) = value {
+                    log.acc.1 += v;
+                    log.acc.2 += 1;
+                }
+
+                if log.dirty && now >= log.flushed_at + Self::FLUSH_INTERVAL {
+                    Self::store_page(&mut self.storage, &series.name, log, now);
+                }
+            }
+        }
+    }
+
+    /// samples of `name` over the last `range` seconds with their step
+    pub fn query(
+        &self,
+        name: &str,
+        range: u64,
+        now: u64,
+    ) -> anyhow::Result<(Vec<(u64, f32)>, u64)> {
+        let series = self
+            .series
+            .iter()
+            .find(|s| s.name == name)
+            .ok_or(Error::msg("unknown series"))?;
+        let log = series
+            .tiers
+            .iter()
+            .find(|l| range <= l.tier.coverage())
+            .unwrap_or(series.tiers.last().unwrap());
+        let tier = log.tier;
+
+        let from = now.saturating_sub(range.min(tier.coverage()));
+        let mut points = Vec::new();
+        let mut start = from / tier.span() * tier.span();
+        while start <= now {
+            let stored;
+            let page = if start == log.page.start {
+                &log.page
+            } else {
+                match self.load_page(&series.name, &tier, start) {
+                    Some(p) => {
+                        stored = p;
+                        &stored
+                    }
+                    None => {
+                        start += tier.span();
+                        continue;
+                    }
+                }
+            };
+
+            for (i, v) in page.samples.iter().enumerate() {
+                let t = page.start + i as u64 * tier.step;
+                if t >= from && !v.is_nan() {
+                    points.push((t, *v));
+                }
+            }
+            start += tier.span();
+        }
+        Ok((points, tier.step))
+    }
+
+    /// CSV of the range in local time, the oldest rows are dropped to
+    /// fit the upload limit. Returns the CSV and whether it was cut.
+    pub fn csv(&self, name: &str, range: u64, now: u64) -> anyhow::Result<(Vec<u8>, bool)> {
+        let (points, _) = self.query(name, range, now)?;
+        const HEADER: &str = "time_wib,epoch,value\n";
+        let mut rows = Vec::with_capacity(points.len());
+        let mut size = HEADER.len();
+        for (t, v) in points.iter().rev() {
+            let time = Time::new(*t).to_string();
+            let row = format!("{},{},{:.3}\n", time.trim_end_matches(" WIB"), t, v);
+            if size + row.len() > SendFile::MAX_SIZE {
+                break;
+            }
+            size += row.len();
+            rows.push(row);
+        }
+
+        let cut = rows.len() < points.len();
+        let mut out = String::with_capacity(size);
+        out.push_str(HEADER);
+        rows.iter().rev().for_each(|r| out.push_str(r));
+        Ok((out.into_bytes(), cut))
+    }
+
+    /// PNG line chart of the range, gaps of missing samples stay open
+    pub fn chart(&self, name: &str, range: u64, now: u64) -> anyhow::Result<Vec<u8>> {
+        let (points, step) = self.query(name, range, now)?;
+        if points.is_empty() {
+            return Err(Error::msg(format!("No history of {} yet", name)));
+        }
+
+        let mut chart = LineChart::new(Self::CHART_WIDTH, Self::CHART_HEIGHT);
+        chart.plot(&points, step * 2);
+        Ok(chart.to_png())
+    }
+
+    /// Handle `/history [name] [range]` with a sparkline of the range.
+    pub fn report(&self, name: &str, range: u64, now: u64) -> anyhow::Result<String> {
+        let (points, step) = self.query(name, range, now)?;
+        let (Some(first), Some(last)) = (points.first(), points.last()) else {
+            return Ok(format!("No history of {} yet", name));
+        };
+
+        let (min, max, sum) = points
+            .iter()
+            .fold((f32::MAX, f32::MIN, 0.0), |(lo, hi, s), (_, v)| {
+                (lo.min(*v), hi.max(*v), s + v)
+            });
+        let avg = sum / points.len() as f32;
+
+        let mut out = format!("History of {} ({} min samples)\n", name, step / 60);
+        let is_relay = self
+            .series
+            .iter()
+            .any(|s| s.name == name && s.kind == SeriesKind::Relay);
+        if is_relay {
+            let _ = writeln!(out, "on {:.0}% of the time", avg * 100.0);
+        } else {
+            let _ = writeln!(out, "min {:.2} max {:.2} avg {:.2}", min, max, avg);
+        }
+        let _ = write!(
+            out,
+            "{}\n{} -\n{}",
+            sparkline(&points, Self::COLUMNS, min, max),
+            Time::new(first.0),
+            Time::new(last.0)
+        );
+        Ok(out)
+    }
+}
+
+/// Points averaged into `columns` buckets by time, drawn with block
+/// characters between `min` and `max`. Empty buckets are blank.
+pub fn sparkline(points: &[(u64, f32)], columns: usize, min: f32, max: f32) -> String {
+    const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let (Some(first), Some(last)) = (points.first(), points.last()) else {
+        return String::new();
+    };
+
+    let width = (last.0 - first.0) / columns as u64 + 1;
+    let mut buckets = vec![(0.0f32, 0u32); columns];
+    for (t, v) in points {
+        let idx = (((t - first.0) / width) as usize).min(columns - 1);
+        buckets[idx].0 += v;
+        buckets[idx].1 += 1;
+    }
+
+    let range = max - min;
+    buckets
+        .iter()
+        .map(|(sum, count)| match count {
+            0 => ' ',
+            _ if range <= f32::EPSILON => BLOCKS[3],
+            _ => {
+                let level = (sum / *count as f32 - min) / range * 7.0;
+                BLOCKS[(level.round() as usize).min(7)]
+            }
+        })
+        .collect()
+}
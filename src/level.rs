@@ -0,0 +1,132 @@
+use esp_idf_svc::hal::gpio::{AnyInputPin, Input, OutputPin, PinDriver, Pull};
+use log::info;
+use serde::Deserialize;
+use std::fmt::Write;
+
+use crate::relay::{DoubleRelay, StopCause};
+use crate::util::input_pin;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LevelKind {
+    /// tripped when the water reaches the switch, stops filling
+    High,
+    /// tripped when the water drops below the switch, protects from dry run
+    Low,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LevelConfig {
+    name: String,
+    pub pin: u8,
+    kind: LevelKind,
+    /// tripped when the pin reads low
+    #[serde(default)]
+    active_low: bool,
+    /// relays stopped and refused to start while tripped
+    relays: Vec<String>,
+}
+
+impl LevelConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.relays
+            .iter()
+            .try_for_each(|r| crate::config::known_relay(r))
+    }
+}
+
+struct LevelSwitch<'cfg> {
+    config: &'cfg LevelConfig,
+    pin: PinDriver<'static, AnyInputPin, Input>,
+    tripped: bool,
+    /// last raw read, a change is accepted after two equal reads
+    candidate: bool,
+}
+
+impl<'cfg> LevelSwitch<'cfg> {
+    fn sample(&mut self) -> bool {
+        let raw = self.pin.is_high() != self.config.active_low;
+        if raw == self.candidate && raw != self.tripped {
+            info!("level {} tripped: {}", self.config.name, raw);
+            self.tripped = raw;
+        }
+        self.candidate = raw;
+        self.tripped
+    }
+
+    fn cause(&self) -> StopCause {
+        match self.config.kind {
+            LevelKind::High => StopCause::LevelHigh,
+            LevelKind::Low => StopCause::DryRun,
+        }
+    }
+}
+
+/// Float switches guarding the relays they are configured for.
+pub struct LevelSwitches<'cfg> {
+    switches: Vec<LevelSwitch<'cfg>>,
+}
+
+impl<'cfg> LevelSwitches<'cfg> {
+    pub fn new(config: &'cfg [LevelConfig]) -> anyhow::Result<Self> {
+        let mut switches = Vec::with_capacity(config.len());
+        for level in config {
+            let mut pin = PinDriver::input(input_pin(level.pin)?)?;
+            pin.set_pull(if level.active_low {
+                Pull::Up
+            } else {
+                Pull::Down
+            })?;
+            switches.push(LevelSwitch {
+                config: level,
+                pin,
+                tripped: false,
+                candidate: false,
+            });
+        }
+        Ok(Self { switches })
+    }
+
+    /// Sample every switch and set the interlock of each relay, dry run
+    /// wins over high level when both are tripped.
+    pub fn apply<R1, R2>(&mut self, relay: &mut DoubleRelay<'_, R1, R2>)
+    where
+        R1: OutputPin,
+        R2: OutputPin,
+    {
+        self.switches.iter_mut().for_each(|s| {
+            s.sample();
+        });
+
+        for name in relay.names() {
+            let cause = self
+                .switches
+                .iter()
+                .filter(|s| s.tripped && s.config.relays.iter().any(|r| r == name))
+                .map(|s| s.cause())
+                .max_by_key(|c| matches!(c, StopCause::DryRun));
+
+            if let Some(addr) = relay.resolve_addr(name) {
+                relay.set_interlock(addr, cause);
+            }
+        }
+    }
+
+    pub fn report(&self) -> String {
+        if self.switches.is_empty() {
+            return String::from("No level switch configured");
+        }
+
+        let mut out = String::new();
+        for s in &self.switches {
+            let _ = writeln!(
+                out,
+                "{} ({:?}): {}",
+                s.config.name,
+                s.config.kind,
+                if s.tripped { "tripped" } else { "normal" }
+            );
+        }
+        out.trim_end().to_owned()
+    }
+}
@@ -1,4 +1,9 @@
+use analog::{AnalogConfig, AnalogSensors};
 use anyhow::Error;
+use api::{ApiBackend, ApiConfig};
+use buttons::{ButtonAction, ButtonConfig, Buttons};
+use config::ConfigStore;
+use connectivity::{Connectivity, LinkState};
 use core::str;
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
@@ -8,42 +13,147 @@ use esp_idf_svc::{
         prelude::Peripherals,
     },
     http::client::{Configuration as HttpConfiguration, EspHttpConnection},
-    nvs::EspDefaultNvsPartition,
-    wifi::{BlockingWifi, EspWifi},
+    netif::{EspNetif, NetifStack},
+    nvs::{EspCustomNvsPartition, EspDefaultNvsPartition},
+    wifi::{BlockingWifi, EspWifi, WifiDriver},
 };
+use feedback::FeedbackConfig;
+use flow::{FlowConfig, FlowMeter};
+use history::HistoryLog;
+use level::{LevelConfig, LevelSwitches};
 use log::{info, warn};
+use maintenance::{Maintenance, MaintenanceConfig};
+use mqtt::{Mqtt, MqttConfig, SensorInfo};
+use ota::{HealthWindow, OtaRequest};
+use provision::ProvisionButton;
 use queue::MsgFMQueue;
-use relay::{DoubleRelay, DoubleRelayStatus, RelayQuery, SetState};
+use relay::{Cycle, DoubleRelay, DoubleRelayStatus, Origin, RelayAddr, RelayQuery, SetState};
+use rule::RuleInputs;
+use rules::{Fired, RuleConfig, RuleEngine};
 use serde::Deserialize;
-use std::time::Duration;
-use telegram::{SendMessage, TeleAPI};
-use util::{connect_wifi, ensure_wifi_connected, sync_ntp};
+use serde_json::{json, Value};
+use stats::StatsStore;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use telegram::{FileKind, SendFile, SendMessage, TeleAPI};
+use temperature::{HookConfig, TemperatureConfig, TemperatureSensors};
+use util::{clock_set, device_id, free_heap, sys_now, uptime, Ntp, Time};
+use web::WebServer;
+use wifi::{sta_netif, WifiConfig, WifiLink};
 
+// hardware-free modules, tested on the host
+use pomel_core::duration::{parse_duration, INVALID_UNIT};
+use pomel_core::{api, band, calibration, ds18b20, feedback, rule, schema, text};
+
+mod adc;
+mod analog;
+mod buttons;
+mod chart;
+mod config;
+mod connectivity;
+mod flow;
+mod history;
+mod level;
+mod maintenance;
+mod mdns;
+mod mqtt;
+mod ota;
+mod probe;
+mod provision;
 pub mod queue;
 mod relay;
+mod rules;
+mod stats;
 mod telegram;
+mod temperature;
 pub mod util;
+mod web;
+mod wifi;
 
 #[derive(Deserialize, Debug)]
 struct AppConfig {
     wifi: WifiConfig,
     telegram: TelegramConfig,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct WifiConfig {
-    ssid: String,
-    password: String,
+    #[serde(default)]
+    relay: RelayConfig,
+    #[serde(default)]
+    maintenance: Vec<MaintenanceConfig>,
+    /// local push buttons, usable without network
+    #[serde(default)]
+    button: Vec<ButtonConfig>,
+    /// float switches guarding relays
+    #[serde(default)]
+    level: Vec<LevelConfig>,
+    /// DS18B20 water temperature probes
+    #[serde(default)]
+    temperature: Vec<TemperatureConfig>,
+    /// calibrated analog probes, e.g. pH or dissolved oxygen
+    #[serde(default)]
+    analog: Vec<AnalogConfig>,
+    /// sensor driven relay automation
+    #[serde(default)]
+    rule: Vec<RuleConfig>,
+    /// MQTT transport next to Telegram, disabled when missing
+    #[serde(default)]
+    mqtt: Option<MqttConfig>,
+    /// local HTTP API and dashboard, disabled when missing
+    #[serde(default)]
+    api: Option<ApiConfig>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct TelegramConfig {
     api_base: String,
     bot_token: String,
+    /// chat ids allowed to use admin commands
+    #[serde(default)]
+    admins: Vec<u32>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct RelayConfig {
+    /// minimum seconds between two relay turn-on events
+    #[serde(default)]
+    start_gap: u32,
+    /// per relay settings keyed by relay name
+    #[serde(default)]
+    channels: HashMap<String, RelayChannelConfig>,
+}
+
+impl RelayConfig {
+    pub fn channel(&self, name: &str) -> RelayChannelConfig {
+        self.channels.get(name).copied().unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+pub struct RelayChannelConfig {
+    /// check the load follows the relay
+    #[serde(default)]
+    feedback: Option<FeedbackConfig>,
+    /// flow sensor measuring the pump output
+    #[serde(default)]
+    flow: Option<FlowConfig>,
+    /// minimum seconds a relay stays on once started
+    #[serde(default)]
+    min_on: u32,
+    /// minimum seconds a relay rests between stop and next start
+    #[serde(default)]
+    min_off: u32,
+    /// maximum run seconds per day, 0 is unlimited
+    #[serde(default)]
+    daily_max: u32,
+    /// what to do with an order exceeding `daily_max`
+    #[serde(default)]
+    daily_max_mode: BudgetMode,
 }
 
-fn load_config() -> AppConfig {
-    toml::from_str(include_str!("../cfg.toml")).expect("Failed to parse config")
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum BudgetMode {
+    #[default]
+    Reject,
+    Truncate,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -54,79 +164,358 @@ fn main() -> anyhow::Result<()> {
     // Bind the log crate to the ESP Logging facilities
     esp_idf_svc::log::EspLogger::initialize_default();
 
+    let nvs = EspDefaultNvsPartition::take()?;
+    // cfg.toml only provides the defaults of the first boot
+    let (mut config_store, cfg) = ConfigStore::load(nvs.clone(), include_str!("../cfg.toml"))?;
+
+    let result = run(nvs, &mut config_store, cfg);
+    if result.is_err() {
+        // e.g. a sensor that is not connected, the next boot runs on the
+        // defaults and the stored config waits for a fix over /config
+        config_store.fall_back()?;
+    }
+    result
+}
+
+fn run(
+    nvs: EspDefaultNvsPartition,
+    config_store: &mut ConfigStore,
+    cfg: AppConfig,
+) -> anyhow::Result<()> {
+    // a freshly updated firmware rolls back unless it reaches Telegram
+    let mut health = HealthWindow::start()?;
+
     let peripherals = Peripherals::take()?;
     let sys_loop = EspSystemEventLoop::take()?;
-    let nvs = EspDefaultNvsPartition::take()?;
 
     let mut internal_led = PinDriver::output(peripherals.pins.gpio2)?;
     internal_led.set_high()?;
 
-    let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs.clone()))?,
-        sys_loop,
-    )?;
+    // local control first, it runs without Wi-Fi and without the time
+    let mut relay = DoubleRelay::new(peripherals.pins.gpio5, peripherals.pins.gpio6, &cfg.relay);
+
+    let mut adc = None;
+    for name in relay.names() {
+        let channel = cfg.relay.channel(name);
+        let addr = relay.resolve_addr(name).unwrap();
+        if let Some(fb_config) = channel.feedback {
+            let feedback = probe::feedback(&fb_config, &mut adc)?;
+            relay.set_feedback(addr, feedback);
+            info!("feedback of {}: {:?}", name, fb_config);
+        }
 
-    let cfg = load_config();
-    info!("Connecting wifi ssid: {}", cfg.wifi.ssid);
-    while connect_wifi(&mut wifi, &cfg.wifi).is_err() {
-        info!("Reconnect Wifi");
-        FreeRtos::delay_ms(1000)
+        if let Some(flow_config) = channel.flow {
+            relay.set_flow(addr, FlowMeter::from_config(&flow_config)?);
+            info!("flow meter of {}: {:?}", name, flow_config);
+        }
     }
 
-    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
-    info!("Wifi DHCP info: {:?}", ip_info);
+    let mut stats_store = StatsStore::new(nvs.clone())?;
+    relay.load_stats(&stats_store)?;
+
+    let mut maintenance = Maintenance::new(nvs.clone(), &cfg.maintenance)?;
+
+    let buttons = Buttons::start(&cfg.button)?;
+    let mut levels = LevelSwitches::new(&cfg.level)?;
+    let mut temperatures = TemperatureSensors::new(&cfg.temperature)?;
+    let mut analog = AnalogSensors::new(nvs.clone(), &cfg.analog, &mut adc)?;
+    let mut rules = RuleEngine::new(nvs.clone(), &cfg.rule)?;
+
+    let sensor_names = [temperatures.names(), analog.names()].concat();
+    let mut history = HistoryLog::new(
+        EspCustomNvsPartition::take("history")?,
+        &sensor_names,
+        &relay.names(),
+    )?;
+
+    let hostname = cfg.wifi.hostname()?;
+    let mut wifi = BlockingWifi::wrap(
+        EspWifi::wrap_all(
+            WifiDriver::new(peripherals.modem, sys_loop.clone(), Some(nvs.clone()))?,
+            sta_netif(&hostname, None)?,
+            EspNetif::new(NetifStack::Ap)?,
+        )?,
+        sys_loop,
+    )?;
 
-    sync_ntp()?;
+    // on the serial console for the label of the device
+    info!(
+        "setup access point pomel-{} password {}",
+        device_id()?,
+        provision::ap_password(config_store, &cfg.wifi)?
+    );
+    let provision_button = ProvisionButton::start(cfg.wifi.provision_pin)?;
+    // the main loop connects, one network per round
+    let mut wifi_link = WifiLink::new(&cfg.wifi, &hostname);
+    // opened once per boot without any known network or connection
+    let mut boot_portal = true;
+    let mut ntp = Ntp::start()?;
 
     const TELE_FETCH_LIMIT: usize = 1;
     let mut tele_api = TeleAPI::new(&cfg.telegram, TELE_FETCH_LIMIT);
 
-    // INITIALIZE PIN
-    let mut relay = DoubleRelay::new(peripherals.pins.gpio5, peripherals.pins.gpio6);
+    let mut mqtt = match cfg.mqtt.as_ref() {
+        Some(mqtt_config) => {
+            let mac = wifi.wifi().sta_netif().get_mac()?;
+            let sensors = temperatures
+                .names()
+                .into_iter()
+                .map(|name| SensorInfo {
+                    name,
+                    unit: "°C",
+                    device_class: Some("temperature"),
+                })
+                .chain(analog.names().into_iter().map(|name| SensorInfo {
+                    name,
+                    unit: analog.unit(name).unwrap_or_default(),
+                    device_class: None,
+                }))
+                .collect();
+            Some(Mqtt::start(mqtt_config)?.with_discovery(mac, &relay.names(), sensors))
+        }
+        None => None,
+    };
+
+    let mut web = cfg.api.as_ref().map(WebServer::start).transpose()?;
+    let _mdns = match cfg.wifi.mdns {
+        true => mdns::advertise(
+            &hostname,
+            cfg.api.as_ref().map(|api| api.port),
+            &relay.names(),
+        )
+        .map_err(|err| warn!("mdns error: {}", err))
+        .ok(),
+        false => None,
+    };
 
+    let admins = cfg.telegram.admins.as_slice();
     let mut message_queue = MsgFMQueue::new(nvs)?;
+    if config_store.is_fallback() {
+        notify(
+            &mut message_queue,
+            Origin::Local,
+            admins,
+            "Stored config failed, running on the cfg.toml defaults this boot. Fix it with /config set, then /config restart",
+        );
+    }
+    let mut connectivity = Connectivity::new(&cfg.telegram.api_base);
+    // the first network right away, the next ones after each round
+    if let Err(err) = wifi_link.ensure_connected(&mut wifi) {
+        warn!("wifi: {}", err);
+    }
     'm: loop {
         info!("--- main loop ---");
         for _ in 0..5 {
-            FreeRtos::delay_ms(10_000);
-
-            let rsvc = relay_service(&mut relay, &mut message_queue);
-            if let Err(err) = rsvc {
-                warn!("{:?}", err);
-                let http_connection = create_http_connection()?;
-                let mut tele_pool = tele_api.create_client(http_connection);
-                let msg = SendMessage {
-                    chat_id: err.order_by,
-                    text: err.message,
+            // wait for the tick in short slices to answer the HTTP API
+            // and push the live stream
+            let tick_end = Instant::now() + Duration::from_secs(10);
+            let press = loop {
+                let left = tick_end.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    break None;
+                }
+                let Some(web) = web.as_mut() else {
+                    break buttons.wait(left);
+                };
+                if let Some(idx) = buttons.wait(left.min(Duration::from_millis(200))) {
+                    break Some(idx);
+                }
+                let mut ctx = ApiContext {
+                    relay: &mut relay,
+                    temperatures: &temperatures,
+                    analog: &analog,
+                    sensor_names: &sensor_names,
+                    queue_len: message_queue.len(),
+                    link: connectivity.state(),
                 };
-                tele_pool.send_message(msg).unwrap();
-                critical_section(&mut relay, &mut message_queue);
+                web.serve(&mut ctx);
+                web.broadcast(&ctx, sys_now());
+                if let Some(mqtt) = mqtt.as_mut() {
+                    mqtt.publish_changes(&mut relay);
+                }
+            };
+            if let Some(idx) = press {
+                let button = &cfg.button[idx];
+                button_press(button, &mut relay, &mut message_queue, admins);
+            }
+            let reason = match provision_button.requested() {
+                true => Some("by button"),
+                false
+                    if boot_portal
+                        && (cfg.wifi.networks.is_empty()
+                            || (cfg.wifi.provision_after > 0
+                                && wifi_link.failures() >= cfg.wifi.provision_after)) =>
+                {
+                    Some("without a reachable network")
+                }
+                false => None,
+            };
+            if let Some(reason) = reason {
+                boot_portal = false;
+                notify(
+                    &mut message_queue,
+                    Origin::Local,
+                    admins,
+                    &format!(
+                        "Wi-Fi provisioning started {}, running relays stopped",
+                        reason
+                    ),
+                );
+                if let Err(err) = relay.set(RelayAddr::Both, SetState::Stop, true) {
+                    warn!("stop relays before provisioning error: {}", err);
+                }
+                if let Err(err) = relay.save_stats(&mut stats_store) {
+                    warn!("save relay stats error: {}", err)
+                }
+                // the portal serves on port 80 itself
+                drop(web.take());
+                provision::run(&mut wifi, config_store, &cfg.wifi);
+                // nobody used the portal, serve the API again
+                web = cfg
+                    .api
+                    .as_ref()
+                    .map(WebServer::start)
+                    .transpose()
+                    .unwrap_or_else(|err| {
+                        warn!("web server error: {}", err);
+                        None
+                    });
+            }
+
+            if let Some(jump) = ntp.poll() {
+                relay.shift_clock(jump);
             }
 
-            const MAX_SEND_EFFORT: usize = 8;
-            let send_result =
-                send_message_queue(&mut tele_api, &mut message_queue, MAX_SEND_EFFORT);
-            if let Err(err) = send_result {
-                warn!("send message from queue error: {}", err)
+            levels.apply(&mut relay);
+            for event in temperatures.tick(sys_now()) {
+                notify(
+                    &mut message_queue,
+                    Origin::Local,
+                    admins,
+                    &event.to_string(),
+                );
+                if let Some(hook) = event.hook() {
+                    temperature_hook(hook, &mut relay, &mut message_queue, admins);
+                }
             }
+
+            for event in analog.tick() {
+                notify(
+                    &mut message_queue,
+                    Origin::Local,
+                    admins,
+                    &event.to_string(),
+                );
+            }
+
+            let readings = Readings {
+                relay: &relay,
+                temperatures: &temperatures,
+                analog: &analog,
+            };
+            // a series over 1970 would push out the real one
+            if clock_set() {
+                history.record(sys_now(), &readings);
+            }
+            if let Some(mqtt) = mqtt.as_mut() {
+                mqtt.publish_sensors(&readings, &sensor_names, message_queue.len());
+            }
+            let now = sys_now();
+            let rejected: Vec<String> = rules
+                .evaluate(&readings, now)
+                .into_iter()
+                .filter_map(|fired| rule_fire(fired, &mut relay, &mut message_queue, admins))
+                .collect();
+            for name in rejected {
+                rules.rejected(&name, now);
+            }
+            if let Some(mqtt) = mqtt.as_mut() {
+                mqtt.service(&mut relay);
+            }
+
+            relay_service(&mut relay, &mut message_queue, admins);
+            if let Some(mqtt) = mqtt.as_mut() {
+                mqtt.publish_changes(&mut relay);
+            }
+
+            if let Err(err) = relay.save_stats(&mut stats_store) {
+                warn!("save relay stats error: {}", err)
+            }
+
+            maintenance
+                .check(&relay, admins)
+                .into_iter()
+                .for_each(|msg| {
+                    message_queue.enqueue(msg);
+                });
+
+            flush_queue(&mut tele_api, &mut message_queue, &mut connectivity, &wifi);
         }
 
-        let connect = ensure_wifi_connected(&mut wifi, &cfg.wifi);
+        // the relays kept running above, only Telegram waits for the link
+        let connect = wifi_link.ensure_connected(&mut wifi);
         if let Err(err) = connect {
-            warn!("err: {:?}", err);
+            warn!("wifi: {}", err);
+            connectivity.set(LinkState::NoWifi);
             continue 'm;
         }
+        boot_portal = false;
 
+        let network = wifi_link.report(&wifi);
+        let mut uploads = Vec::new();
+        let mut ota_request = None;
+        let mut restart_requested = false;
         let tele_notif = {
             let mut buffer = [0u8; 1024];
             get_tele_notif(&mut tele_api, &mut buffer)
         };
 
+        match &tele_notif {
+            Ok(_) => {
+                if let Some(summary) = connectivity.set(LinkState::Online) {
+                    let mut text = format!(
+                        "{}\n{} messages were queued meanwhile",
+                        summary,
+                        message_queue.len()
+                    );
+                    match message_queue.take_dropped() {
+                        0 => {}
+                        dropped => text.push_str(&format!(
+                            ", {} older ones were dropped to make room",
+                            dropped
+                        )),
+                    }
+                    notify_now(&mut tele_api, &mut message_queue, admins, &text);
+                }
+                if let Some(window) = health.take() {
+                    let text = match window.confirm() {
+                        Ok(()) => format!("Firmware {} running", env!("CARGO_PKG_VERSION")),
+                        Err(err) => format!("Failed to confirm the firmware: {}", err),
+                    };
+                    notify(&mut message_queue, Origin::Local, admins, &text);
+                }
+            }
+            Err(_) => connectivity.telegram_failed(wifi.is_connected().unwrap_or(false)),
+        }
+
         match tele_notif {
             Ok(notification) => notification.into_iter().for_each(|each| {
                 let text = if each.is_command {
-                    match run_command(&each, &mut relay) {
-                        Ok(s) => s.to_string(),
+                    let ctx = CommandContext {
+                        maintenance: &mut maintenance,
+                        levels: &levels,
+                        temperatures: &temperatures,
+                        analog: &mut analog,
+                        rules: &mut rules,
+                        history: &history,
+                        uploads: &mut uploads,
+                        ota: &mut ota_request,
+                        config: &mut *config_store,
+                        network: &network,
+                        restart: &mut restart_requested,
+                    };
+                    match run_command(&each, &mut relay, ctx) {
+                        Ok(s) => s,
                         Err(err) => err.to_string(),
                     }
                 } else {
@@ -143,6 +532,27 @@ fn main() -> anyhow::Result<()> {
                 warn!("failed to get updates: {}", err);
             }
         };
+        if let Some(mqtt) = mqtt.as_mut() {
+            mqtt.publish_changes(&mut relay);
+        }
+
+        send_uploads(&mut tele_api, uploads, &mut message_queue);
+        if let Some(req) = ota_request {
+            ota_update(
+                req,
+                &mut tele_api,
+                &mut relay,
+                &mut message_queue,
+                &mut stats_store,
+            );
+        }
+
+        if let Err(err) = relay.save_stats(&mut stats_store) {
+            warn!("save relay stats error: {}", err)
+        }
+        if restart_requested {
+            restart(&mut tele_api, &mut message_queue);
+        }
     }
 }
 
@@ -156,82 +566,303 @@ fn create_http_connection() -> anyhow::Result<EspHttpConnection> {
     EspHttpConnection::new(&http_config).map_err(Into::into)
 }
 
-fn relay_service<R1, R2>(
+/// chats notified about an order, local orders go to the admins
+fn recipients(origin: Origin, admins: &[u32]) -> Vec<u32> {
+    match origin {
+        Origin::Chat(chat_id) => vec![chat_id],
+        Origin::Local | Origin::Remote | Origin::Automation => admins.to_vec(),
+    }
+}
+
+fn notify(message_queue: &mut MsgFMQueue, origin: Origin, admins: &[u32], text: &str) {
+    for chat_id in recipients(origin, admins) {
+        message_queue.enqueue(SendMessage {
+            chat_id,
+            text: text.to_owned(),
+        });
+    }
+}
+
+/// Apply a local button press as an order of `Origin::Local`. Admins and
+/// the chat owning an overridden order are told about it.
+fn button_press<R1, R2>(
+    button: &ButtonConfig,
+    relay: &mut DoubleRelay<'_, R1, R2>,
+    message_queue: &mut MsgFMQueue,
+    admins: &[u32],
+) where
+    R1: OutputPin,
+    R2: OutputPin,
+{
+    info!("button {:?} {}", button.action, button.relay);
+    let previous =
+        relay
+            .resolve_addr(&button.relay)
+            .and_then(|addr| match relay.get_status(addr) {
+                DoubleRelayStatus::Single(s) => s.run_info.map(|ord| ord.order_by),
+                DoubleRelayStatus::Both(_) => None,
+            });
+
+    let mut rlq = RelayQuery::new(Origin::Local);
+    rlq.name = Some(&button.relay);
+    rlq.instruction = Some(button.action == ButtonAction::Start || previous.is_none());
+    rlq.duration = Some(button.duration);
+
+    let text = match relay.interprete(rlq) {
+        Ok(status) => format!("Local override by button\n{}", status),
+        Err(err) => format!("Local button on {} rejected: {}", button.relay, err),
+    };
+
+    notify(message_queue, Origin::Local, admins, &text);
+    if let Some(prev @ Origin::Chat(chat_id)) = previous {
+        if !admins.contains(&chat_id) {
+            notify(message_queue, prev, admins, &text);
+        }
+    }
+}
+
+/// Start the relay hooked to a temperature going high, reported to admins.
+fn temperature_hook<R1, R2>(
+    hook: &HookConfig,
+    relay: &mut DoubleRelay<'_, R1, R2>,
+    message_queue: &mut MsgFMQueue,
+    admins: &[u32],
+) where
+    R1: OutputPin,
+    R2: OutputPin,
+{
+    let mut rlq = RelayQuery::new(Origin::Automation);
+    rlq.name = Some(&hook.relay);
+    rlq.instruction = Some(true);
+    rlq.duration = Some(hook.duration);
+
+    let text = match relay.interprete(rlq) {
+        Ok(status) => format!("Started by temperature\n{}", status),
+        Err(err) => format!("Temperature start of {} rejected: {}", hook.relay, err),
+    };
+    notify(message_queue, Origin::Automation, admins, &text);
+}
+
+/// sensor values and relay states seen by the rules
+struct Readings<'a, 'drv, 'cfg, R1, R2>
+where
+    R1: OutputPin,
+    R2: OutputPin,
+{
+    relay: &'a DoubleRelay<'drv, R1, R2>,
+    temperatures: &'a TemperatureSensors<'cfg>,
+    analog: &'a AnalogSensors<'cfg>,
+}
+
+impl<'a, 'drv, 'cfg, R1, R2> RuleInputs for Readings<'a, 'drv, 'cfg, R1, R2>
+where
+    R1: OutputPin,
+    R2: OutputPin,
+{
+    fn sensor(&self, name: &str) -> Option<f32> {
+        self.temperatures
+            .value(name)
+            .or_else(|| self.analog.value(name))
+    }
+
+    fn relay_running(&self, name: &str) -> Option<bool> {
+        self.relay.is_running(name)
+    }
+}
+
+/// main loop state seen by the local HTTP API
+struct ApiContext<'a, 'drv, 'cfg, R1, R2>
+where
+    R1: OutputPin,
+    R2: OutputPin,
+{
+    relay: &'a mut DoubleRelay<'drv, R1, R2>,
+    temperatures: &'a TemperatureSensors<'cfg>,
+    analog: &'a AnalogSensors<'cfg>,
+    sensor_names: &'a [&'cfg str],
+    queue_len: usize,
+    link: LinkState,
+}
+
+impl<'a, 'drv, 'cfg, R1, R2> ApiBackend for ApiContext<'a, 'drv, 'cfg, R1, R2>
+where
+    R1: OutputPin,
+    R2: OutputPin,
+{
+    fn relays(&self) -> Value {
+        json!(self.relay.states())
+    }
+
+    fn sensors(&self) -> Value {
+        let readings = Readings {
+            relay: self.relay,
+            temperatures: self.temperatures,
+            analog: self.analog,
+        };
+        let sensors: serde_json::Map<String, Value> = self
+            .sensor_names
+            .iter()
+            .map(|name| (name.to_string(), json!(readings.sensor(name))))
+            .collect();
+        Value::Object(sensors)
+    }
+
+    fn queue_len(&self) -> usize {
+        self.queue_len
+    }
+
+    fn status(&self) -> Value {
+        json!({
+            "time": Time::now().to_string(),
+            "uptime": uptime(),
+            "free_heap": free_heap(),
+            "queue": self.queue_len,
+            "link": self.link.to_string(),
+            "sensors": self.sensors(),
+            "relays": self.relays(),
+        })
+    }
+
+    fn command(&mut self, relay: &str, args: &str, is_admin: bool) -> anyhow::Result<String> {
+        let rlq = relay_query(
+            relay,
+            &mut args.split_whitespace(),
+            Origin::Remote,
+            is_admin,
+        )?;
+        self.relay.interprete(rlq).map(|s| s.to_string())
+    }
+}
+
+/// Apply the order of a fired rule, reported to admins. Returns the rule
+/// name when the order was rejected.
+fn rule_fire<R1, R2>(
+    fired: Fired,
     relay: &mut DoubleRelay<'_, R1, R2>,
     message_queue: &mut MsgFMQueue,
-) -> Result<(), RelayServiError>
+    admins: &[u32],
+) -> Option<String>
 where
     R1: OutputPin,
     R2: OutputPin,
+{
+    let name = fired.name;
+    let (text, rejected) = match relay.interprete(fired.query) {
+        Ok(status) => (format!("Rule {} fired\n{}", name, status), None),
+        Err(err) => (
+            format!("Rule {} rejected: {}", name, err),
+            Some(name.to_owned()),
+        ),
+    };
+    notify(message_queue, Origin::Automation, admins, &text);
+    rejected
+}
+
+/// Stop relays past their deadline or interlocked, and report faults. A
+/// stop that fails keeps the order, so the next poll tries again; the
+/// admins are alerted once instead of holding up the loop.
+fn relay_service<R1, R2>(
+    relay: &mut DoubleRelay<'_, R1, R2>,
+    message_queue: &mut MsgFMQueue,
+    admins: &[u32],
+) where
+    R1: OutputPin,
+    R2: OutputPin,
 {
     let events = relay.pool_event();
     info!("events: {:?}", events);
     for event in events.into_iter().flatten() {
         let addr = relay.resolve_addr(event.name).unwrap();
-        if !event.run_deadline {
-            continue;
+        if let Some(fault) = event.fault {
+            let text = format!("Fault on relay {}: {}", event.name, fault);
+            match relay.last_order_by(addr) {
+                None => warn!("fault of {} has no chat to notify", event.name),
+                Some(origin) => notify(message_queue, origin, admins, &text),
+            }
         }
 
+        let Some(cause) = event.cause else {
+            continue;
+        };
+
         let msg = {
-            let status = relay.get_status(addr);
-            let r_status = match status {
-                DoubleRelayStatus::Single(ref s) => s,
-                DoubleRelayStatus::Both(_) => panic!(),
+            // events are of single relays
+            let DoubleRelayStatus::Single(r_status) = relay.get_status(addr) else {
+                continue;
             };
 
             let inf = r_status.run_info.unwrap();
+            let volume = r_status
+                .volume
+                .map(|v| format!("\nVolume: {:.1} l", v))
+                .unwrap_or_default();
             (
                 inf.order_by,
-                SendMessage {
-                    chat_id: inf.order_by,
-                    text: format!(
-                        "Deadline... Turned off {}\nStart: {}\nFinish: {}",
-                        r_status.name, inf.start_at, inf.end_at
-                    ),
-                },
+                format!(
+                    "{}... Turned off {}\nStart: {}\nFinish: {}{}",
+                    cause, r_status.name, inf.start_at, inf.end_at, volume
+                ),
             )
         };
 
-        let set_result = relay.set(addr, SetState::Stop);
-
-        if let Err(err) = set_result {
-            let err = RelayServiError {
-                message: format!(
-                    "cannot stop {} when deadline exceed, reason: {}",
-                    event.name, err
-                ),
-                order_by: msg.0,
-            };
-            return Err(err);
+        let retry = relay.stop_failed(addr);
+        // deadline and interlocks always win over the minimum on-time protection
+        match relay.set(addr, SetState::Stop, true) {
+            Ok(()) => {
+                if retry {
+                    let text = format!("Relay {} stopped after a failed stop", event.name);
+                    notify(message_queue, Origin::Local, admins, &text);
+                }
+                notify(message_queue, msg.0, admins, &msg.1);
+            }
+            Err(err) if retry => warn!("stop {} retry failed: {}", event.name, err),
+            Err(err) => {
+                let text = format!(
+                    "Cannot stop {} on {}, reason: {}\nRetrying on every poll",
+                    event.name,
+                    cause.name(),
+                    err
+                );
+                warn!("{}", text);
+                notify(message_queue, Origin::Local, admins, &text);
+            }
         }
-
-        message_queue.enqueue(msg.1);
     }
-    Ok(())
 }
 
-fn critical_section<R1, R2>(relay: &mut DoubleRelay<'_, R1, R2>, message_queue: &mut MsgFMQueue)
-where
-    R1: OutputPin,
-    R2: OutputPin,
-{
-    let critical_retry = 12;
-    for _ in 0..critical_retry {
-        let retry = relay_service(relay, message_queue);
-        if retry.is_ok() {
-            return;
+/// Send to the admins ahead of the queue, which may be full after an
+/// outage, and queue it for the ones it fails for.
+fn notify_now(tele_api: &mut TeleAPI, message_queue: &mut MsgFMQueue, admins: &[u32], text: &str) {
+    for &chat_id in admins {
+        let msg = || SendMessage {
+            chat_id,
+            text: text.to_owned(),
+        };
+        let sent = create_http_connection()
+            .and_then(|conn| tele_api.create_client(conn).send_message(msg()));
+        if let Err(err) = sent {
+            warn!("send message error: {}", err);
+            message_queue.enqueue(msg());
         }
-        // delay 5 minutes
-        FreeRtos::delay_ms(300_000);
     }
-
-    panic!()
 }
 
-#[derive(Debug)]
-struct RelayServiError {
-    order_by: u32,
-    message: String,
+/// Send queued messages while online, a failure takes the link down
+/// until the next poll of Telegram succeeds.
+fn flush_queue(
+    tele_api: &mut TeleAPI,
+    message_queue: &mut MsgFMQueue,
+    connectivity: &mut Connectivity,
+    wifi: &BlockingWifi<EspWifi<'static>>,
+) {
+    if !connectivity.is_online() {
+        return;
+    }
+    const MAX_SEND_EFFORT: usize = 8;
+    if let Err(err) = send_message_queue(tele_api, message_queue, MAX_SEND_EFFORT) {
+        warn!("send message from queue error: {}", err);
+        connectivity.telegram_failed(wifi.is_connected().unwrap_or(false));
+    }
 }
 
 fn send_message_queue(
@@ -246,10 +877,8 @@ fn send_message_queue(
     let http_connection = create_http_connection()?;
     let mut tele_pool = tele_api.create_client(http_connection);
 
-    let mut buffer = [0_u8; 512];
-
     for _ in 0..max_try {
-        let msg = match message_queue.peek(&mut buffer) {
+        let msg = match message_queue.peek() {
             None => break,
             Some(text) => text,
         };
@@ -268,6 +897,114 @@ fn send_message_queue(
     Ok(())
 }
 
+/// Files are too large for the flash queue, they are sent right away
+/// and only a failure notice is queued.
+fn send_uploads(tele_api: &mut TeleAPI, uploads: Vec<SendFile>, message_queue: &mut MsgFMQueue) {
+    if uploads.is_empty() {
+        return;
+    }
+
+    let conn = match create_http_connection() {
+        Ok(conn) => conn,
+        Err(err) => {
+            warn!("upload connection error: {}", err);
+            return;
+        }
+    };
+
+    let mut tele_client = tele_api.create_client(conn);
+    for file in uploads {
+        if let Err(err) = tele_client.send_file(&file) {
+            warn!("upload {} error: {}", file.filename, err);
+            message_queue.enqueue(SendMessage {
+                chat_id: file.chat_id,
+                text: format!("Failed to send {}: {}", file.filename, err),
+            });
+        }
+    }
+}
+
+/// send right away, for progress while the main loop is blocked
+fn send_now(tele_api: &mut TeleAPI, chat_id: u32, text: String) {
+    let sent = create_http_connection().and_then(|conn| {
+        tele_api
+            .create_client(conn)
+            .send_message(SendMessage { chat_id, text })
+    });
+    if let Err(err) = sent {
+        warn!("send message error: {}", err);
+    }
+}
+
+/// Run a firmware update requested by `/ota`. Relays are stopped before
+/// the download and stay off when it fails, a verified image is booted
+/// right away.
+fn ota_update<R1, R2>(
+    req: OtaRequest,
+    tele_api: &mut TeleAPI,
+    relay: &mut DoubleRelay<'_, R1, R2>,
+    message_queue: &mut MsgFMQueue,
+    stats_store: &mut StatsStore,
+) where
+    R1: OutputPin,
+    R2: OutputPin,
+{
+    const MAX_SEND_EFFORT: usize = 8;
+    if let Err(err) = send_message_queue(tele_api, message_queue, MAX_SEND_EFFORT) {
+        warn!("send message from queue error: {}", err)
+    }
+    if let Err(err) = relay.set(RelayAddr::Both, SetState::Stop, true) {
+        warn!("stop relays before update error: {}", err);
+    }
+    if let Err(err) = relay.save_stats(stats_store) {
+        warn!("save relay stats error: {}", err)
+    }
+
+    let chat_id = req.chat_id;
+    let result = create_http_connection().and_then(|conn| {
+        ota::download(&req, conn, &mut |pct| {
+            send_now(tele_api, chat_id, format!("Firmware download {}%", pct))
+        })
+    });
+    if let Err(err) = result {
+        warn!("ota error: {}", err);
+        message_queue.enqueue(SendMessage {
+            chat_id,
+            text: format!(
+                "Firmware update failed: {}\nRelays were stopped, buttons, rules \
+                 and temperature hooks may start them again",
+                err
+            ),
+        });
+        return;
+    }
+
+    send_now(
+        tele_api,
+        chat_id,
+        String::from(
+            "Firmware verified, restarting\nIt must reach Telegram within 5 minutes, \
+             otherwise the previous firmware returns",
+        ),
+    );
+    restart(tele_api, message_queue);
+}
+
+/// Restart once the queued replies are out and the handled commands are
+/// confirmed to Telegram, so they do not run again after the boot.
+fn restart(tele_api: &mut TeleAPI, message_queue: &mut MsgFMQueue) -> ! {
+    const MAX_SEND_EFFORT: usize = 8;
+    if let Err(err) = send_message_queue(tele_api, message_queue, MAX_SEND_EFFORT) {
+        warn!("send message from queue error: {}", err)
+    }
+    let acked =
+        create_http_connection().and_then(|conn| tele_api.create_client(conn).ack_updates());
+    if let Err(err) = acked {
+        warn!("ack updates error: {}", err);
+    }
+    unsafe { esp_idf_svc::sys::esp_restart() }
+}
+
 fn get_tele_notif(tele_api: &mut TeleAPI, buffer: &mut [u8]) -> anyhow::Result<Vec<BotQuery>> {
     let http_connection = create_http_connection()?;
     let mut tele_client = tele_api.create_client(http_connection);
@@ -280,6 +1017,7 @@ fn get_tele_notif(tele_api: &mut TeleAPI, buffer: &mut [u8]) -> anyhow::Result<V
             chat_id: v.message.chat.id,
             is_command: v.message.text.starts_with('/'),
             q: v.message.text.split_off(1),
+            is_admin: tele_api.is_admin(v.message.chat.id),
         })
         .collect();
 
@@ -292,15 +1030,117 @@ pub struct BotQuery {
     pub chat_id: u32,
     pub q: String,
     pub is_command: bool,
+    pub is_admin: bool,
 }
 
 const INVALID_CMD: &str = "Invalid Command";
-const INVALID_UNIT: &str = "Invalid unit, example: 1h (one hours)";
+const ADMIN_ONLY: &str = "Only admins allowed";
+const INVALID_CYCLE: &str = "expected \"... cycle [on] [off]\", example: cycle 15m 45m for 12h";
+const INVALID_PULSE: &str =
+    "expected \"... pulse [on] every [period]\", example: pulse 5s every 2h for 1h";
+
+/// subsystems reachable from bot commands besides the relays
+struct CommandContext<'a, 'cfg> {
+    maintenance: &'a mut Maintenance<'cfg>,
+    levels: &'a LevelSwitches<'cfg>,
+    temperatures: &'a TemperatureSensors<'cfg>,
+    analog: &'a mut AnalogSensors<'cfg>,
+    rules: &'a mut RuleEngine,
+    history: &'a HistoryLog,
+    /// files to send after the commands
+    uploads: &'a mut Vec<SendFile>,
+    /// firmware update to run after the replies
+    ota: &'a mut Option<OtaRequest>,
+    config: &'a mut ConfigStore,
+    /// Wi-Fi and address lines of `/status`
+    network: &'a str,
+    /// restart after the replies
+    restart: &'a mut bool,
+}
+
+/// Parse the arguments of `/relay [name] ...` after the name, shared by
+/// every transport taking relay commands.
+fn relay_query<'a>(
+    name: &'a str,
+    split: &mut dyn Iterator<Item = &'a str>,
+    origin: Origin,
+    is_admin: bool,
+) -> anyhow::Result<RelayQuery<'a>> {
+    let mut rlq = RelayQuery::new(origin);
+    rlq.name = Some(name);
+
+    let r_instruction = split.next().ok_or(Error::msg(INVALID_CMD))?;
+
+    let r_instruction = match r_instruction {
+        "on" => true,
+        "off" => false,
+        // cycle [on] [off], e.g. cycle 15m 45m
+        "cycle" => {
+            let on = parse_duration(split.next().ok_or(Error::msg(INVALID_CYCLE))?)?;
+            let off = parse_duration(split.next().ok_or(Error::msg(INVALID_CYCLE))?)?;
+            if on == 0 || off == 0 {
+                return Err(Error::msg(INVALID_CYCLE));
+            }
+            rlq.cycle = Some(Cycle { on, off });
+            true
+        }
+        // pulse [on] every [period], e.g. pulse 5s every 2h
+        "pulse" => {
+            let on = parse_duration(split.next().ok_or(Error::msg(INVALID_PULSE))?)?;
+            if split.next() != Some("every") {
+                return Err(Error::msg(INVALID_PULSE));
+            }
+            let period = parse_duration(split.next().ok_or(Error::msg(INVALID_PULSE))?)?;
+            if on == 0 || period <= on {
+                return Err(Error::msg(INVALID_PULSE));
+            }
+            rlq.cycle = Some(Cycle {
+                on,
+                off: period - on,
+            });
+            true
+        }
+        _ => return Err(Error::msg(INVALID_CMD)),
+    };
 
-fn run_command<'a, R1, R2>(
+    rlq.instruction = Some(r_instruction);
+
+    while let Some(r_pred) = split.next() {
+        match r_pred {
+            "for" => {
+                let dur_str = split
+                    .next()
+                    .ok_or(Error::msg("expected \"... for [duration]\""))?;
+                match dur_str.strip_suffix('l') {
+                    Some(litres) => {
+                        let litres = litres
+                            .parse::<f32>()
+                            .ok()
+                            .filter(|l| *l > 0.0)
+                            .ok_or(Error::msg(INVALID_UNIT))?;
+                        rlq.volume = Some(litres);
+                    }
+                    None => rlq.duration = Some(parse_duration(dur_str)?),
+                }
+            }
+            "force" => {
+                if !is_admin {
+                    return Err(Error::msg(ADMIN_ONLY));
+                }
+                rlq.force = true;
+            }
+            _ => return Err(Error::msg("no matching pattern")),
+        }
+    }
+
+    Ok(rlq)
+}
+
+fn run_command<R1, R2>(
     q: &BotQuery,
-    relay: &'a mut DoubleRelay<'_, R1, R2>,
-) -> anyhow::Result<DoubleRelayStatus<'a>>
+    relay: &mut DoubleRelay<'_, R1, R2>,
+    ctx: CommandContext,
+) -> anyhow::Result<String>
 where
     R1: OutputPin,
     R2: OutputPin,
@@ -310,48 +1150,169 @@ where
 
     match top_cmd {
         "relay" => {
-            let mut rlq = RelayQuery::new(q.chat_id);
             let r_name = split.next().ok_or(Error::msg(INVALID_CMD))?;
-            rlq.name = Some(r_name);
-
-            let r_instruction = split.next().ok_or(Error::msg(INVALID_CMD))?;
-
-            let r_instruction = match r_instruction {
-                "on" => true,
-                "off" => false,
-                _ => return Err(Error::msg(INVALID_CMD)),
+            let rlq = relay_query(r_name, &mut split, Origin::Chat(q.chat_id), q.is_admin)?;
+            relay.interprete(rlq).map(|s| s.to_string())
+        }
+        "stats" => {
+            let report = relay.stats_report();
+            Ok(format!("{}\n\n{}", report[0], report[1]))
+        }
+        "status" => {
+            let up = uptime();
+            Ok(format!(
+                "Firmware {}, up {}d {}h {}m, free heap {} B\n{}",
+                env!("CARGO_PKG_VERSION"),
+                up / 86400,
+                up % 86400 / 3600,
+                up % 3600 / 60,
+                free_heap(),
+                ctx.network
+            ))
+        }
+        "level" => Ok(ctx.levels.report()),
+        "sensors" => {
+            let report = [ctx.temperatures.report(), ctx.analog.report()]
+                .into_iter()
+                .filter(|r| !r.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n");
+            if report.is_empty() {
+                return Ok(String::from("No sensor configured"));
+            }
+            Ok(report)
+        }
+        "history" => {
+            let name = split.next().ok_or(Error::msg(
+                "expected \"/history [name] [range]\", example: /history kolam 24h",
+            ))?;
+            let range = split
+                .next()
+                .map(parse_duration)
+                .transpose()?
+                .unwrap_or(86400) as u64;
+            let now = sys_now();
+            let (kind, filename, content_type, data, caption) = match split.next() {
+                None => return ctx.history.report(name, range, now),
+                Some("csv") => {
+                    let (data, cut) = ctx.history.csv(name, range, now)?;
+                    let caption = match cut {
+                        true => format!("History of {}, oldest rows cut to fit", name),
+                        false => format!("History of {}", name),
+                    };
+                    let filename = format!("{}.csv", name);
+                    (FileKind::Document, filename, "text/csv", data, caption)
+                }
+                Some("chart") => {
+                    let data = ctx.history.chart(name, range, now)?;
+                    let caption = ctx.history.report(name, range, now)?;
+                    let filename = format!("{}.png", name);
+                    (FileKind::Photo, filename, "image/png", data, caption)
+                }
+                Some(_) => {
+                    return Err(Error::msg(
+                        "expected \"/history [name] [range] [csv|chart]\"",
+                    ))
+                }
             };
 
-            rlq.instruction = Some(r_instruction);
-
-            if let Some(r_pred) = split.next() {
-                rlq.duration = match r_pred.eq("for") {
-                    true => {
-                        let dur_str = split
-                            .next()
-                            .ok_or(Error::msg("expected \"... for [duration]\""))?;
-                        if dur_str.len() < 2 {
-                            return Err(Error::msg(INVALID_UNIT));
-                        }
-
-                        let (dur, unit) = dur_str.split_at(dur_str.len() - 1);
-                        let unit = unit.as_bytes()[0];
-
-                        let mul = match unit {
-                            b'm' => 60,
-                            b'h' => 3600,
-                            _ => return Err(Error::msg(INVALID_UNIT)),
-                        };
-
-                        let duration = dur.parse::<u32>().map_err(|_| Error::msg(INVALID_UNIT))?;
-
-                        Some(duration * mul)
+            let text = format!("Sending {}, {} bytes", filename, data.len());
+            ctx.uploads.push(SendFile {
+                chat_id: q.chat_id,
+                kind,
+                filename,
+                content_type,
+                caption,
+                data,
+            });
+            Ok(text)
+        }
+        "calibrate" => {
+            if !q.is_admin {
+                return Err(Error::msg(ADMIN_ONLY));
+            }
+            let name = split.next().ok_or(Error::msg(
+                "expected \"/calibrate [name] [reference|reset]\"",
+            ))?;
+            ctx.analog.calibrate(name, split.next())
+        }
+        "ota" => {
+            if !q.is_admin {
+                return Err(Error::msg(ADMIN_ONLY));
+            }
+            let Some(url) = split.next() else {
+                return ota::report();
+            };
+            let req = OtaRequest::parse(q.chat_id, url, split.next())?;
+            let text = format!(
+                "Updating firmware from {}\nRelays are stopped during the update",
+                req.url
+            );
+            *ctx.ota = Some(req);
+            Ok(text)
+        }
+        "config" => {
+            if !q.is_admin {
+                return Err(Error::msg(ADMIN_ONLY));
+            }
+            match split.next() {
+                None | Some("get") => ctx.config.get(split.next()),
+                Some("set") => {
+                    let path = split.next().unwrap_or_default();
+                    let value = split.collect::<Vec<_>>().join(" ");
+                    if path.is_empty() || value.is_empty() {
+                        return Err(Error::msg("expected \"/config set [path] [value]\""));
                     }
-                    false => return Err(Error::msg("no matching pattern")),
-                };
+                    ctx.config.set(path, &value)
+                }
+                Some("restart") => {
+                    *ctx.restart = true;
+                    Ok(String::from("Restarting, running relays stop"))
+                }
+                Some("reset") => {
+                    ctx.config.reset()?;
+                    *ctx.restart = true;
+                    Ok(String::from(
+                        "Configuration reset to the defaults, restarting",
+                    ))
+                }
+                Some(_) => Err(Error::msg(INVALID_CMD)),
+            }
+        }
+        "maintenance" => match split.next() {
+            None => Ok(ctx.maintenance.report(relay)),
+            Some("done") => {
+                if !q.is_admin {
+                    return Err(Error::msg(ADMIN_ONLY));
+                }
+                let name = split
+                    .next()
+                    .ok_or(Error::msg("expected \"/maintenance done [name]\""))?;
+                ctx.maintenance.done(name, relay)
+            }
+            Some(_) => Err(Error::msg(INVALID_CMD)),
+        },
+        "rules" => {
+            let Some(action) = split.next() else {
+                return Ok(ctx.rules.report());
+            };
+            if !q.is_admin {
+                return Err(Error::msg(ADMIN_ONLY));
             }
 
-            relay.interprete(rlq)
+            let name = split.next().ok_or(Error::msg(
+                "expected \"/rules add|remove|enable|disable [name]\"",
+            ))?;
+            match action {
+                "add" => {
+                    let text = split.collect::<Vec<_>>().join(" ");
+                    ctx.rules.add(name, &text, &relay.names())
+                }
+                "remove" => ctx.rules.remove(name),
+                "enable" => ctx.rules.set_enabled(name, true),
+                "disable" => ctx.rules.set_enabled(name, false),
+                _ => Err(Error::msg(INVALID_CMD)),
+            }
         }
         _ => Err(Error::msg("unregister command")),
     }
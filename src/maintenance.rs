@@ -0,0 +1,186 @@
+use anyhow::Error;
+use esp_idf_svc::hal::gpio::OutputPin;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use serde::Deserialize;
+use std::fmt::Write;
+
+use crate::relay::DoubleRelay;
+use crate::telegram::SendMessage;
+
+#[derive(Deserialize, Debug)]
+pub struct MaintenanceConfig {
+    /// item name, also the NVS key so at most 14 characters with the "!"
+    /// of its notified flag
+    pub name: String,
+    /// relay whose run hours are counted
+    relay: String,
+    /// run hours between two maintenances
+    interval_hours: u32,
+}
+
+impl MaintenanceConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        crate::config::check_name(&self.name, Maintenance::MAX_NAME)
+    }
+}
+
+/// Maintenance items due after a number of relay run hours.
+/// NVS keeps, per item, the relay lifetime run seconds at the last
+/// maintenance and whether admins were already notified.
+pub struct Maintenance<'cfg> {
+    items: &'cfg [MaintenanceConfig],
+    storage: EspNvs<NvsDefault>,
+}
+
+struct ItemState {
+    run_hours: f32,
+    due: bool,
+}
+
+impl<'cfg> Maintenance<'cfg> {
+    const MAX_NAME: usize = 14;
+
+    pub fn new(
+        partition: EspDefaultNvsPartition,
+        items: &'cfg [MaintenanceConfig],
+    ) -> anyhow::Result<Self> {
+        for item in items {
+            item.validate()
+                .map_err(|e| Error::msg(format!("maintenance: {}", e)))?;
+        }
+        let storage = EspNvs::new(partition, "maintenance", true)?;
+
+        Ok(Self { items, storage })
+    }
+
+    fn baseline(&self, item: &MaintenanceConfig) -> u64 {
+        self.storage
+            .get_u64(&item.name)
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+    }
+
+    fn notified_key(item: &MaintenanceConfig) -> String {
+        format!("{}!", item.name)
+    }
+
+    fn state<R1, R2>(
+        &self,
+        item: &MaintenanceConfig,
+        relay: &DoubleRelay<'_, R1, R2>,
+    ) -> Option<ItemState>
+    where
+        R1: OutputPin,
+        R2: OutputPin,
+    {
+        let lifetime = relay.lifetime_run(&item.relay)?;
+        let run_secs = lifetime.saturating_sub(self.baseline(item));
+        Some(ItemState {
+            run_hours: run_secs as f32 / 3600.0,
+            due: run_secs >= item.interval_hours as u64 * 3600,
+        })
+    }
+
+    /// list every item, due ones first
+    pub fn report<R1, R2>(&self, relay: &DoubleRelay<'_, R1, R2>) -> String
+    where
+        R1: OutputPin,
+        R2: OutputPin,
+    {
+        if self.items.is_empty() {
+            return String::from("No maintenance configured");
+        }
+
+        let mut due = String::new();
+        let mut pending = String::new();
+        for item in self.items {
+            let Some(state) = self.state(item, relay) else {
+                continue;
+            };
+
+            let out = if state.due { &mut due } else { &mut pending };
+            let _ = writeln!(
+                out,
+                "{}{} ({}): {:.1}h of {}h",
+                if state.due { "DUE " } else { "" },
+                item.name,
+                item.relay,
+                state.run_hours,
+                item.interval_hours
+            );
+        }
+
+        due.push_str(&pending);
+        due.trim_end().to_owned()
+    }
+
+    /// restart counting run hours of `name` from now
+    pub fn done<R1, R2>(
+        &mut self,
+        name: &str,
+        relay: &DoubleRelay<'_, R1, R2>,
+    ) -> anyhow::Result<String>
+    where
+        R1: OutputPin,
+        R2: OutputPin,
+    {
+        let item = self
+            .items
+            .iter()
+            .find(|i| i.name == name)
+            .ok_or(Error::msg("unknown maintenance item"))?;
+        let lifetime = relay
+            .lifetime_run(&item.relay)
+            .ok_or(Error::msg("unknown relay of maintenance item"))?;
+
+        self.storage.set_u64(&item.name, lifetime)?;
+        self.storage.set_u8(&Self::notified_key(item), 0)?;
+        info!("maintenance {} done at {}s", item.name, lifetime);
+        Ok(format!(
+            "Maintenance {} done, next in {}h",
+            item.name, item.interval_hours
+        ))
+    }
+
+    /// Notify admins once for every item that became due.
+    pub fn check<R1, R2>(
+        &mut self,
+        relay: &DoubleRelay<'_, R1, R2>,
+        admins: &[u32],
+    ) -> Vec<SendMessage>
+    where
+        R1: OutputPin,
+        R2: OutputPin,
+    {
+        let mut messages = Vec::new();
+        for item in self.items {
+            let Some(state) = self.state(item, relay) else {
+                continue;
+            };
+
+            let key = Self::notified_key(item);
+            let notified = self.storage.get_u8(&key).ok().flatten() == Some(1);
+            if !state.due || notified {
+                continue;
+            }
+
+            if let Err(err) = self.storage.set_u8(&key, 1) {
+                warn!("cannot mark maintenance {} notified: {}", item.name, err);
+                continue;
+            }
+
+            admins.iter().for_each(|admin| {
+                messages.push(SendMessage {
+                    chat_id: *admin,
+                    text: format!(
+                        "Maintenance due: {} ({})\nRun {:.1}h of {}h interval\nReply /maintenance done {} when finished",
+                        item.name, item.relay, state.run_hours, item.interval_hours, item.name
+                    ),
+                })
+            });
+        }
+        messages
+    }
+}
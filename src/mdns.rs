@@ -0,0 +1,34 @@
+use esp_idf_svc::mdns::EspMdns;
+use log::info;
+
+/// Announce `{hostname}.local` on the LAN. With the local API running
+/// also `_http._tcp` for browsers and `_pomel._tcp` for scripts, with the
+/// firmware version and relay names in TXT records. The announcements
+/// stop when the returned handle is dropped.
+pub fn advertise(
+    hostname: &str,
+    api_port: Option<u16>,
+    relays: &[&str],
+) -> anyhow::Result<EspMdns> {
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(hostname)?;
+    mdns.set_instance_name(hostname)?;
+
+    if let Some(port) = api_port {
+        let relays = relays.join(",");
+        mdns.add_service(None, "_http", "_tcp", port, &[("path", "/")])?;
+        mdns.add_service(
+            None,
+            "_pomel",
+            "_tcp",
+            port,
+            &[
+                ("version", env!("CARGO_PKG_VERSION")),
+                ("relays", relays.as_str()),
+                ("api", "/api"),
+            ],
+        )?;
+    }
+    info!("mdns {}.local", hostname);
+    Ok(mdns)
+}
@@ -0,0 +1,421 @@
+use esp_idf_svc::hal::gpio::OutputPin;
+use esp_idf_svc::mqtt::client::{
+    EspMqttClient, EventPayload, LwtConfiguration, MqttClientConfiguration, QoS,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+use crate::relay::{DoubleRelay, Origin};
+use crate::rule::RuleInputs;
+use crate::util::{free_heap, sys_now, uptime};
+
+#[derive(Deserialize, Debug)]
+pub struct MqttConfig {
+    /// e.g. mqtt://192.168.1.10:1883 or mqtts://broker:8883
+    url: String,
+    #[serde(default = "MqttConfig::default_client_id")]
+    client_id: String,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    /// every topic starts with it, e.g. pomel/relay/pompa_air/state
+    #[serde(default = "MqttConfig::default_prefix")]
+    prefix: String,
+    /// commands may bypass the minimum on/off time with "force"
+    #[serde(default)]
+    allow_force: bool,
+    /// publish Home Assistant discovery configs on connect
+    #[serde(default = "MqttConfig::default_discovery")]
+    discovery: bool,
+    #[serde(default = "MqttConfig::default_discovery_prefix")]
+    discovery_prefix: String,
+}
+
+impl MqttConfig {
+    fn default_client_id() -> String {
+        String::from("pomel")
+    }
+
+    fn default_prefix() -> String {
+        String::from("pomel")
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self.url.starts_with("mqtt://") || self.url.starts_with("mqtts://") {
+            true => Ok(()),
+            false => Err(anyhow::Error::msg(
+                "url must start with mqtt:// or mqtts://",
+            )),
+        }
+    }
+
+    fn default_discovery() -> bool {
+        true
+    }
+
+    fn default_discovery_prefix() -> String {
+        String::from("homeassistant")
+    }
+}
+
+/// sensor as announced to Home Assistant
+pub struct SensorInfo<'a> {
+    pub name: &'a str,
+    pub unit: &'a str,
+    pub device_class: Option<&'static str>,
+}
+
+/// entities announced by Home Assistant discovery
+struct Discovery<'cfg> {
+    mac: [u8; 6],
+    relays: Vec<&'static str>,
+    sensors: Vec<SensorInfo<'cfg>>,
+}
+
+enum MqttEvent {
+    Connected,
+    Disconnected,
+    /// payload of `[prefix]/relay/[name]/set`
+    Command {
+        relay: String,
+        payload: String,
+    },
+}
+
+#[derive(Serialize)]
+struct Health {
+    uptime: u64,
+    free_heap: u32,
+    queue: usize,
+}
+
+/// MQTT transport next to Telegram. State is published retained with
+/// QoS 1, the broker publishes "offline" on `[prefix]/status` when the
+/// device drops, and `[prefix]/relay/[name]/set` takes the same
+/// arguments as `/relay [name]`, e.g. "on for 30m".
+pub struct Mqtt<'cfg> {
+    config: &'cfg MqttConfig,
+    client: EspMqttClient<'static>,
+    rx: Receiver<MqttEvent>,
+    connected: bool,
+    /// publish every relay state with the next changes, set on connect
+    republish: bool,
+    last_periodic: u64,
+    discovery: Option<Discovery<'cfg>>,
+}
+
+impl<'cfg> Mqtt<'cfg> {
+    /// seconds between sensor and health publications
+    const PERIOD: u64 = 60;
+
+    pub fn start(config: &'cfg MqttConfig) -> anyhow::Result<Self> {
+        let status_topic = format!("{}/status", config.prefix);
+        let conf = MqttClientConfiguration {
+            client_id: Some(&config.client_id),
+            username: config.username.as_deref(),
+            password: config.password.as_deref(),
+            keep_alive_interval: Some(Duration::from_secs(30)),
+            lwt: Some(LwtConfiguration {
+                topic: &status_topic,
+                payload: b"offline",
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+            ..Default::default()
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let command_prefix = format!("{}/relay/", config.prefix);
+        let client = EspMqttClient::new_cb(&config.url, &conf, move |event| {
+            forward(&tx, &command_prefix, event.payload())
+        })?;
+        info!("mqtt client {} to {}", config.client_id, config.url);
+
+        Ok(Self {
+            config,
+            client,
+            rx,
+            connected: false,
+            republish: true,
+            last_periodic: 0,
+            discovery: None,
+        })
+    }
+
+    /// announce `relays` and `sensors` to Home Assistant on every connect
+    pub fn with_discovery(
+        mut self,
+        mac: [u8; 6],
+        relays: &[&'static str],
+        sensors: Vec<SensorInfo<'cfg>>,
+    ) -> Self {
+        if self.config.discovery {
+            self.discovery = Some(Discovery {
+                mac,
+                relays: relays.to_vec(),
+                sensors,
+            });
+        }
+        self
+    }
+
+    fn topic(&self, suffix: &str) -> String {
+        format!("{}/{}", self.config.prefix, suffix)
+    }
+
+    fn publish(&mut self, suffix: &str, retain: bool, payload: &[u8]) {
+        if !self.connected {
+            return;
+        }
+        let topic = self.topic(suffix);
+        if let Err(err) = self
+            .client
+            .publish(&topic, QoS::AtLeastOnce, retain, payload)
+        {
+            warn!("mqtt publish {} error: {}", topic, err);
+        }
+    }
+
+    fn on_connected(&mut self) {
+        info!("mqtt connected");
+        self.connected = true;
+        self.republish = true;
+        self.last_periodic = 0;
+        self.publish("status", true, b"online");
+
+        let topic = self.topic("relay/+/set");
+        if let Err(err) = self.client.subscribe(&topic, QoS::AtLeastOnce) {
+            warn!("mqtt subscribe {} error: {}", topic, err);
+        }
+        self.publish_discovery();
+    }
+
+    /// Retained Home Assistant configs: a switch and a "run for minutes"
+    /// number per relay and a sensor per reading, grouped in one device.
+    fn publish_discovery(&mut self) {
+        let Some(discovery) = &self.discovery else {
+            return;
+        };
+
+        let c = self.config;
+        let mac = discovery.mac.map(|b| format!("{:02x}", b));
+        let node = format!("{}_{}", c.client_id, mac.concat());
+        let device = json!({
+            "ids": [node],
+            "name": c.client_id,
+            "mdl": "Pomel",
+            "mf": "Pomel",
+            "sw": env!("CARGO_PKG_VERSION"),
+            "cns": [["mac", mac.join(":")]],
+        });
+        let availability = self.topic("status");
+
+        let mut configs = Vec::new();
+        for name in &discovery.relays {
+            let base = self.topic(&format!("relay/{}", name));
+            configs.push((
+                format!("switch/{}/{}", node, name),
+                json!({
+                    "~": base,
+                    "name": name,
+                    "uniq_id": format!("{}_{}", node, name),
+                    "cmd_t": "~/set",
+                    "pl_on": "on",
+                    "pl_off": "off",
+                    "stat_t": "~/state",
+                    "val_tpl": "{{ 'ON' if value_json.on else 'OFF' }}",
+                    "json_attr_t": "~/state",
+                    "avty_t": availability,
+                    "dev": device,
+                }),
+            ));
+            configs.push((
+                format!("number/{}/{}_run", node, name),
+                json!({
+                    "~": base,
+                    "name": format!("{} run for", name),
+                    "uniq_id": format!("{}_{}_run", node, name),
+                    "cmd_t": "~/set",
+                    "cmd_tpl": "on for {{ value | int }}m",
+                    "min": 1,
+                    "max": 720,
+                    "unit_of_meas": "min",
+                    "mode": "box",
+                    "icon": "mdi:timer-outline",
+                    "avty_t": availability,
+                    "dev": device,
+                }),
+            ));
+        }
+
+        for sensor in &discovery.sensors {
+            let mut config = json!({
+                "name": sensor.name,
+                "uniq_id": format!("{}_{}", node, sensor.name),
+                "stat_t": self.topic(&format!("sensor/{}", sensor.name)),
+                "stat_cla": "measurement",
+                "avty_t": availability,
+                "dev": device,
+            });
+            if !sensor.unit.is_empty() {
+                config["unit_of_meas"] = json!(sensor.unit);
+            }
+            if let Some(class) = sensor.device_class {
+                config["dev_cla"] = json!(class);
+            }
+            configs.push((format!("sensor/{}/{}", node, sensor.name), config));
+        }
+
+        for (path, config) in configs {
+            let topic = format!("{}/{}/config", c.discovery_prefix, path);
+            let payload = config.to_string();
+            if let Err(err) =
+                self.client
+                    .publish(&topic, QoS::AtLeastOnce, true, payload.as_bytes())
+            {
+                warn!("mqtt discovery {} error: {}", topic, err);
+            }
+        }
+        info!("mqtt discovery published as {}", node);
+    }
+
+    /// Handle broker events and apply received commands, then publish
+    /// the relay states they changed.
+    pub fn service<R1, R2>(&mut self, relay: &mut DoubleRelay<'_, R1, R2>)
+    where
+        R1: OutputPin,
+        R2: OutputPin,
+    {
+        while let Ok(event) = self.rx.try_recv() {
+            match event {
+                MqttEvent::Connected => self.on_connected(),
+                MqttEvent::Disconnected => {
+                    warn!("mqtt disconnected");
+                    self.connected = false;
+                }
+                MqttEvent::Command {
+                    relay: name,
+                    payload,
+                } => {
+                    self.command(relay, &name, &payload);
+                }
+            }
+        }
+
+        self.publish_changes(relay);
+    }
+
+    /// publish sensor readings and health every `PERIOD` seconds
+    pub fn publish_sensors(&mut self, inputs: &dyn RuleInputs, sensors: &[&str], queue_len: usize) {
+        if !self.connected {
+            return;
+        }
+        let now = sys_now();
+        if now < self.last_periodic + Self::PERIOD {
+            return;
+        }
+        self.last_periodic = now;
+
+        for name in sensors {
+            if let Some(value) = inputs.sensor(name) {
+                let payload = format!("{:.3}", value);
+                self.publish(&format!("sensor/{}", name), true, payload.as_bytes());
+            }
+        }
+
+        let health = Health {
+            uptime: uptime(),
+            free_heap: free_heap(),
+            queue: queue_len,
+        };
+        if let Ok(payload) = serde_json::to_vec(&health) {
+            self.publish("health", false, &payload);
+        }
+    }
+
+    fn command<R1, R2>(&mut self, relay: &mut DoubleRelay<'_, R1, R2>, name: &str, payload: &str)
+    where
+        R1: OutputPin,
+        R2: OutputPin,
+    {
+        info!("mqtt command {}: {}", name, payload);
+        let result = crate::relay_query(
+            name,
+            &mut payload.split_whitespace(),
+            Origin::Remote,
+            self.config.allow_force,
+        )
+        .and_then(|rlq| relay.interprete(rlq).map(|s| s.to_string()));
+
+        let text = match result {
+            Ok(status) => status,
+            Err(err) => format!("rejected: {}", err),
+        };
+        self.publish(&format!("relay/{}/result", name), false, text.as_bytes());
+    }
+
+    /// Publish the relay states changed since the previous call, called
+    /// right after orders and deadline events. Changes while offline are
+    /// covered by publishing every state on connect.
+    pub fn publish_changes<R1, R2>(&mut self, relay: &mut DoubleRelay<'_, R1, R2>)
+    where
+        R1: OutputPin,
+        R2: OutputPin,
+    {
+        let changes = relay.take_changes();
+        if !self.connected {
+            return;
+        }
+        let states = match std::mem::take(&mut self.republish) {
+            true => relay.states().to_vec(),
+            false => changes,
+        };
+
+        for state in states {
+            let topic = format!("relay/{}/state", state.name);
+            match serde_json::to_vec(&state) {
+                Ok(payload) => self.publish(&topic, true, &payload),
+                Err(err) => warn!("mqtt state of {}: {}", state.name, err),
+            }
+        }
+    }
+}
+
+/// runs on the MQTT task, hands events over to the main loop
+fn forward(
+    tx: &Sender<MqttEvent>,
+    command_prefix: &str,
+    payload: EventPayload<'_, esp_idf_svc::sys::EspError>,
+) {
+    let event = match payload {
+        EventPayload::Connected(_) => MqttEvent::Connected,
+        EventPayload::Disconnected => MqttEvent::Disconnected,
+        EventPayload::Received {
+            topic: Some(topic),
+            data,
+            ..
+        } => {
+            let relay = topic
+                .strip_prefix(command_prefix)
+                .and_then(|t| t.strip_suffix("/set"));
+            let (Some(relay), Ok(payload)) = (relay, std::str::from_utf8(data)) else {
+                warn!("mqtt message on {} ignored", topic);
+                return;
+            };
+            MqttEvent::Command {
+                relay: relay.to_owned(),
+                payload: payload.to_owned(),
+            }
+        }
+        _ => return,
+    };
+
+    if tx.send(event).is_err() {
+        warn!("mqtt receiver dropped");
+    }
+}
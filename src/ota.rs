@@ -0,0 +1,199 @@
+use anyhow::Error;
+use embedded_svc::http::client::Client;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::client::EspHttpConnection;
+use esp_idf_svc::ota::{EspOta, SlotState};
+use esp_idf_svc::sys;
+use log::{error, info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// `/ota [url] [sha256]` of an admin
+pub struct OtaRequest {
+    pub chat_id: u32,
+    pub url: String,
+    sha256: [u8; 32],
+}
+
+impl OtaRequest {
+    const USAGE: &'static str = "expected \"/ota [https url] [sha256 of the image]\"";
+
+    pub fn parse(chat_id: u32, url: &str, sha256: Option<&str>) -> anyhow::Result<Self> {
+        let hex = sha256.ok_or(Error::msg(Self::USAGE))?;
+        if !url.starts_with("https://") {
+            return Err(Error::msg("firmware is only downloaded over https"));
+        }
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(Error::msg(Self::USAGE));
+        }
+
+        let mut sha256 = [0u8; 32];
+        for (i, byte) in sha256.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| Error::msg(Self::USAGE))?;
+        }
+        Ok(Self {
+            chat_id,
+            url: url.to_owned(),
+            sha256,
+        })
+    }
+}
+
+/// SHA-256 of the mbedTLS port, hardware accelerated
+struct Sha256(sys::mbedtls_sha256_context);
+
+impl Sha256 {
+    fn new() -> Self {
+        let mut ctx = Default::default();
+        unsafe {
+            sys::mbedtls_sha256_init(&mut ctx);
+            sys::mbedtls_sha256_starts(&mut ctx, 0);
+        }
+        Self(ctx)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        unsafe { sys::mbedtls_sha256_update(&mut self.0, data.as_ptr(), data.len()) };
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let mut out = [0u8; 32];
+        unsafe { sys::mbedtls_sha256_finish(&mut self.0, out.as_mut_ptr()) };
+        out
+    }
+}
+
+impl Drop for Sha256 {
+    fn drop(&mut self) {
+        unsafe { sys::mbedtls_sha256_free(&mut self.0) };
+    }
+}
+
+/// Download the image into the inactive slot, calling `progress` with
+/// every completed quarter. The image must match the requested SHA-256,
+/// and completing the update checks its signature against the key of
+/// the running firmware before it is set to boot.
+pub fn download(
+    req: &OtaRequest,
+    conn: EspHttpConnection,
+    progress: &mut dyn FnMut(u8),
+) -> anyhow::Result<()> {
+    let mut client = Client::wrap(conn);
+    let mut response = client.get(&req.url)?.submit()?;
+    if response.status() != 200 {
+        return Err(Error::msg(format!(
+            "download failed with code {}",
+            response.status()
+        )));
+    }
+    let total = response
+        .header("Content-Length")
+        .and_then(|l| l.parse::<usize>().ok());
+
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    let mut sha = Sha256::new();
+    let mut buf = vec![0u8; 4096];
+    let (mut written, mut reported) = (0usize, 0u8);
+    let copied = loop {
+        let n = match response.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => n,
+            Err(err) => break Err(Error::msg(format!("download error: {}", err))),
+        };
+        sha.update(&buf[..n]);
+        if let Err(err) = update.write_all(&buf[..n]) {
+            break Err(Error::msg(format!("flash write error: {:?}", err)));
+        }
+
+        written += n;
+        if let Some(total) = total.filter(|t| *t > 0) {
+            let quarter = (written * 4 / total).min(4) as u8 * 25;
+            if quarter > reported && quarter < 100 {
+                reported = quarter;
+                progress(quarter);
+            }
+        }
+    };
+
+    let verified = copied.and_then(|()| match sha.finish() == req.sha256 {
+        true => Ok(()),
+        false => Err(Error::msg("SHA-256 of the image does not match")),
+    });
+    if let Err(err) = verified {
+        if let Err(abort) = update.abort() {
+            warn!("ota abort error: {}", abort);
+        }
+        return Err(err);
+    }
+
+    update
+        .complete()
+        .map_err(|err| Error::msg(format!("image rejected, bad signature or format: {}", err)))?;
+    info!("ota image of {} bytes written", written);
+    progress(100);
+    Ok(())
+}
+
+/// version and slot of the running firmware
+pub fn report() -> anyhow::Result<String> {
+    let ota = EspOta::new()?;
+    let slot = ota.get_running_slot()?;
+    Ok(format!(
+        "Firmware {} on {} ({:?})",
+        env!("CARGO_PKG_VERSION"),
+        slot.label,
+        slot.state
+    ))
+}
+
+/// After an update the bootloader boots the new image once unverified.
+/// It must reach Wi-Fi and Telegram within `WINDOW`, otherwise the
+/// device marks it invalid and reboots into the previous firmware.
+pub struct HealthWindow {
+    confirmed: Arc<AtomicBool>,
+}
+
+impl HealthWindow {
+    const WINDOW: Duration = Duration::from_secs(300);
+
+    /// Some when the running image still waits for confirmation
+    pub fn start() -> anyhow::Result<Option<Self>> {
+        let state = EspOta::new()?.get_running_slot()?.state;
+        if state != SlotState::Unverified {
+            return Ok(None);
+        }
+
+        warn!("new firmware unverified, rollback in {:?}", Self::WINDOW);
+        let confirmed = Arc::new(AtomicBool::new(false));
+        let flag = confirmed.clone();
+        std::thread::Builder::new()
+            .stack_size(4096)
+            .spawn(move || {
+                std::thread::sleep(Self::WINDOW);
+                if flag.load(Ordering::SeqCst) {
+                    return;
+                }
+                error!("firmware not confirmed in time, rolling back");
+                match EspOta::new() {
+                    Ok(mut ota) => {
+                        let err = ota.mark_running_slot_invalid_and_reboot();
+                        error!("rollback error: {}", err);
+                    }
+                    Err(err) => error!("rollback error: {}", err),
+                }
+                unsafe { sys::esp_restart() };
+            })?;
+        Ok(Some(Self { confirmed }))
+    }
+
+    /// mark the running image valid, keeping it for later boots
+    pub fn confirm(self) -> anyhow::Result<()> {
+        EspOta::new()?.mark_running_slot_valid()?;
+        self.confirmed.store(true, Ordering::SeqCst);
+        info!("firmware {} confirmed", env!("CARGO_PKG_VERSION"));
+        Ok(())
+    }
+}
@@ -0,0 +1,56 @@
+use esp_idf_svc::hal::gpio::{AnyInputPin, Input, PinDriver, Pull};
+use std::rc::Rc;
+
+use crate::adc::{Adc, AdcChannel};
+use crate::feedback::{Feedback, FeedbackConfig, FeedbackProbe, ThresholdProbe};
+use crate::util::input_pin;
+
+/// auxiliary contact wired to a digital input
+pub struct InputProbe<'d> {
+    pin: PinDriver<'d, AnyInputPin, Input>,
+    active_low: bool,
+}
+
+impl<'d> FeedbackProbe for InputProbe<'d> {
+    fn is_on(&mut self) -> anyhow::Result<bool> {
+        Ok(self.pin.is_high() != self.active_low)
+    }
+}
+
+/// Feedback of a relay on its configured pin, the ADC is only created
+/// when a current probe needs it.
+pub fn feedback(
+    config: &FeedbackConfig,
+    adc: &mut Option<Rc<Adc>>,
+) -> anyhow::Result<Feedback<'static>> {
+    match *config {
+        FeedbackConfig::Input {
+            pin,
+            active_low,
+            settle,
+        } => {
+            let mut pin = PinDriver::input(input_pin(pin)?)?;
+            pin.set_pull(if active_low { Pull::Up } else { Pull::Down })?;
+            Ok(Feedback::new(
+                Box::new(InputProbe { pin, active_low }),
+                settle,
+            ))
+        }
+        FeedbackConfig::Current {
+            pin,
+            threshold_mv,
+            settle,
+        } => {
+            let adc = match adc {
+                Some(adc) => adc.clone(),
+                None => adc.insert(Rc::new(Adc::new()?)).clone(),
+            };
+            let channel: AdcChannel = adc.channel(pin)?;
+            let sample = move || adc.read_mv(&channel);
+            Ok(Feedback::new(
+                Box::new(ThresholdProbe::new(sample, threshold_mv)),
+                settle,
+            ))
+        }
+    }
+}
@@ -0,0 +1,396 @@
+use anyhow::Error;
+use embedded_svc::http::Method;
+use embedded_svc::io::Write;
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::gpio::{PinDriver, Pull};
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_svc::sys;
+use esp_idf_svc::wifi::{
+    AccessPointConfiguration, AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi,
+};
+use log::{error, info, warn};
+use serde_json::json;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender, SyncSender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::ConfigStore;
+use crate::util::{device_id, input_pin};
+use crate::wifi::WifiConfig;
+
+/// page of the captive portal, compiled into flash
+const PORTAL_HTML: &str = include_str!("../web/portal.html");
+
+/// Button held down to start the provisioning portal, sampled on its
+/// own thread so a press is seen while the device retries Wi-Fi.
+pub struct ProvisionButton {
+    held: Arc<AtomicBool>,
+}
+
+impl ProvisionButton {
+    const SAMPLE_MS: u32 = 100;
+    const HOLD_MS: u32 = 5000;
+
+    pub fn start(pin: Option<u8>) -> anyhow::Result<Self> {
+        let held = Arc::new(AtomicBool::new(false));
+        let Some(num) = pin else {
+            return Ok(Self { held });
+        };
+
+        // pressed pulls the pin low
+        let mut pin = PinDriver::input(input_pin(num)?)?;
+        pin.set_pull(Pull::Up)?;
+        info!("provisioning button gpio{}", num);
+
+        let flag = held.clone();
+        std::thread::Builder::new()
+            .stack_size(2048)
+            .spawn(move || {
+                let mut pressed_ms = 0;
+                loop {
+                    match pin.is_low() {
+                        true => pressed_ms += Self::SAMPLE_MS,
+                        false => pressed_ms = 0,
+                    }
+                    // once per press, however long it is held
+                    if pressed_ms == Self::HOLD_MS {
+                        info!("provisioning button held");
+                        flag.store(true, Ordering::SeqCst);
+                    }
+                    FreeRtos::delay_ms(Self::SAMPLE_MS);
+                }
+            })?;
+        Ok(Self { held })
+    }
+
+    /// the button was held long enough since the last call
+    pub fn requested(&self) -> bool {
+        self.held.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// network entered on the portal page
+struct Credentials {
+    ssid: String,
+    password: String,
+    /// kept as stored when left empty
+    bot_token: Option<String>,
+}
+
+/// Portal requests are read on the server task and answered by the
+/// provisioning loop, which owns the Wi-Fi driver and the config.
+enum PortalCall {
+    Scan(SyncSender<String>),
+    Save(Credentials, SyncSender<Result<(), String>>),
+}
+
+/// Start an access point with a captive portal to choose a network and
+/// enter its password and the bot token. The device restarts once they
+/// are stored. After `PORTAL_TIMEOUT` without a visitor the portal closes
+/// and the known networks are retried, e.g. when the router was only
+/// powered off.
+pub fn run(
+    wifi: &mut BlockingWifi<EspWifi<'static>>,
+    store: &mut ConfigStore,
+    config: &WifiConfig,
+) {
+    match portal(wifi, store, config) {
+        Ok(true) => {
+            info!("wifi credentials stored, restarting");
+            unsafe { sys::esp_restart() }
+        }
+        Ok(false) => warn!("provisioning timed out"),
+        Err(err) => error!("provisioning error: {}", err),
+    }
+    // the station is started again by the next connection attempt
+    if let Err(err) = wifi.stop() {
+        warn!("stop provisioning access point error: {}", err);
+    }
+}
+
+const PORTAL_TIMEOUT: Duration = Duration::from_secs(900);
+static DNS_STARTED: AtomicBool = AtomicBool::new(false);
+/// a scan takes a few seconds
+const REPLY_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_BODY: usize = 512;
+
+/// WPA2 password of the access point, `ap_password` or the one made up
+/// for the device. Anyone on the portal can replace the Wi-Fi credentials
+/// and the bot token, so it is never open.
+pub fn ap_password(store: &mut ConfigStore, config: &WifiConfig) -> anyhow::Result<String> {
+    match &config.ap_password {
+        Some(password) => Ok(password.clone()),
+        None => store.portal_password(),
+    }
+}
+
+fn portal(
+    wifi: &mut BlockingWifi<EspWifi<'static>>,
+    store: &mut ConfigStore,
+    config: &WifiConfig,
+) -> anyhow::Result<bool> {
+    let ssid = format!("pomel-{}", device_id()?);
+    let password = ap_password(store, config)?;
+    let ap = AccessPointConfiguration {
+        ssid: ssid
+            .as_str()
+            .try_into()
+            .map_err(|_| Error::msg("access point ssid too long"))?,
+        auth_method: AuthMethod::WPA2Personal,
+        password: password
+            .as_str()
+            .try_into()
+            .map_err(|_| Error::msg("wifi: ap_password too long"))?,
+        channel: 1,
+        max_connections: 4,
+        ..Default::default()
+    };
+
+    // the station side stays up unconnected for scanning
+    if wifi.is_started()? {
+        wifi.stop()?;
+    }
+    wifi.set_configuration(&Configuration::Mixed(ClientConfiguration::default(), ap))?;
+    wifi.start()?;
+    let ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    warn!("provisioning portal on {} at http://{}/", ssid, ip);
+
+    // the server outlives the portal and answers the next one
+    if !DNS_STARTED.swap(true, Ordering::SeqCst) {
+        std::thread::Builder::new()
+            .stack_size(4096)
+            .spawn(move || dns_server(ip))?;
+    }
+    let (tx, rx) = mpsc::channel();
+    let _server = start_server(tx, ip)?;
+
+    let mut deadline = Instant::now() + PORTAL_TIMEOUT;
+    while Instant::now() < deadline {
+        let Ok(call) = rx.recv_timeout(Duration::from_secs(1)) else {
+            continue;
+        };
+        deadline = Instant::now() + PORTAL_TIMEOUT;
+        match call {
+            PortalCall::Scan(reply) => {
+                let _ = reply.send(scan(wifi));
+            }
+            PortalCall::Save(creds, reply) => {
+                let saved = store
+                    .provision(&creds.ssid, &creds.password, creds.bot_token.as_deref())
+                    .map_err(|err| err.to_string());
+                let done = saved.is_ok();
+                let _ = reply.send(saved);
+                if done {
+                    // let the server send the answer before the restart
+                    FreeRtos::delay_ms(2000);
+                    return Ok(true);
+                }
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// networks in range as JSON, strongest first and one entry per name
+fn scan(wifi: &mut BlockingWifi<EspWifi<'static>>) -> String {
+    let mut found = wifi.scan().unwrap_or_else(|err| {
+        warn!("wifi scan error: {}", err);
+        Vec::new()
+    });
+    found.sort_by_key(|ap| std::cmp::Reverse(ap.signal_strength));
+
+    let mut networks = Vec::new();
+    let mut seen: Vec<&str> = Vec::new();
+    for ap in &found {
+        let ssid: &str = &ap.ssid;
+        if ssid.is_empty() || seen.contains(&ssid) {
+            continue;
+        }
+        seen.push(ssid);
+        networks.push(json!({
+            "ssid": ssid,
+            "rssi": ap.signal_strength,
+            "open": ap.auth_method == Some(AuthMethod::None),
+        }));
+    }
+    json!(networks).to_string()
+}
+
+fn start_server(tx: Sender<PortalCall>, ip: Ipv4Addr) -> anyhow::Result<EspHttpServer<'static>> {
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    let scan_tx = tx.clone();
+    server.fn_handler("/scan", Method::Get, move |req| -> anyhow::Result<()> {
+        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+        scan_tx
+            .send(PortalCall::Scan(reply_tx))
+            .map_err(|_| Error::msg("portal receiver dropped"))?;
+        let body = reply_rx
+            .recv_timeout(REPLY_TIMEOUT)
+            .unwrap_or_else(|_| String::from("[]"));
+
+        let headers = [("Content-Type", "application/json")];
+        req.into_response(200, None, &headers)?
+            .write_all(body.as_bytes())?;
+        Ok(())
+    })?;
+
+    server.fn_handler(
+        "/save",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let mut body = Vec::new();
+            let mut buf = [0u8; 128];
+            loop {
+                let n = req.read(&mut buf)?;
+                if n == 0 || body.len() > MAX_BODY {
+                    break;
+                }
+                body.extend_from_slice(&buf[..n]);
+            }
+            let body = String::from_utf8_lossy(&body);
+
+            let ssid = form_value(&body, "ssid").unwrap_or_default();
+            let saved = match ssid.trim() {
+                "" => Err(String::from("choose a network")),
+                ssid => {
+                    let creds = Credentials {
+                        ssid: ssid.to_owned(),
+                        password: form_value(&body, "password").unwrap_or_default(),
+                        bot_token: form_value(&body, "bot_token")
+                            .map(|t| t.trim().to_owned())
+                            .filter(|t| !t.is_empty()),
+                    };
+                    let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+                    tx.send(PortalCall::Save(creds, reply_tx))
+                        .map_err(|_| Error::msg("portal receiver dropped"))?;
+                    reply_rx
+                        .recv_timeout(REPLY_TIMEOUT)
+                        .unwrap_or_else(|_| Err(String::from("device busy")))
+                }
+            };
+
+            let (status, text) = match saved {
+                Ok(()) => (
+                    200,
+                    format!("Saved, the device restarts and joins {}.", ssid.trim()),
+                ),
+                Err(err) => (400, format!("Not saved: {}\nGo back and try again.", err)),
+            };
+            let headers = [("Content-Type", "text/plain; charset=utf-8")];
+            req.into_response(status, None, &headers)?
+                .write_all(text.as_bytes())?;
+            Ok(())
+        },
+    )?;
+
+    // registered last, the wildcard would match the paths above; phones
+    // probing for internet access are sent to the portal page
+    let location = format!("http://{}/", ip);
+    server.fn_handler("/*", Method::Get, move |req| -> anyhow::Result<()> {
+        if req.uri() == "/" {
+            let headers = [("Content-Type", "text/html")];
+            req.into_response(200, None, &headers)?
+                .write_all(PORTAL_HTML.as_bytes())?;
+        } else {
+            let headers = [("Location", location.as_str())];
+            req.into_response(302, None, &headers)?;
+        }
+        Ok(())
+    })?;
+    Ok(server)
+}
+
+/// Answer every name with the portal address. Runs until the restart.
+fn dns_server(ip: Ipv4Addr) {
+    let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 53)) {
+        Ok(socket) => socket,
+        Err(err) => {
+            error!("portal dns error: {}", err);
+            return;
+        }
+    };
+
+    let mut buf = [0u8; 512];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err) => {
+                warn!("portal dns receive error: {}", err);
+                FreeRtos::delay_ms(100);
+                continue;
+            }
+        };
+        if let Some(answer) = dns_answer(&buf[..len], ip) {
+            if let Err(err) = socket.send_to(&answer, peer) {
+                warn!("portal dns send error: {}", err);
+            }
+        }
+    }
+}
+
+/// Response to a standard query of a single question. A queries get
+/// `ip`, other types an empty answer.
+fn dns_answer(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    // a query with opcode 0 and one question
+    if query.len() < 12 || query[2] & 0xf8 != 0 || query[4..6] != [0, 1] {
+        return None;
+    }
+    let mut pos = 12;
+    while *query.get(pos)? != 0 {
+        pos += 1 + *query.get(pos)? as usize;
+    }
+    // zero label, type and class
+    let end = pos + 5;
+    let is_a = query.get(pos + 1..end)? == [0, 1, 0, 1];
+
+    let mut answer = Vec::with_capacity(end + 16);
+    answer.extend_from_slice(&query[..2]);
+    // response, authoritative, recursion desired as asked and available
+    answer.extend_from_slice(&[0x84 | (query[2] & 0x01), 0x80]);
+    answer.extend_from_slice(&[0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+    answer.extend_from_slice(&query[12..end]);
+    if is_a {
+        // name pointer to the question, type A, class IN, ttl 60 s
+        answer.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        answer.extend_from_slice(&ip.octets());
+    }
+    Some(answer)
+}
+
+/// value of `key` in an `application/x-www-form-urlencoded` body
+fn form_value(body: &str, key: &str) -> Option<String> {
+    body.split('&').find_map(|pair| {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        (k == key).then(|| url_decode(v))
+    })
+}
+
+fn url_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' => match s
+                .get(i + 1..i + 3)
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+            {
+                Some(byte) => {
+                    out.push(byte);
+                    i += 2;
+                }
+                None => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
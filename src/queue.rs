@@ -1,8 +1,10 @@
 use core::str;
 use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::warn;
 use std::fmt::Display;
 
 use crate::telegram::SendMessage;
+use crate::text;
 
 // pub enum QueueError {
 //     InsertAtFull,
@@ -30,20 +32,31 @@ pub struct MsgFMQueue {
 }
 
 impl MsgFMQueue {
+    /// longest text of one entry, longer replies are split at line breaks
+    const MAX_TEXT: usize = 1000;
+
     pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
         Ok(Self {
             inner: FMemQueue::new(partition)?,
         })
     }
 
+    /// queue `msg`, true when every piece of it was stored
     pub fn enqueue(&mut self, msg: SendMessage) -> bool {
-        let buf = msg.into_bytes();
-        self.inner.enqueue(&buf)
+        let mut stored = true;
+        for piece in text::split(&msg.text, Self::MAX_TEXT) {
+            let entry = SendMessage {
+                chat_id: msg.chat_id,
+                text: piece.to_owned(),
+            };
+            stored &= self.inner.enqueue(&entry.into_bytes());
+        }
+        stored
     }
 
-    pub fn peek(&mut self, buf: &mut [u8]) -> Option<SendMessage> {
-        let peek = self.inner.peek(buf)?;
-        let msg = SendMessage::from_bytes(peek);
+    pub fn peek(&mut self) -> Option<SendMessage> {
+        let peek = self.inner.peek()?;
+        let msg = SendMessage::from_bytes(&peek);
         Some(msg)
     }
 
@@ -54,6 +67,15 @@ impl MsgFMQueue {
     pub fn is_empty(&self) -> bool {
         self.inner.is_empty()
     }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// oldest messages dropped for new ones since the last call
+    pub fn take_dropped(&mut self) -> u32 {
+        std::mem::take(&mut self.inner.dropped)
+    }
 }
 
 // Ring buffer
@@ -62,10 +84,13 @@ pub struct FMemQueue {
     /// head = addr[0]
     /// tail = addr[1]
     addr: [u8; 2],
+    /// entries dropped to make room, not kept across a restart
+    dropped: u32,
 }
 
 impl FMemQueue {
-    const QUEUE_LIMIT: u8 = 20;
+    /// one slot stays free, single character keys `A` to `|`
+    const QUEUE_LIMIT: u8 = 60;
     const START_INDEX: u8 = 0x41;
 
     pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
@@ -80,6 +105,7 @@ impl FMemQueue {
         Ok(Self {
             storage,
             addr: [head, tail],
+            dropped: 0,
         })
     }
 
@@ -91,40 +117,60 @@ impl FMemQueue {
         self.storage.set_u8(&key, self.addr[idx]).unwrap();
     }
 
+    /// Store `value` at the tail. When the queue or the flash is full the
+    /// oldest entries make room, newer messages matter more after an
+    /// outage. False when it could not be stored at all.
     pub fn enqueue(&mut self, value: &[u8]) -> bool {
-        let is_full = self.is_full();
-        if is_full {
-            return !is_full;
+        if self.is_full() {
+            self.drop_oldest();
+        }
+        let key = [self.addr[1]];
+        let tail = unsafe { str::from_utf8_unchecked(&key) };
+        while let Err(err) = self.storage.set_blob(tail, value) {
+            if self.is_empty() {
+                warn!("queue entry not stored: {}", err);
+                self.dropped += 1;
+                return false;
+            }
+            self.drop_oldest();
         }
-        let tail = unsafe { str::from_utf8_unchecked(&self.addr[1..]) };
-
-        self.storage.set_blob(tail, value).unwrap();
 
         // increment tail
         self.increment_address(QTarget::Tail);
-        false
+        true
     }
 
-    pub fn dequeue<'a>(&mut self, buf: &'a mut [u8]) -> Option<&'a [u8]> {
-        let peek = self.peek(buf)?;
-        match self.remove_first() {
-            true => panic!(),
-            false => Some(peek),
-        }
+    fn drop_oldest(&mut self) {
+        self.remove_first();
+        self.dropped += 1;
+        warn!("queue full, {} oldest entries dropped", self.dropped);
     }
 
-    pub fn peek<'a>(&mut self, buf: &'a mut [u8]) -> Option<&'a [u8]> {
-        if self.is_empty() {
-            return None;
-        }
-
-        let head = unsafe { str::from_utf8_unchecked(&self.addr[0..1]) };
-        let get_val = self.storage.get_blob(head, buf).unwrap();
+    pub fn dequeue(&mut self) -> Option<Vec<u8>> {
+        let peek = self.peek()?;
+        self.remove_first();
+        Some(peek)
+    }
 
-        match get_val {
-            None => panic!(),
-            Some(rslt) => Some(rslt),
+    /// First entry, read into a buffer of its stored length. An entry
+    /// that cannot be read is dropped, it would block the queue for good.
+    pub fn peek(&mut self) -> Option<Vec<u8>> {
+        while !self.is_empty() {
+            let head = unsafe { str::from_utf8_unchecked(&self.addr[0..1]) };
+            let read = self.storage.blob_len(head).and_then(|len| {
+                let mut buf = vec![0u8; len.unwrap_or_default()];
+                let len = self.storage.get_blob(head, &mut buf)?.map(<[u8]>::len);
+                buf.truncate(len.unwrap_or_default());
+                Ok(len.map(|_| buf))
+            });
+            match read {
+                Ok(Some(entry)) if entry.len() >= 4 => return Some(entry),
+                Ok(_) => warn!("queue entry {} missing, dropped", head),
+                Err(err) => warn!("queue entry {} unreadable, dropped: {}", head, err),
+            }
+            self.remove_first();
         }
+        None
     }
 
     pub fn remove_first(&mut self) -> bool {
@@ -144,6 +190,11 @@ impl FMemQueue {
         self.addr[0] == self.addr[1]
     }
 
+    pub fn len(&self) -> usize {
+        let [head, tail] = self.addr;
+        ((tail + Self::QUEUE_LIMIT - head) % Self::QUEUE_LIMIT) as usize
+    }
+
     pub fn is_full(&self) -> bool {
         let inc_tail = self.increment(self.addr[1]);
         let head = self.addr[0];
@@ -1,31 +1,161 @@
 use std::fmt::Display;
 
+use crate::feedback::{Fault, Feedback};
+use crate::flow::FlowMeter;
+use crate::stats::{RunStats, StatsReport, StatsStore};
 use crate::util::{sys_now, Time};
+use crate::{BudgetMode, RelayChannelConfig, RelayConfig};
 use anyhow::Error;
 use esp_idf_svc::hal::{
     gpio::{Output, OutputPin, PinDriver},
     peripheral::Peripheral,
 };
-use log::info;
+use log::{info, warn};
+use serde::Serialize;
+
+/// who placed an order
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Origin {
+    Chat(u32),
+    /// push button on the device
+    Local,
+    /// MQTT command topic or the local HTTP API
+    Remote,
+    /// rule or sensor hook on the device
+    Automation,
+}
 
 #[derive(Clone, Debug)]
 pub struct RunOrder {
+    /// when the order was received
+    pub requested_at: Time,
+    /// when the relay actually turns on
     pub start_at: Time,
     pub end_at: Time,
-    pub order_by: u32,
+    pub order_by: Origin,
+    /// switch on and off periodically until `end_at`
+    pub cycle: Option<Cycle>,
+    /// `end_at` was shortened to fit the daily runtime budget
+    pub truncated: bool,
+    /// stop after this many litres, `end_at` stays as safety limit
+    pub volume_limit: Option<f32>,
+}
+
+/// duty cycle of a run order, in seconds
+#[derive(Clone, Copy, Debug)]
+pub struct Cycle {
+    pub on: u32,
+    pub off: u32,
+}
+
+impl Cycle {
+    #[inline]
+    fn period(&self) -> u64 {
+        self.on as u64 + self.off as u64
+    }
 }
 
 impl RunOrder {
     #[inline]
     /// panic when end <= start
-    pub fn new(start_at: u64, end_at: u64, chat_id: u32) -> Self {
+    pub fn new(start_at: u64, end_at: u64, origin: Origin) -> Self {
         assert!(start_at <= end_at);
         Self {
+            requested_at: Time::new(start_at),
             start_at: Time::new(start_at),
             end_at: Time::new(end_at),
-            order_by: chat_id,
+            order_by: origin,
+            cycle: None,
+            truncated: false,
+            volume_limit: None,
+        }
+    }
+
+    /// shift the order to start at `start_at`, keeping its duration
+    pub fn defer(&mut self, start_at: u64) {
+        let current = self.start_at.as_secs();
+        if start_at <= current {
+            return;
+        }
+
+        let shift = start_at - current;
+        self.start_at = Time::new(start_at);
+        self.end_at = Time::new(self.end_at.as_secs() + shift);
+    }
+
+    /// follow the clock moved ahead by `secs`
+    fn shift(&mut self, secs: u64) {
+        self.requested_at = Time::new(self.requested_at.as_secs() + secs);
+        self.start_at = Time::new(self.start_at.as_secs() + secs);
+        self.end_at = Time::new(self.end_at.as_secs() + secs);
+    }
+
+    /// seconds between the request and the actual start
+    #[inline]
+    pub fn delay(&self) -> u64 {
+        self.start_at.as_secs() - self.requested_at.as_secs()
+    }
+
+    /// cycle index and whether it is in the on phase at `now`,
+    /// an order without cycle is a single endless on phase
+    pub fn phase_at(&self, now: u64) -> (u64, bool) {
+        let elapsed = now.saturating_sub(self.start_at.as_secs());
+        match self.cycle {
+            None => (0, true),
+            Some(c) => (elapsed / c.period(), elapsed % c.period() < c.on as u64),
+        }
+    }
+
+    /// seconds the relay is planned to be on during the whole order
+    pub fn on_seconds(&self) -> u64 {
+        let duration = self.end_at.as_secs() - self.start_at.as_secs();
+        match self.cycle {
+            None => duration,
+            Some(c) => {
+                let rem = duration % c.period();
+                (duration / c.period()) * c.on as u64 + rem.min(c.on as u64)
+            }
+        }
+    }
+
+    /// shorten the order so it is on for `on_secs` at most
+    pub fn truncate_on(&mut self, on_secs: u64) {
+        let start = self.start_at.as_secs();
+        let end = match self.cycle {
+            None => start + on_secs,
+            Some(c) => {
+                let (full, rem) = (on_secs / c.on as u64, on_secs % c.on as u64);
+                match rem {
+                    // end right after the last full on phase
+                    0 => start + (full * c.period()).saturating_sub(c.off as u64),
+                    _ => start + full * c.period() + rem,
+                }
+            }
+        };
+
+        if end < self.end_at.as_secs() {
+            self.end_at = Time::new(end);
+            self.truncated = true;
         }
     }
+
+    /// next time the relay switches on or off
+    pub fn next_transition(&self, now: u64) -> Time {
+        let start = self.start_at.as_secs();
+        let end = self.end_at.as_secs();
+        let next = match self.cycle {
+            _ if now < start => start,
+            None => end,
+            Some(c) => {
+                let pos = (now - start) % c.period();
+                match pos < c.on as u64 {
+                    true => now + c.on as u64 - pos,
+                    false => now + c.period() - pos,
+                }
+            }
+        };
+        Time::new(next.min(end))
+    }
 }
 
 struct Relay<'drv, R>
@@ -35,6 +165,42 @@ where
     pin: PinDriver<'drv, R, Output>,
     name: &'static str,
     running: Option<RunOrder>,
+    /// pin is high, false while a staggered order waits for its start
+    energized: bool,
+    /// minimum seconds the relay stays on once started
+    min_on: u64,
+    /// minimum seconds the relay rests before the next start
+    min_off: u64,
+    /// when the relay was last switched off, 0 if never
+    last_stop: u64,
+    /// last cycle index switched on, a short pulse is served even
+    /// when no poll lands inside its on phase
+    served_cycle: Option<u64>,
+    /// when the pin went high
+    energized_at: u64,
+    stats: RunStats,
+    /// stats changed since the last save
+    stats_dirty: bool,
+    /// order, pin, fault or interlock changed since the last
+    /// `take_changes`
+    state_changed: bool,
+    /// maximum run seconds per day, 0 is unlimited
+    daily_max: u64,
+    budget_mode: BudgetMode,
+    feedback: Option<Feedback<'drv>>,
+    fault: Option<Fault>,
+    /// when the pin last changed
+    last_switch: u64,
+    /// origin of the latest order, notified about faults
+    last_order_by: Option<Origin>,
+    /// sensor condition blocking the relay
+    interlock: Option<StopCause>,
+    flow: Option<FlowMeter>,
+    /// flow pulse count when the order started
+    order_pulses: u32,
+    /// the last stop failed, the order stays and is stopped again on
+    /// the next poll
+    stop_failed: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -43,11 +209,52 @@ pub enum SetState {
     Stop,
 }
 
+/// why a running relay has to be stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopCause {
+    Deadline,
+    /// high level switch tripped
+    LevelHigh,
+    /// low level switch tripped, the pump would run dry
+    DryRun,
+    /// flow stayed below the minimum, the pump runs dry
+    LowFlow,
+    /// ordered volume passed the flow meter
+    Volume,
+}
+
+impl StopCause {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StopCause::Deadline => "deadline",
+            StopCause::LevelHigh => "level_high",
+            StopCause::DryRun => "dry_run",
+            StopCause::LowFlow => "low_flow",
+            StopCause::Volume => "volume",
+        }
+    }
+}
+
+impl Display for StopCause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            StopCause::Deadline => "Deadline",
+            StopCause::LevelHigh => "Water level high",
+            StopCause::DryRun => "Water level low, dry run protection",
+            StopCause::LowFlow => "Flow too low, dry run protection",
+            StopCause::Volume => "Volume reached",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 #[derive(Debug)]
 pub struct Event {
-    /// time to stop the device when its true
-    pub run_deadline: bool,
+    /// stop the device when set
+    pub cause: Option<StopCause>,
     pub name: &'static str,
+    /// feedback mismatch raised on this poll
+    pub fault: Option<Fault>,
 }
 
 impl<'drv, R> Relay<'drv, R>
@@ -55,54 +262,377 @@ where
     R: OutputPin,
 {
     #[inline]
-    fn new(pin: PinDriver<'drv, R, Output>, name: &'static str) -> Self {
+    fn new(
+        pin: PinDriver<'drv, R, Output>,
+        name: &'static str,
+        config: RelayChannelConfig,
+    ) -> Self {
         Self {
             pin,
             name,
             running: None,
+            energized: false,
+            min_on: config.min_on as u64,
+            min_off: config.min_off as u64,
+            last_stop: 0,
+            served_cycle: None,
+            energized_at: 0,
+            stats: RunStats::default(),
+            stats_dirty: false,
+            state_changed: false,
+            daily_max: config.daily_max as u64,
+            budget_mode: config.daily_max_mode,
+            feedback: None,
+            fault: None,
+            last_switch: 0,
+            last_order_by: None,
+            interlock: None,
+            flow: None,
+            order_pulses: 0,
+            stop_failed: false,
         }
     }
 
-    fn run(&mut self, ord: RunOrder) -> anyhow::Result<()> {
-        match self.running {
-            None => {
-                self.running = Some(ord);
+    /// time the relay is allowed to start again after its rest period
+    fn next_start(&self) -> Option<u64> {
+        if self.last_stop == 0 || self.min_off == 0 {
+            return None;
+        }
+        Some(self.last_stop + self.min_off)
+    }
+
+    /// Check an order against the protections, the budget may shorten
+    /// it. Nothing changes until `apply`.
+    fn admit(&self, state: SetState, now: u64, force: bool) -> anyhow::Result<SetState> {
+        match state {
+            SetState::Run(mut ord) => {
+                self.check_run(&mut ord, force)?;
+                Ok(SetState::Run(ord))
+            }
+            SetState::Stop => {
+                self.check_stop(now, force)?;
+                Ok(SetState::Stop)
+            }
+        }
+    }
+
+    /// switch to an admitted state
+    fn apply(&mut self, state: SetState, now: u64) -> anyhow::Result<()> {
+        match state {
+            SetState::Run(ord) => self.run(ord, now),
+            SetState::Stop => self.stop(now),
+        }
+    }
+
+    fn check_run(&self, ord: &mut RunOrder, force: bool) -> anyhow::Result<()> {
+        if self.running.is_some() {
+            return Err(Error::msg(format!(
+                "relay {} at ON state, turn off first!",
+                self.name
+            )));
+        }
+
+        if let Some(cause) = self.interlock {
+            return Err(Error::msg(format!(
+                "relay {} blocked: {}",
+                self.name, cause
+            )));
+        }
+
+        if !force {
+            if let Some(fault) = self.fault {
+                return Err(Error::msg(format!(
+                    "relay {} faulted: {}",
+                    self.name, fault
+                )));
+            }
+
+            if let Some(next) = self.next_start() {
+                if ord.start_at < Time::new(next) {
+                    return Err(Error::msg(format!(
+                        "relay {} is resting, next start allowed at {}",
+                        self.name,
+                        Time::new(next)
+                    )));
+                }
+            }
+
+            let (on, off) = match ord.cycle {
+                Some(c) => (c.on as u64, c.off as u64),
+                None => (ord.end_at.as_secs() - ord.start_at.as_secs(), u64::MAX),
+            };
+            if on < self.min_on {
+                return Err(Error::msg(format!(
+                    "relay {} must run at least {}s",
+                    self.name, self.min_on
+                )));
             }
-            Some(_) => {
+            if off < self.min_off {
                 return Err(Error::msg(format!(
-                    "relay {} at ON state, turn off first!",
-                    self.name
-                )))
+                    "relay {} must rest at least {}s between cycles",
+                    self.name, self.min_off
+                )));
             }
         }
 
-        self.pin.set_high().map_err(Into::into)
+        // the daily cap holds even for forced orders
+        if self.daily_max > 0 {
+            self.apply_budget(ord)?;
+        }
+
+        if self.flow.is_none() && ord.volume_limit.is_some() {
+            return Err(Error::msg(format!(
+                "relay {} has no flow meter for volume orders",
+                self.name
+            )));
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, ord: RunOrder, now: u64) -> anyhow::Result<()> {
+        if let Some(flow) = &self.flow {
+            self.order_pulses = flow.count();
+        }
+
+        let start_now = ord.start_at <= Time::new(now);
+        self.last_order_by = Some(ord.order_by);
+        self.running = Some(ord);
+        self.served_cycle = None;
+        self.state_changed = true;
+
+        if !start_now {
+            return Ok(());
+        }
+        self.served_cycle = Some(0);
+        self.energize(now)
+    }
+
+    /// reject or shorten an order exceeding the run time left today,
+    /// counted against the day the order starts
+    fn apply_budget(&self, ord: &mut RunOrder) -> anyhow::Result<()> {
+        let used = self.stats.today(ord.start_at.as_secs()) as u64;
+        let left = self.daily_max.saturating_sub(used);
+        if ord.on_seconds() <= left {
+            return Ok(());
+        }
+
+        if left == 0 || matches!(self.budget_mode, BudgetMode::Reject) {
+            return Err(Error::msg(format!(
+                "relay {} daily runtime budget exceeded, {}s left of {}s",
+                self.name, left, self.daily_max
+            )));
+        }
+
+        ord.truncate_on(left);
+        Ok(())
+    }
+
+    fn energize(&mut self, now: u64) -> anyhow::Result<()> {
+        self.pin.set_high()?;
+        self.energized = true;
+        self.energized_at = now;
+        self.last_switch = now;
+        self.state_changed = true;
+        self.stats.add_start(now);
+        self.stats_dirty = true;
+        Ok(())
     }
 
-    fn stop(&mut self) -> anyhow::Result<()> {
+    fn deenergize(&mut self, now: u64) -> anyhow::Result<()> {
         self.pin.set_low()?;
+        self.account_run(now);
+        self.last_switch = now;
+        self.state_changed = true;
+        Ok(())
+    }
+
+    /// add the time since energized to the counters
+    fn account_run(&mut self, now: u64) {
+        if !self.energized {
+            return;
+        }
+        self.stats.add_run(self.energized_at, now);
+        self.stats_dirty = true;
+        self.energized = false;
+    }
+
+    /// keep deadlines and rest times set before the clock moved ahead
+    fn shift_clock(&mut self, secs: u64) {
+        if let Some(ord) = self.running.as_mut() {
+            ord.shift(secs);
+        }
+        // 0 is never
+        for time in [
+            &mut self.last_stop,
+            &mut self.energized_at,
+            &mut self.last_switch,
+        ] {
+            if *time > 0 {
+                *time += secs;
+            }
+        }
+        if let Some(flow) = self.flow.as_mut() {
+            flow.shift_clock(secs);
+        }
+    }
+
+    /// counters including the ongoing run
+    fn stats_at(&self, now: u64) -> RunStats {
+        let mut stats = self.stats.clone();
+        if self.energized {
+            stats.add_run(self.energized_at, now);
+        }
+        stats
+    }
+
+    /// Follow the running order: staggered start and duty cycle phases.
+    fn sync_phase(&mut self, now: u64) -> anyhow::Result<()> {
+        let ord = match self.running.as_mut() {
+            Some(ord) if ord.start_at <= Time::new(now) => ord,
+            _ => return Ok(()),
+        };
+        if self.served_cycle.is_none() {
+            // a staggered start lands on a poll, keep when it really started
+            ord.defer(now);
+        }
+
+        let (index, on_phase) = ord.phase_at(now);
+        let new_cycle = !matches!(self.served_cycle, Some(c) if c >= index);
+        match (self.energized, on_phase || new_cycle) {
+            (false, true) => {
+                info!("{} phase on, cycle {}", self.name, index);
+                self.served_cycle = Some(index);
+                self.energize(now)
+            }
+            (true, false) => {
+                info!("{} phase off, cycle {}", self.name, index);
+                self.deenergize(now)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_stop(&self, now: u64, force: bool) -> anyhow::Result<()> {
+        if let (Some(ord), true) = (&self.running, self.energized) {
+            let keep_until = ord.start_at.as_secs() + self.min_on;
+            if !force && now < keep_until {
+                return Err(Error::msg(format!(
+                    "relay {} must stay on until {}",
+                    self.name,
+                    Time::new(keep_until)
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self, now: u64) -> anyhow::Result<()> {
+        if let Err(err) = self.pin.set_low() {
+            self.stop_failed = true;
+            return Err(err.into());
+        }
+        self.stop_failed = false;
+        if self.energized {
+            self.last_stop = now;
+            self.last_switch = now;
+        }
+        self.account_run(now);
         self.running = None;
+        self.served_cycle = None;
+        self.state_changed = true;
         Ok(())
     }
 
-    fn set(&mut self, state: SetState) -> anyhow::Result<()> {
-        match state {
-            SetState::Run(ord) => self.run(ord),
-            SetState::Stop => self.stop(),
+    /// last time the pin went high, or the start of an order still waiting
+    fn latest_start(&self) -> u64 {
+        match (&self.running, self.served_cycle) {
+            (Some(ord), None) => ord.start_at.as_secs().max(self.energized_at),
+            _ => self.energized_at,
+        }
+    }
+
+    /// Read the feedback once settled, returns a fault when newly raised.
+    fn check_feedback(&mut self, now: u64) -> Option<Fault> {
+        let feedback = self.feedback.as_mut()?;
+        if !feedback.settled(now.saturating_sub(self.last_switch)) {
+            return None;
         }
+
+        let verdict = match feedback.verify(self.energized) {
+            Ok(verdict) => verdict,
+            Err(err) => {
+                warn!("cannot read feedback of {}: {}", self.name, err);
+                return None;
+            }
+        };
+
+        let raised = match (self.fault, verdict) {
+            (Some(_), None) => {
+                info!("{} feedback back to normal", self.name);
+                None
+            }
+            (old, Some(fault)) if old != Some(fault) => {
+                warn!("{} faulted: {}", self.name, fault);
+                Some(fault)
+            }
+            _ => None,
+        };
+        self.state_changed |= self.fault != verdict;
+        self.fault = verdict;
+        raised
     }
 
-    fn is_run_deadline(&self, now: u64) -> bool {
-        if let Some(r) = &self.running {
-            return r.end_at <= Time::new(now);
+    fn set_interlock(&mut self, cause: Option<StopCause>) {
+        self.state_changed |= self.interlock != cause;
+        self.interlock = cause;
+    }
+
+    fn sample_flow(&mut self, now: u64) {
+        if let Some(flow) = self.flow.as_mut() {
+            flow.sample(now);
+        }
+    }
+
+    /// litres since the order started
+    fn order_volume(&self) -> Option<f32> {
+        self.running.as_ref()?;
+        Some(self.flow.as_ref()?.litres(self.order_pulses))
+    }
+
+    /// an interlock stops the relay before its deadline
+    fn stop_cause(&self, now: u64) -> Option<StopCause> {
+        let r = self.running.as_ref()?;
+        if self.interlock.is_some() {
+            return self.interlock;
+        }
+
+        if let Some(flow) = &self.flow {
+            if self.energized && flow.is_dry(now.saturating_sub(self.energized_at)) {
+                return Some(StopCause::LowFlow);
+            }
+
+            let volume = flow.litres(self.order_pulses);
+            if r.volume_limit.is_some_and(|limit| volume >= limit) {
+                return Some(StopCause::Volume);
+            }
+        }
+
+        match r.end_at <= Time::new(now) {
+            true => Some(StopCause::Deadline),
+            false => None,
         }
-        false
     }
 
     fn get_status(&self) -> RelayStatus {
         RelayStatus {
             name: self.name,
             run_info: self.running.as_ref(),
+            energized: self.energized,
+            next_start: self.next_start().map(Time::new),
+            fault: self.fault,
+            interlock: self.interlock,
+            flow_lpm: self.flow.as_ref().map(FlowMeter::lpm),
+            volume: self.order_volume(),
         }
     }
 }
@@ -114,8 +644,13 @@ where
 {
     first_relay: Relay<'drv, R1>,
     second_relay: Relay<'drv, R2>,
+    /// minimum seconds between two turn-on events across the bank
+    start_gap: u64,
 }
 
+/// names of the first and second relay
+pub const RELAY_NAMES: [&str; 2] = ["pompa_air", "lain_lain"];
+
 #[derive(Clone, Copy)]
 pub enum RelayAddr {
     First = 1,
@@ -132,45 +667,99 @@ where
     pub fn new(
         first_pin: impl Peripheral<P = R1> + 'drv,
         second_pin: impl Peripheral<P = R2> + 'drv,
+        config: &RelayConfig,
     ) -> Self {
         Self {
-            first_relay: Relay::new(PinDriver::output(first_pin).unwrap(), "pompa_air"),
-            second_relay: Relay::new(PinDriver::output(second_pin).unwrap(), "lain_lain"),
+            first_relay: Relay::new(
+                PinDriver::output(first_pin).unwrap(),
+                RELAY_NAMES[0],
+                config.channel(RELAY_NAMES[0]),
+            ),
+            second_relay: Relay::new(
+                PinDriver::output(second_pin).unwrap(),
+                RELAY_NAMES[1],
+                config.channel(RELAY_NAMES[1]),
+            ),
+            start_gap: config.start_gap as u64,
         }
     }
 
-    pub fn set(&mut self, target: RelayAddr, state: SetState) -> anyhow::Result<()> {
+    /// `force` skips the minimum on/off time protection
+    pub fn set(&mut self, target: RelayAddr, state: SetState, force: bool) -> anyhow::Result<()> {
         let muxed = target as u8;
         if muxed == 3 && matches!(state, SetState::Run(_)) {
-            let first = self.first_relay.get_status();
-            let second = self.second_relay.get_status();
-
-            let mut err = String::new();
-            if first.run_info.is_some() {
-                err = format!("Relay {} at ON state, turn off first!", first.name);
-                if second.run_info.is_some() {
-                    err = format!(
-                        "{}\nRelay {} at ON state, turn off first!",
-                        err, second.name
-                    );
+            let mut err = Vec::new();
+            for status in [
+                self.first_relay.get_status(),
+                self.second_relay.get_status(),
+            ] {
+                if status.run_info.is_some() {
+                    err.push(format!(
+                        "Relay {} at ON state, turn off first!",
+                        status.name
+                    ));
                 }
             }
-            return Err(anyhow::Error::msg(err));
+
+            if !err.is_empty() {
+                return Err(anyhow::Error::msg(err.join("\n")));
+            }
         }
 
-        if (muxed & 1) == 1 {
+        // both relays are checked before either switches, so `Both`
+        // never ends half applied
+        let now = sys_now();
+        let first = match (muxed & 1) == 1 {
+            true => {
+                let state = self.sequence_start(state.clone(), 0);
+                Some(self.first_relay.admit(state, now, force)?)
+            }
+            false => None,
+        };
+        let second = match (muxed >> 1) == 1 {
+            true => {
+                // after the start planned for the first relay
+                let planned = match &first {
+                    Some(SetState::Run(ord)) => ord.start_at.as_secs(),
+                    _ => 0,
+                };
+                let state = self.sequence_start(state, planned);
+                Some(self.second_relay.admit(state, now, force)?)
+            }
+            false => None,
+        };
+
+        if let Some(state) = first {
             info!("first relay set : {:?}", state);
-            self.first_relay.set(state.clone())?;
+            self.first_relay.apply(state, now)?;
         }
-
-        if (muxed >> 1) == 1 {
+        if let Some(state) = second {
             info!("second relay set : {:?}", state);
-            self.second_relay.set(state)?;
+            self.second_relay.apply(state, now)?;
         }
-
         Ok(())
     }
 
+    /// Delay a run order so turn-on events keep at least `start_gap` apart,
+    /// limiting the inrush current when several pumps start together.
+    /// Only starts that happened or are still waiting count, a rejected
+    /// order leaves no trace. `planned` is a start not applied yet.
+    fn sequence_start(&self, state: SetState, planned: u64) -> SetState {
+        let SetState::Run(mut ord) = state else {
+            return state;
+        };
+
+        let last_start = self
+            .first_relay
+            .latest_start()
+            .max(self.second_relay.latest_start())
+            .max(planned);
+        if self.start_gap > 0 && last_start > 0 {
+            ord.defer(last_start + self.start_gap);
+        }
+        SetState::Run(ord)
+    }
+
     pub fn resolve_addr(&self, name: &str) -> Option<RelayAddr> {
         if name.eq("both") {
             Some(RelayAddr::Both)
@@ -186,27 +775,178 @@ where
     #[must_use]
     pub fn pool_event(&mut self) -> [Option<Event>; 2] {
         let t = sys_now();
-        let e1 = self.first_relay.is_run_deadline(t);
-        let e2 = self.second_relay.is_run_deadline(t);
+        self.sync_phase(t);
+        self.first_relay.sample_flow(t);
+        self.second_relay.sample_flow(t);
+
+        let e1 = self.first_relay.stop_cause(t);
+        let e2 = self.second_relay.stop_cause(t);
+        let f1 = self.first_relay.check_feedback(t);
+        let f2 = self.second_relay.check_feedback(t);
 
         let mut events: [Option<Event>; 2] = [const { None }; 2];
-        if e1 {
+        if e1.is_some() || f1.is_some() {
             events[0] = Some(Event {
                 name: self.first_relay.name,
-                run_deadline: e1,
+                cause: e1,
+                fault: f1,
             })
         }
 
-        if e2 {
+        if e2.is_some() || f2.is_some() {
             events[1] = Some(Event {
                 name: self.second_relay.name,
-                run_deadline: e2,
+                cause: e2,
+                fault: f2,
             })
         }
 
         events
     }
 
+    /// switch staggered starts and duty cycle phases that are due,
+    /// a failed switch is retried on the next poll
+    fn sync_phase(&mut self, now: u64) {
+        if let Err(err) = self.first_relay.sync_phase(now) {
+            warn!("cannot switch {}: {}", self.first_relay.name, err);
+        }
+
+        if let Err(err) = self.second_relay.sync_phase(now) {
+            warn!("cannot switch {}: {}", self.second_relay.name, err);
+        }
+    }
+
+    #[inline]
+    pub fn names(&self) -> [&'static str; 2] {
+        [self.first_relay.name, self.second_relay.name]
+    }
+
+    /// block a single relay by a sensor condition, None releases it
+    pub fn set_interlock(&mut self, target: RelayAddr, cause: Option<StopCause>) {
+        match target {
+            RelayAddr::First => self.first_relay.set_interlock(cause),
+            RelayAddr::Second => self.second_relay.set_interlock(cause),
+            RelayAddr::Both => {}
+        }
+    }
+
+    pub fn set_flow(&mut self, target: RelayAddr, flow: FlowMeter) {
+        match target {
+            RelayAddr::First => self.first_relay.flow = Some(flow),
+            RelayAddr::Second => self.second_relay.flow = Some(flow),
+            RelayAddr::Both => {}
+        }
+    }
+
+    pub fn set_feedback(&mut self, target: RelayAddr, feedback: Feedback<'drv>) {
+        match target {
+            RelayAddr::First => self.first_relay.feedback = Some(feedback),
+            RelayAddr::Second => self.second_relay.feedback = Some(feedback),
+            RelayAddr::Both => {}
+        }
+    }
+
+    /// the last stop of a single relay failed and waits for a retry
+    pub fn stop_failed(&self, target: RelayAddr) -> bool {
+        match target {
+            RelayAddr::First => self.first_relay.stop_failed,
+            RelayAddr::Second => self.second_relay.stop_failed,
+            RelayAddr::Both => false,
+        }
+    }
+
+    /// origin of the latest order on a single relay
+    pub fn last_order_by(&self, target: RelayAddr) -> Option<Origin> {
+        match target {
+            RelayAddr::First => self.first_relay.last_order_by,
+            RelayAddr::Second => self.second_relay.last_order_by,
+            RelayAddr::Both => None,
+        }
+    }
+
+    /// The clock moved ahead by `secs`, e.g. at the first NTP sync, so
+    /// running orders keep their remaining time.
+    pub fn shift_clock(&mut self, secs: u64) {
+        self.first_relay.shift_clock(secs);
+        self.second_relay.shift_clock(secs);
+    }
+
+    pub fn load_stats(&mut self, store: &StatsStore) -> anyhow::Result<()> {
+        self.first_relay.stats = store.load(self.first_relay.name)?;
+        self.second_relay.stats = store.load(self.second_relay.name)?;
+        Ok(())
+    }
+
+    /// persist counters changed since the last save
+    pub fn save_stats(&mut self, store: &mut StatsStore) -> anyhow::Result<()> {
+        if self.first_relay.stats_dirty {
+            store.save(self.first_relay.name, &self.first_relay.stats)?;
+            self.first_relay.stats_dirty = false;
+        }
+
+        if self.second_relay.stats_dirty {
+            store.save(self.second_relay.name, &self.second_relay.stats)?;
+            self.second_relay.stats_dirty = false;
+        }
+        Ok(())
+    }
+
+    /// relay `name` has a running order
+    pub fn is_running(&self, name: &str) -> Option<bool> {
+        match self.resolve_addr(name)? {
+            RelayAddr::First => Some(self.first_relay.running.is_some()),
+            RelayAddr::Second => Some(self.second_relay.running.is_some()),
+            RelayAddr::Both => None,
+        }
+    }
+
+    /// lifetime run seconds of relay `name` including the ongoing run
+    pub fn lifetime_run(&self, name: &str) -> Option<u64> {
+        let now = sys_now();
+        match self.resolve_addr(name)? {
+            RelayAddr::First => Some(self.first_relay.stats_at(now).lifetime_run()),
+            RelayAddr::Second => Some(self.second_relay.stats_at(now).lifetime_run()),
+            RelayAddr::Both => None,
+        }
+    }
+
+    pub fn stats_report(&self) -> [StatsReport; 2] {
+        let now = sys_now();
+        [
+            StatsReport {
+                name: self.first_relay.name,
+                stats: self.first_relay.stats_at(now),
+                now,
+            },
+            StatsReport {
+                name: self.second_relay.name,
+                stats: self.second_relay.stats_at(now),
+                now,
+            },
+        ]
+    }
+
+    /// state of every relay for the MQTT and HTTP APIs
+    pub fn states(&self) -> [RelayState; 2] {
+        [
+            RelayState::from(&self.first_relay.get_status()),
+            RelayState::from(&self.second_relay.get_status()),
+        ]
+    }
+
+    /// states of the relays changed since the previous call, e.g. by
+    /// `set` or a deadline, for publishing them as they happen
+    pub fn take_changes(&mut self) -> Vec<RelayState> {
+        let mut changes = Vec::new();
+        if std::mem::take(&mut self.first_relay.state_changed) {
+            changes.push(RelayState::from(&self.first_relay.get_status()));
+        }
+        if std::mem::take(&mut self.second_relay.state_changed) {
+            changes.push(RelayState::from(&self.second_relay.get_status()));
+        }
+        changes
+    }
+
     pub fn get_status(&self, target: RelayAddr) -> DoubleRelayStatus {
         let single = match target {
             RelayAddr::First => self.first_relay.get_status(),
@@ -238,12 +978,15 @@ where
                     None => t + 3600,
                     Some(dur) => t + dur as u64,
                 };
-                SetState::Run(RunOrder::new(t, end, query.chat_id))
+                let mut ord = RunOrder::new(t, end, query.origin);
+                ord.cycle = query.cycle;
+                ord.volume_limit = query.volume;
+                SetState::Run(ord)
             }
             false => SetState::Stop,
         };
 
-        self.set(r_addr, instruction)?;
+        self.set(r_addr, instruction, query.force)?;
         Ok(self.get_status(r_addr))
     }
 }
@@ -265,35 +1008,145 @@ impl<'r> Display for DoubleRelayStatus<'r> {
 pub struct RelayStatus<'r> {
     pub name: &'r str,
     pub run_info: Option<&'r RunOrder>,
+    pub energized: bool,
+    /// end of the rest period after the last stop
+    pub next_start: Option<Time>,
+    pub fault: Option<Fault>,
+    pub interlock: Option<StopCause>,
+    /// litres per minute at the last poll
+    pub flow_lpm: Option<f32>,
+    /// litres of the running order
+    pub volume: Option<f32>,
+}
+
+/// serialized relay state
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct RelayState {
+    pub name: String,
+    pub on: bool,
+    pub energized: bool,
+    pub start_at: Option<u64>,
+    pub end_at: Option<u64>,
+    pub fault: Option<String>,
+    pub blocked: Option<&'static str>,
+}
+
+impl From<&RelayStatus<'_>> for RelayState {
+    fn from(s: &RelayStatus<'_>) -> Self {
+        Self {
+            name: s.name.to_owned(),
+            on: s.run_info.is_some(),
+            energized: s.energized,
+            start_at: s.run_info.map(|o| o.start_at.as_secs()),
+            end_at: s.run_info.map(|o| o.end_at.as_secs()),
+            fault: s.fault.map(|f| f.to_string()),
+            blocked: s.interlock.map(|c| c.name()),
+        }
+    }
 }
 
 impl<'r> Display for RelayStatus<'r> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Relay {} status ", self.name)?;
-        match self.run_info {
-            None => write!(f, "off."),
-            Some(ord) => write!(f, "on.\nStart: {}\nFinish: {}", ord.start_at, ord.end_at),
+        self.fmt_state(f)?;
+        if let Some(fault) = self.fault {
+            write!(f, "\nFaulted: {}", fault)?;
+        }
+        if let Some(cause) = self.interlock {
+            write!(f, "\nBlocked: {}", cause)?;
+        }
+        if let Some(lpm) = self.flow_lpm {
+            write!(f, "\nFlow: {:.1} l/min", lpm)?;
+        }
+        if let Some(volume) = self.volume {
+            write!(f, "\nVolume: {:.1} l", volume)?;
+            if let Some(limit) = self.run_info.and_then(|ord| ord.volume_limit) {
+                write!(f, " of {:.0} l", limit)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'r> RelayStatus<'r> {
+    fn fmt_state(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ord = match self.run_info {
+            Some(ord) => ord,
+            None => {
+                write!(f, "off.")?;
+                if let Some(next) = self.next_start.as_ref().filter(|t| **t > Time::now()) {
+                    write!(f, "\nNext start allowed at {}", next)?;
+                }
+                return Ok(());
+            }
+        };
+
+        let now = Time::now();
+        match (self.energized, ord.start_at <= now) {
+            (true, _) => write!(f, "on.")?,
+            (false, true) => write!(f, "resting.")?,
+            (false, false) => write!(f, "waiting.")?,
         }
+        write!(f, "\nStart: {}\nFinish: {}", ord.start_at, ord.end_at)?;
+        if ord.truncated {
+            write!(f, "\nFinish shortened to the daily runtime budget")?;
+        }
+        match ord.order_by {
+            Origin::Local => write!(f, "\nStarted locally by button")?,
+            Origin::Remote => write!(f, "\nStarted remotely")?,
+            Origin::Automation => write!(f, "\nStarted automatically")?,
+            Origin::Chat(_) => {}
+        }
+
+        if let Some(c) = ord.cycle {
+            write!(
+                f,
+                "\nCycle: {}s on / {}s off\nNext transition: {}",
+                c.on,
+                c.off,
+                ord.next_transition(now.as_secs())
+            )?;
+        }
+
+        let delay = ord.delay();
+        if delay > 0 {
+            write!(
+                f,
+                "\nStart delayed {}s after the previous relay to limit inrush current",
+                delay
+            )?;
+        }
+        Ok(())
     }
 }
-#[derive(Default)]
 pub struct RelayQuery<'a> {
     /// reference for who sent the query
-    pub chat_id: u32,
+    pub origin: Origin,
     /// relay name
     pub name: Option<&'a str>,
     /// set On when is true
     pub instruction: Option<bool>,
     /// time second
     pub duration: Option<u32>,
+    /// bypass minimum on/off time, admin only
+    pub force: bool,
+    /// run in duty cycle instead of continuously
+    pub cycle: Option<Cycle>,
+    /// litres until stop
+    pub volume: Option<f32>,
 }
 
 impl<'a> RelayQuery<'a> {
     #[must_use]
-    pub fn new(chat_id: u32) -> Self {
+    pub fn new(origin: Origin) -> Self {
         Self {
-            chat_id,
-            ..Default::default()
+            origin,
+            name: None,
+            instruction: None,
+            duration: None,
+            force: false,
+            cycle: None,
+            volume: None,
         }
     }
 }
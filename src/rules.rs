@@ -0,0 +1,294 @@
+use anyhow::Error;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use serde::Deserialize;
+use std::fmt::Write;
+
+use crate::relay::{Origin, RelayQuery};
+use crate::rule::{check_name, Rule, RuleInputs};
+use crate::util::{clock_set, Time};
+
+#[derive(Deserialize, Debug)]
+pub struct RuleConfig {
+    /// rule name, also the NVS key of its enable toggle
+    pub name: String,
+    /// rule text, see `Rule`
+    when: String,
+    #[serde(default = "RuleConfig::default_enabled")]
+    enabled: bool,
+}
+
+impl RuleConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        check_name(&self.name)?;
+        self.when
+            .parse::<Rule>()
+            .map(|_| ())
+            .map_err(|err| Error::msg(format!("{}: {}", self.name, err)))
+    }
+
+    fn default_enabled() -> bool {
+        true
+    }
+}
+
+struct RuleEntry {
+    name: String,
+    rule: Rule,
+    enabled: bool,
+    /// added over the bot and kept in NVS
+    stored: bool,
+    last_fire: Option<u64>,
+    /// not fired before this time, set when its order was rejected
+    retry_at: u64,
+}
+
+/// A rule that fired with the order it produced.
+pub struct Fired<'r> {
+    pub name: &'r str,
+    pub query: RelayQuery<'r>,
+}
+
+/// Rules from config and rules added over the bot, evaluated on every
+/// tick. NVS keeps the added rules, their names under `index`, and the
+/// enable toggle of every rule under its name followed by "!".
+pub struct RuleEngine {
+    rules: Vec<RuleEntry>,
+    storage: EspNvs<NvsDefault>,
+}
+
+impl RuleEngine {
+    const INDEX_KEY: &'static str = "index";
+    /// added rules and their text, keeps the NVS strings short
+    const MAX_STORED: usize = 16;
+    const MAX_TEXT: usize = 200;
+    /// seconds before a rule whose order was rejected fires again
+    const RETRY_AFTER: u64 = 600;
+
+    pub fn new(partition: EspDefaultNvsPartition, config: &[RuleConfig]) -> anyhow::Result<Self> {
+        let storage = EspNvs::new(partition, "rules", true)?;
+        let mut engine = Self {
+            rules: Vec::new(),
+            storage,
+        };
+
+        for cfg in config {
+            match cfg.when.parse::<Rule>() {
+                Ok(rule) => engine.push(&cfg.name, rule, cfg.enabled, false),
+                Err(err) => warn!("rule {} ignored: {}", cfg.name, err),
+            }
+        }
+
+        // a broken entry must not keep the device from booting
+        let index = engine.read_str(Self::INDEX_KEY).unwrap_or_else(|err| {
+            warn!("stored rules ignored: {}", err);
+            None
+        });
+        for name in index
+            .unwrap_or_default()
+            .split(',')
+            .filter(|n| !n.is_empty())
+        {
+            let text = match engine.read_str(name) {
+                Ok(Some(text)) => text,
+                Ok(None) => {
+                    warn!("stored rule {} missing", name);
+                    continue;
+                }
+                Err(err) => {
+                    warn!("stored rule {} unreadable: {}", name, err);
+                    continue;
+                }
+            };
+            if engine.rules.iter().any(|r| r.name == name) {
+                warn!(
+                    "stored rule {} ignored, the config has one of that name",
+                    name
+                );
+                continue;
+            }
+            match text.parse::<Rule>() {
+                Ok(rule) => engine.push(name, rule, true, true),
+                Err(err) => warn!("stored rule {} ignored: {}", name, err),
+            }
+        }
+
+        info!("{} rules loaded", engine.rules.len());
+        Ok(engine)
+    }
+
+    /// string of `key` in a buffer of its stored length
+    fn read_str(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let Some(len) = self.storage.str_len(key)? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0u8; len];
+        Ok(self.storage.get_str(key, &mut buf)?.map(str::to_owned))
+    }
+
+    fn toggle_key(name: &str) -> String {
+        format!("{}!", name)
+    }
+
+    fn push(&mut self, name: &str, rule: Rule, enabled: bool, stored: bool) {
+        let toggle = self.storage.get_u8(&Self::toggle_key(name)).ok().flatten();
+        self.rules.push(RuleEntry {
+            name: name.to_owned(),
+            rule,
+            enabled: toggle.map_or(enabled, |t| t == 1),
+            stored,
+            last_fire: None,
+            retry_at: 0,
+        });
+    }
+
+    fn save_index(&mut self) -> anyhow::Result<()> {
+        let index = self
+            .rules
+            .iter()
+            .filter(|r| r.stored)
+            .map(|r| r.name.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+        self.storage.set_str(Self::INDEX_KEY, &index)?;
+        Ok(())
+    }
+
+    /// Fire every enabled rule whose conditions hold and whose cooldown
+    /// passed. A rule is skipped while its relay is already as ordered,
+    /// and time windows wait for the clock to be set.
+    pub fn evaluate(&mut self, inputs: &dyn RuleInputs, now: u64) -> Vec<Fired<'_>> {
+        let time_of_day = clock_set().then(|| Time::new(now).time_of_day());
+        let mut fired = Vec::new();
+        for (idx, entry) in self.rules.iter_mut().enumerate() {
+            if !entry.enabled
+                || !entry.rule.cooled(entry.last_fire, now)
+                || now < entry.retry_at
+                || inputs.relay_running(&entry.rule.relay) == Some(entry.rule.on)
+                || !entry.rule.matches(inputs, time_of_day)
+            {
+                continue;
+            }
+
+            info!("rule {} fired", entry.name);
+            entry.last_fire = Some(now);
+            fired.push(idx);
+        }
+
+        fired
+            .into_iter()
+            .map(|idx| {
+                let entry = &self.rules[idx];
+                let mut query = RelayQuery::new(Origin::Automation);
+                query.name = Some(&entry.rule.relay);
+                query.instruction = Some(entry.rule.on);
+                query.duration = entry.rule.duration;
+                Fired {
+                    name: &entry.name,
+                    query,
+                }
+            })
+            .collect()
+    }
+
+    /// add a rule over the bot, `relays` are the known relay names
+    pub fn add(&mut self, name: &str, text: &str, relays: &[&str]) -> anyhow::Result<String> {
+        check_name(name)?;
+        if self.rules.iter().any(|r| r.name == name) {
+            return Err(Error::msg("rule name already used"));
+        }
+        if self.rules.iter().filter(|r| r.stored).count() >= Self::MAX_STORED {
+            return Err(Error::msg(format!(
+                "at most {} rules can be added",
+                Self::MAX_STORED
+            )));
+        }
+
+        let rule = text.parse::<Rule>()?;
+        if rule.relay != "both" && !relays.contains(&rule.relay.as_str()) {
+            return Err(Error::msg(format!("unknown relay {}", rule.relay)));
+        }
+
+        let text = rule.to_string();
+        if text.len() > Self::MAX_TEXT {
+            return Err(Error::msg(format!(
+                "rule longer than {} characters",
+                Self::MAX_TEXT
+            )));
+        }
+        self.storage.set_str(name, &text)?;
+        self.storage.remove(&Self::toggle_key(name))?;
+        self.push(name, rule, true, true);
+        self.save_index()?;
+        Ok(format!("Rule {} added: {}", name, text))
+    }
+
+    /// The order of a fired rule was rejected, e.g. by the minimum off
+    /// time or the daily budget, hold the rule back instead of retrying
+    /// and reporting it on every tick.
+    pub fn rejected(&mut self, name: &str, now: u64) {
+        if let Some(entry) = self.rules.iter_mut().find(|r| r.name == name) {
+            entry.retry_at = now + Self::RETRY_AFTER;
+        }
+    }
+
+    /// remove a rule added over the bot
+    pub fn remove(&mut self, name: &str) -> anyhow::Result<String> {
+        let idx = self
+            .rules
+            .iter()
+            .position(|r| r.name == name)
+            .ok_or(Error::msg("unknown rule"))?;
+        if !self.rules[idx].stored {
+            return Err(Error::msg("rule is from config, disable it instead"));
+        }
+
+        self.rules.remove(idx);
+        self.save_index()?;
+        self.storage.remove(name)?;
+        self.storage.remove(&Self::toggle_key(name))?;
+        Ok(format!("Rule {} removed", name))
+    }
+
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> anyhow::Result<String> {
+        let entry = self
+            .rules
+            .iter_mut()
+            .find(|r| r.name == name)
+            .ok_or(Error::msg("unknown rule"))?;
+        self.storage
+            .set_u8(&Self::toggle_key(name), enabled as u8)?;
+        entry.enabled = enabled;
+        Ok(format!(
+            "Rule {} {}",
+            name,
+            if enabled { "enabled" } else { "disabled" }
+        ))
+    }
+
+    pub fn report(&self) -> String {
+        if self.rules.is_empty() {
+            return String::from("No rule configured");
+        }
+
+        let mut out = String::new();
+        for r in &self.rules {
+            let _ = writeln!(
+                out,
+                "{} [{}]: {}",
+                r.name,
+                if r.enabled { "on" } else { "off" },
+                r.rule
+            );
+            match r.last_fire {
+                Some(t) => {
+                    let _ = writeln!(out, "  last fired {}", Time::new(t));
+                }
+                None => {
+                    let _ = writeln!(out, "  not fired since boot");
+                }
+            }
+        }
+        out.trim_end().to_owned()
+    }
+}
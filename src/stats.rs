@@ -0,0 +1,199 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use std::fmt::Display;
+
+use crate::util::Time;
+
+const DAYS: usize = 7;
+
+/// Run time and start counters of a single relay.
+/// Daily counters live in a ring indexed by local day, so the last
+/// seven days are kept without any calendar bookkeeping.
+#[derive(Clone, Debug, Default)]
+pub struct RunStats {
+    /// local day index of the newest slot
+    day: u64,
+    /// run seconds per day
+    run: [u32; DAYS],
+    /// start count per day
+    starts: [u16; DAYS],
+    lifetime_run: u64,
+    lifetime_starts: u32,
+}
+
+impl RunStats {
+    const VERSION: u8 = 1;
+    const BYTES_LEN: usize = 1 + 8 + DAYS * 4 + DAYS * 2 + 8 + 4;
+
+    /// clear the slots of the days passed since the newest slot
+    fn roll(&mut self, day: u64) {
+        if day <= self.day {
+            return;
+        }
+
+        let passed = (day - self.day).min(DAYS as u64);
+        for d in (day + 1 - passed)..=day {
+            let slot = (d % DAYS as u64) as usize;
+            self.run[slot] = 0;
+            self.starts[slot] = 0;
+        }
+        self.day = day;
+    }
+
+    /// slot of `day`, days older than the newest slot count to the newest
+    fn slot(&mut self, day: u64) -> usize {
+        self.roll(day);
+        (self.day % DAYS as u64) as usize
+    }
+
+    pub fn add_start(&mut self, now: u64) {
+        let slot = self.slot(Time::new(now).local_day());
+        self.starts[slot] = self.starts[slot].saturating_add(1);
+        self.lifetime_starts = self.lifetime_starts.saturating_add(1);
+    }
+
+    /// account the run between `from` and `to`, split at midnight
+    pub fn add_run(&mut self, from: u64, to: u64) {
+        let mut from = from;
+        while from < to {
+            let day = Time::new(from).local_day();
+            let seg_end = to.min(Time::day_start(day + 1));
+            let slot = self.slot(day);
+
+            let secs = (seg_end - from) as u32;
+            self.run[slot] = self.run[slot].saturating_add(secs);
+            self.lifetime_run += secs as u64;
+            from = seg_end;
+        }
+    }
+
+    #[inline]
+    pub fn lifetime_run(&self) -> u64 {
+        self.lifetime_run
+    }
+
+    /// run seconds of the day of `now`
+    pub fn today(&self, now: u64) -> u32 {
+        let day = Time::new(now).local_day();
+        if day != self.day {
+            return 0;
+        }
+        self.run[(day % DAYS as u64) as usize]
+    }
+
+    fn sum_week(&self, now: u64) -> (u32, u32) {
+        let day = Time::new(now).local_day();
+        let mut run = 0;
+        let mut starts = 0;
+        for d in day.saturating_sub(DAYS as u64 - 1)..=day {
+            if d > self.day || d + (DAYS as u64) <= self.day {
+                continue;
+            }
+            let slot = (d % DAYS as u64) as usize;
+            run += self.run[slot];
+            starts += self.starts[slot] as u32;
+        }
+        (run, starts)
+    }
+
+    fn starts_today(&self, now: u64) -> u16 {
+        let day = Time::new(now).local_day();
+        if day != self.day {
+            return 0;
+        }
+        self.starts[(day % DAYS as u64) as usize]
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::BYTES_LEN);
+        bytes.push(Self::VERSION);
+        bytes.extend_from_slice(&self.day.to_be_bytes());
+        self.run
+            .iter()
+            .for_each(|v| bytes.extend_from_slice(&v.to_be_bytes()));
+        self.starts
+            .iter()
+            .for_each(|v| bytes.extend_from_slice(&v.to_be_bytes()));
+        bytes.extend_from_slice(&self.lifetime_run.to_be_bytes());
+        bytes.extend_from_slice(&self.lifetime_starts.to_be_bytes());
+        bytes
+    }
+
+    /// None when the layout is unknown
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() != Self::BYTES_LEN || buf[0] != Self::VERSION {
+            return None;
+        }
+
+        let mut stats = Self::default();
+        let mut cursor = &buf[1..];
+        let mut take = |n: usize| {
+            let (head, tail) = cursor.split_at(n);
+            cursor = tail;
+            head
+        };
+
+        stats.day = u64::from_be_bytes(take(8).try_into().ok()?);
+        for v in stats.run.iter_mut() {
+            *v = u32::from_be_bytes(take(4).try_into().ok()?);
+        }
+        for v in stats.starts.iter_mut() {
+            *v = u16::from_be_bytes(take(2).try_into().ok()?);
+        }
+        stats.lifetime_run = u64::from_be_bytes(take(8).try_into().ok()?);
+        stats.lifetime_starts = u32::from_be_bytes(take(4).try_into().ok()?);
+        Some(stats)
+    }
+}
+
+/// Human readable run time summary of a relay at `now`
+pub struct StatsReport<'r> {
+    pub name: &'r str,
+    pub stats: RunStats,
+    pub now: u64,
+}
+
+impl<'r> Display for StatsReport<'r> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hours = |secs: u64| secs as f32 / 3600.0;
+        let (week_run, week_starts) = self.stats.sum_week(self.now);
+
+        write!(
+            f,
+            "Relay {}\nToday: {:.1}h, {} starts\n7 days: {:.1}h, {} starts\nLifetime: {:.1}h, {} starts",
+            self.name,
+            hours(self.stats.today(self.now) as u64),
+            self.stats.starts_today(self.now),
+            hours(week_run as u64),
+            week_starts,
+            hours(self.stats.lifetime_run),
+            self.stats.lifetime_starts
+        )
+    }
+}
+
+/// Run time counters persisted in NVS, one blob per relay name
+pub struct StatsStore {
+    storage: EspNvs<NvsDefault>,
+}
+
+impl StatsStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        let storage = EspNvs::new(partition, "stats", true)?;
+        Ok(Self { storage })
+    }
+
+    pub fn load(&self, name: &str) -> anyhow::Result<RunStats> {
+        let mut buf = [0u8; RunStats::BYTES_LEN];
+        let stats = self
+            .storage
+            .get_blob(name, &mut buf)?
+            .and_then(RunStats::from_bytes)
+            .unwrap_or_default();
+        Ok(stats)
+    }
+
+    pub fn save(&mut self, name: &str, stats: &RunStats) -> anyhow::Result<()> {
+        self.storage.set_blob(name, &stats.to_bytes())?;
+        Ok(())
+    }
+}
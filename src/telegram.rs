@@ -24,6 +24,10 @@ impl<'cfg, 'cl> TeleAPI<'cfg> {
         }
     }
 
+    pub fn is_admin(&self, chat_id: u32) -> bool {
+        self.config.admins.contains(&chat_id)
+    }
+
     pub fn create_client(&'cl mut self, conn: EspHttpConnection) -> TeleClient<'cl, 'cfg> {
         TeleClient {
             client: Client::wrap(conn),
@@ -68,6 +72,22 @@ impl<'tp, 'cfg> TeleClient<'tp, 'cfg> {
         Ok(updates)
     }
 
+    /// Confirm the fetched updates to Telegram, so they are not delivered
+    /// again after a restart.
+    pub fn ack_updates(&mut self) -> anyhow::Result<()> {
+        let url = format!(
+            "{}/bot{}/getUpdates?limit=1&timeout=0&offset={}",
+            self.tele.config.api_base,
+            self.tele.config.bot_token,
+            self.tele.last_updtid + 1
+        );
+        let response = self.client.get(&url)?.submit()?;
+        match response.status() {
+            200..=299 => Ok(()),
+            status => Err(Error::msg(format!("code {}", status))),
+        }
+    }
+
     pub fn send_message(&mut self, msg: SendMessage) -> anyhow::Result<()> {
         let headers = [("Content-Type", "application/json")];
         let url = format!(
@@ -93,6 +113,99 @@ impl<'tp, 'cfg> TeleClient<'tp, 'cfg> {
 
         Ok(())
     }
+
+    /// Upload `file` as multipart/form-data, the body is streamed from
+    /// the buffer in chunks.
+    pub fn send_file(&mut self, file: &SendFile) -> anyhow::Result<()> {
+        if file.data.len() > SendFile::MAX_SIZE {
+            return Err(Error::msg(format!(
+                "{} is {} bytes, limit is {}",
+                file.filename,
+                file.data.len(),
+                SendFile::MAX_SIZE
+            )));
+        }
+
+        let (method, field) = match file.kind {
+            FileKind::Document => ("sendDocument", "document"),
+            FileKind::Photo => ("sendPhoto", "photo"),
+        };
+        let url = format!(
+            "{}/bot{}/{}",
+            self.tele.config.api_base, self.tele.config.bot_token, method
+        );
+
+        let head = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"chat_id\"\r\n\r\n{}\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"caption\"\r\n\r\n{}\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\
+             Content-Type: {}\r\n\r\n",
+            file.chat_id,
+            file.caption,
+            field,
+            file.filename,
+            file.content_type,
+            b = MULTIPART_BOUNDARY
+        );
+        let tail = format!("\r\n--{}--\r\n", MULTIPART_BOUNDARY);
+
+        let content_type = format!("multipart/form-data; boundary={}", MULTIPART_BOUNDARY);
+        let content_length = (head.len() + file.data.len() + tail.len()).to_string();
+        let headers = [
+            ("Content-Type", content_type.as_str()),
+            ("Content-Length", content_length.as_str()),
+        ];
+        let mut request = self.client.post(url.as_ref(), &headers)?;
+
+        let parts = [head.as_bytes(), &file.data, tail.as_bytes()];
+        for chunk in parts.iter().flat_map(|p| p.chunks(UPLOAD_CHUNK)) {
+            let mut written = 0;
+            while written < chunk.len() {
+                written += request.write(&chunk[written..])?;
+            }
+        }
+        request.flush()?;
+
+        let response = request.submit()?;
+        let status = response.status();
+        info!("{} {}: {}", method, file.filename, status);
+
+        if !matches!(status, 200..299) {
+            return Err(Error::msg(format!(
+                "code {}: {:?}",
+                response.status(),
+                response.status_message()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+const MULTIPART_BOUNDARY: &str = "pomel-7MA4YWxkTrZu0gW";
+const UPLOAD_CHUNK: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub enum FileKind {
+    Document,
+    /// shown inline, PNG or JPEG
+    Photo,
+}
+
+/// File uploaded straight from memory, never queued in flash.
+#[derive(Debug)]
+pub struct SendFile {
+    pub chat_id: u32,
+    pub kind: FileKind,
+    pub filename: String,
+    pub content_type: &'static str,
+    pub caption: String,
+    pub data: Vec<u8>,
+}
+
+impl SendFile {
+    /// the whole file sits in RAM next to the TLS buffers
+    pub const MAX_SIZE: usize = 64 * 1024;
 }
 
 #[derive(Serialize, Debug)]
@@ -112,7 +225,7 @@ impl SendMessage {
     }
 
     pub fn from_bytes(buf: &[u8]) -> Self {
-        assert!(buf.len() > 5);
+        assert!(buf.len() >= 4);
         let s = unsafe { str::from_utf8_unchecked(&buf[4..]) };
         Self {
             chat_id: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
@@ -0,0 +1,307 @@
+use esp_idf_svc::hal::delay::Ets;
+use esp_idf_svc::hal::gpio::{AnyIOPin, InputOutput, PinDriver, Pull};
+use esp_idf_svc::hal::interrupt;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::fmt::{Display, Write};
+
+use crate::band::{classify, Band};
+use crate::ds18b20::{Ds18b20, OneWireBus};
+use crate::history::HistoryLog;
+use crate::util::io_pin;
+
+/// 1-Wire bit-banged on an open drain GPIO with the internal pull-up,
+/// an external 4.7k pull-up is still recommended for long cables.
+pub struct GpioOneWire {
+    pin: PinDriver<'static, AnyIOPin, InputOutput>,
+}
+
+impl GpioOneWire {
+    pub fn new(num: u8) -> anyhow::Result<Self> {
+        let mut pin = PinDriver::input_output_od(io_pin(num)?)?;
+        pin.set_pull(Pull::Up)?;
+        pin.set_high()?;
+        Ok(Self { pin })
+    }
+
+    fn write_bit(&mut self, bit: bool) -> anyhow::Result<()> {
+        let (low_us, release_us) = if bit { (6, 64) } else { (60, 10) };
+        interrupt::free(|| {
+            self.pin.set_low()?;
+            Ets::delay_us(low_us);
+            self.pin.set_high()?;
+            Ets::delay_us(release_us);
+            Ok(())
+        })
+    }
+
+    fn read_bit(&mut self) -> anyhow::Result<bool> {
+        let bit = interrupt::free(|| -> anyhow::Result<bool> {
+            self.pin.set_low()?;
+            Ets::delay_us(6);
+            self.pin.set_high()?;
+            Ets::delay_us(9);
+            Ok(self.pin.is_high())
+        })?;
+        Ets::delay_us(55);
+        Ok(bit)
+    }
+}
+
+impl OneWireBus for GpioOneWire {
+    fn reset(&mut self) -> anyhow::Result<bool> {
+        self.pin.set_low()?;
+        Ets::delay_us(480);
+        let present = interrupt::free(|| -> anyhow::Result<bool> {
+            self.pin.set_high()?;
+            Ets::delay_us(70);
+            Ok(self.pin.is_low())
+        })?;
+        Ets::delay_us(410);
+        Ok(present)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> anyhow::Result<()> {
+        (0..8).try_for_each(|i| self.write_bit(byte >> i & 1 == 1))
+    }
+
+    fn read_byte(&mut self) -> anyhow::Result<u8> {
+        let mut byte = 0;
+        for i in 0..8 {
+            if self.read_bit()? {
+                byte |= 1 << i;
+            }
+        }
+        Ok(byte)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TemperatureConfig {
+    /// also the history key
+    pub name: String,
+    pub pin: u8,
+    /// alert below, celsius
+    #[serde(default)]
+    low: Option<f32>,
+    /// alert above, celsius
+    #[serde(default)]
+    high: Option<f32>,
+    #[serde(default = "TemperatureConfig::default_hysteresis")]
+    hysteresis: f32,
+    /// relay started when the temperature goes above `high`
+    #[serde(default)]
+    on_high: Option<HookConfig>,
+}
+
+impl TemperatureConfig {
+    fn default_hysteresis() -> f32 {
+        0.5
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        crate::config::check_name(&self.name, HistoryLog::MAX_NAME)?;
+        crate::config::check_band(self.low, self.high, self.hysteresis)?;
+        match &self.on_high {
+            Some(hook) => crate::config::known_relay(&hook.relay),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HookConfig {
+    pub relay: String,
+    /// run seconds of the started relay
+    #[serde(default = "HookConfig::default_duration")]
+    pub duration: u32,
+}
+
+impl HookConfig {
+    fn default_duration() -> u32 {
+        1800
+    }
+}
+
+#[derive(Debug)]
+pub enum TempAlert {
+    Band(Band, f32),
+    /// several reads in a row failed
+    Lost,
+}
+
+#[derive(Debug)]
+pub struct TempEvent<'cfg> {
+    config: &'cfg TemperatureConfig,
+    pub alert: TempAlert,
+}
+
+impl<'cfg> TempEvent<'cfg> {
+    /// relay to start for this event
+    pub fn hook(&self) -> Option<&'cfg HookConfig> {
+        match self.alert {
+            TempAlert::Band(Band::High, _) => self.config.on_high.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+impl<'cfg> Display for TempEvent<'cfg> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = &self.config.name;
+        match self.alert {
+            TempAlert::Band(Band::High, v) => write!(f, "Temperature {} high: {:.1} °C", name, v),
+            TempAlert::Band(Band::Low, v) => write!(f, "Temperature {} low: {:.1} °C", name, v),
+            TempAlert::Band(Band::Normal, v) => {
+                write!(f, "Temperature {} back to normal: {:.1} °C", name, v)
+            }
+            TempAlert::Lost => write!(f, "Temperature sensor {} is not responding", name),
+        }
+    }
+}
+
+struct Probe<'cfg> {
+    config: &'cfg TemperatureConfig,
+    sensor: Ds18b20<GpioOneWire>,
+    /// a conversion was started on the previous tick
+    pending: bool,
+    last_sample: u64,
+    band: Band,
+    failures: u32,
+    /// (time, celsius), oldest first
+    history: VecDeque<(u64, f32)>,
+}
+
+impl<'cfg> Probe<'cfg> {
+    fn latest(&self) -> Option<f32> {
+        self.history.back().map(|(_, v)| *v)
+    }
+
+    /// count a failed access, alerting once when the probe is lost
+    fn fail(&mut self) -> Option<TempAlert> {
+        self.failures += 1;
+        (self.failures == TemperatureSensors::LOST_AFTER).then_some(TempAlert::Lost)
+    }
+
+    fn read(&mut self, now: u64) -> Option<TempAlert> {
+        let value = match self.sensor.read() {
+            Ok(value) => value,
+            Err(err) => {
+                warn!("temperature {} read error: {}", self.config.name, err);
+                return self.fail();
+            }
+        };
+
+        self.failures = 0;
+        if self.history.len() == TemperatureSensors::HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back((now, value));
+
+        let c = self.config;
+        let band = classify(value, self.band, c.low, c.high, c.hysteresis);
+        if band == self.band {
+            return None;
+        }
+        info!("temperature {} {:?} -> {:?}", c.name, self.band, band);
+        self.band = band;
+        Some(TempAlert::Band(band, value))
+    }
+}
+
+/// DS18B20 probes sampled every `INTERVAL` seconds. The conversion runs
+/// between two ticks of the main loop so sampling never blocks.
+pub struct TemperatureSensors<'cfg> {
+    probes: Vec<Probe<'cfg>>,
+}
+
+impl<'cfg> TemperatureSensors<'cfg> {
+    const INTERVAL: u64 = 60;
+    /// one hour of samples
+    const HISTORY_LEN: usize = 60;
+    const LOST_AFTER: u32 = 3;
+
+    pub fn new(config: &'cfg [TemperatureConfig]) -> anyhow::Result<Self> {
+        let mut probes = Vec::with_capacity(config.len());
+        for temp in config {
+            probes.push(Probe {
+                config: temp,
+                sensor: Ds18b20::new(GpioOneWire::new(temp.pin)?),
+                pending: false,
+                last_sample: 0,
+                band: Band::Normal,
+                failures: 0,
+                history: VecDeque::with_capacity(Self::HISTORY_LEN),
+            });
+            info!("temperature {} on gpio{}", temp.name, temp.pin);
+        }
+        Ok(Self { probes })
+    }
+
+    /// read finished conversions and start the due ones
+    pub fn tick(&mut self, now: u64) -> Vec<TempEvent<'cfg>> {
+        let mut events = Vec::new();
+        for probe in self.probes.iter_mut() {
+            let mut alert = None;
+            if probe.pending {
+                probe.pending = false;
+                alert = probe.read(now);
+            }
+
+            if now >= probe.last_sample + Self::INTERVAL {
+                probe.last_sample = now;
+                match probe.sensor.start_conversion() {
+                    Ok(()) => probe.pending = true,
+                    Err(err) => {
+                        warn!("temperature {} convert error: {}", probe.config.name, err);
+                        alert = alert.or(probe.fail());
+                    }
+                }
+            }
+
+            if let Some(alert) = alert {
+                events.push(TempEvent {
+                    config: probe.config,
+                    alert,
+                });
+            }
+        }
+        events
+    }
+
+    pub fn names(&self) -> Vec<&'cfg str> {
+        self.probes.iter().map(|p| p.config.name.as_str()).collect()
+    }
+
+    /// latest reading of a probe by name
+    pub fn value(&self, name: &str) -> Option<f32> {
+        self.probes
+            .iter()
+            .find(|p| p.config.name == name)
+            .and_then(Probe::latest)
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        for p in &self.probes {
+            let Some(latest) = p.latest() else {
+                let _ = writeln!(out, "{}: no reading", p.config.name);
+                continue;
+            };
+
+            let (min, max) = p
+                .history
+                .iter()
+                .fold((f32::MAX, f32::MIN), |(lo, hi), (_, v)| {
+                    (lo.min(*v), hi.max(*v))
+                });
+            let _ = writeln!(
+                out,
+                "{}: {:.1} °C ({:?}), last hour {:.1} - {:.1} °C",
+                p.config.name, latest, p.band, min, max
+            );
+        }
+        out.trim_end().to_owned()
+    }
+}
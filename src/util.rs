@@ -1,13 +1,11 @@
-use esp_idf_svc::hal::delay::FreeRtos;
+use anyhow::Error;
+use esp_idf_svc::hal::gpio::{AnyIOPin, AnyInputPin};
 use esp_idf_svc::sntp::{EspSntp, SyncStatus};
-use esp_idf_svc::sys::EspError;
-use esp_idf_svc::wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi};
 use log::info;
 use std::fmt::Display;
+use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::WifiConfig;
-
 const WIB_OFFSET: u64 = 25200;
 
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
@@ -22,6 +20,29 @@ impl Time {
         Self(now)
     }
 
+    #[inline]
+    pub fn as_secs(&self) -> u64 {
+        self.0
+    }
+
+    /// day index since epoch in WIB
+    #[inline]
+    pub fn local_day(&self) -> u64 {
+        (self.0 + WIB_OFFSET) / 86400
+    }
+
+    /// seconds since WIB midnight
+    #[inline]
+    pub fn time_of_day(&self) -> u32 {
+        ((self.0 + WIB_OFFSET) % 86400) as u32
+    }
+
+    /// epoch of WIB midnight starting `day`
+    #[inline]
+    pub fn day_start(day: u64) -> u64 {
+        (day * 86400).saturating_sub(WIB_OFFSET)
+    }
+
     fn is_leap_year(year: i64) -> bool {
         (year % 4 == 0 && year % 100 != 0) || (year % 400 == 0)
     }
@@ -98,50 +119,101 @@ pub fn sys_now() -> u64 {
         .as_secs()
 }
 
-pub fn sync_ntp() -> anyhow::Result<()> {
-    let sntp = EspSntp::new_default()?;
-    println!("Synchronizing with NTP Server");
-    while sntp.get_sync_status() != SyncStatus::Completed {
-        FreeRtos::delay_ms(10)
+/// seconds since boot
+pub fn uptime() -> u64 {
+    (unsafe { esp_idf_svc::sys::esp_timer_get_time() } / 1_000_000) as u64
+}
+
+pub fn free_heap() -> u32 {
+    unsafe { esp_idf_svc::sys::esp_get_free_heap_size() }
+}
+
+/// last three bytes of the station MAC in hex, tells devices apart by name
+pub fn device_id() -> anyhow::Result<String> {
+    use esp_idf_svc::sys::{esp, esp_mac_type_t_ESP_MAC_WIFI_STA, esp_read_mac};
+
+    let mut mac = [0u8; 6];
+    esp!(unsafe { esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_WIFI_STA) })?;
+    Ok(format!("{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5]))
+}
+
+/// the clock holds a real date, not the seconds since boot
+pub fn clock_set() -> bool {
+    // 2024-01-01
+    sys_now() >= 1_704_067_200
+}
+
+/// SNTP syncing in the background. Until then the clock counts from boot,
+/// or from the time kept over a restart.
+pub struct Ntp {
+    sntp: EspSntp<'static>,
+    /// clock minus uptime before the first sync
+    offset: u64,
+    synced: bool,
+}
+
+impl Ntp {
+    pub fn start() -> anyhow::Result<Self> {
+        Ok(Self {
+            sntp: EspSntp::new_default()?,
+            offset: sys_now().saturating_sub(uptime()),
+            synced: false,
+        })
+    }
+
+    /// Seconds the clock jumped ahead, once when the first sync completed.
+    pub fn poll(&mut self) -> Option<u64> {
+        if self.synced || self.sntp.get_sync_status() != SyncStatus::Completed {
+            return None;
+        }
+        self.synced = true;
+        let jump = sys_now()
+            .saturating_sub(uptime())
+            .saturating_sub(self.offset);
+        info!("time synchronized, clock moved {}s ahead", jump);
+        Some(jump)
     }
-    println!("Time Sync Completed");
-    Ok(())
 }
 
-pub fn ensure_wifi_connected(
-    wifi: &mut BlockingWifi<EspWifi<'static>>,
-    config: &WifiConfig,
-) -> Result<(), EspError> {
-    if wifi.is_connected()? {
-        return Ok(());
+/// GPIO numbers already handed out, one bit per pin
+static CLAIMED_PINS: Mutex<u32> = Mutex::new(0);
+
+/// pins of the ESP32-C3 that are not free on this board: internal led,
+/// both relays, SPI flash and USB
+const RESERVED_PINS: [u8; 12] = [2, 5, 6, 11, 12, 13, 14, 15, 16, 17, 18, 19];
+const MAX_PIN: u8 = 21;
+
+/// GPIO number that exists and is free on this board
+pub fn check_pin(num: u8) -> anyhow::Result<()> {
+    match num > MAX_PIN || RESERVED_PINS.contains(&num) {
+        true => Err(Error::msg(format!("gpio{} is not available", num))),
+        false => Ok(()),
     }
+}
+
+/// Claim a GPIO by number so configured inputs never share a pin.
+pub fn claim_pin(num: u8) -> anyhow::Result<()> {
+    check_pin(num)?;
 
-    connect_wifi(wifi, config)?;
-    let ip_info = wifi.wifi().sta_netif().get_ip_info();
-    info!("Wifi DHCP info: {:?}", ip_info);
+    let mut claimed = CLAIMED_PINS.lock().unwrap();
+    if *claimed & (1 << num) != 0 {
+        return Err(Error::msg(format!("gpio{} already in use", num)));
+    }
+    *claimed |= 1 << num;
     Ok(())
 }
 
-pub fn connect_wifi(
-    wifi: &mut BlockingWifi<EspWifi<'static>>,
-    config: &WifiConfig,
-) -> Result<(), EspError> {
-    let wifi_configuration: Configuration = Configuration::Client(ClientConfiguration {
-        ssid: config.ssid.as_str().try_into().unwrap(),
-        bssid: None,
-        auth_method: AuthMethod::WPA2Personal,
-        password: config.password.as_str().try_into().unwrap(),
-        channel: None,
-        ..Default::default()
-    });
-
-    wifi.set_configuration(&wifi_configuration)?;
-
-    wifi.start()?;
-    wifi.connect()?;
-    wifi.wait_netif_up()?;
-    info!("Wifi connected");
-    info!("Wifi netif up");
+/// input pin from a configured GPIO number
+pub fn input_pin(num: u8) -> anyhow::Result<AnyInputPin> {
+    claim_pin(num)?;
+    // SAFETY: claim_pin hands out every free pin once and refuses the
+    // pins taken from `Peripherals` in main
+    Ok(unsafe { AnyInputPin::new(num as i32) })
+}
 
-    Ok(())
+/// input/output pin from a configured GPIO number, e.g. for a 1-Wire bus
+pub fn io_pin(num: u8) -> anyhow::Result<AnyIOPin> {
+    claim_pin(num)?;
+    // SAFETY: see `input_pin`
+    Ok(unsafe { AnyIOPin::new(num as i32) })
 }
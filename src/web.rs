@@ -0,0 +1,223 @@
+use anyhow::Error;
+use embedded_svc::http::Method;
+use embedded_svc::io::Write;
+use embedded_svc::ws::FrameType;
+use esp_idf_svc::http::server::ws::{EspHttpWsConnection, EspHttpWsDetachedSender};
+use esp_idf_svc::http::server::{Configuration, EspHttpServer};
+use esp_idf_svc::sys::EspError;
+use log::{info, warn};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::api::{
+    self, ApiBackend, ApiConfig, ApiMethod, ApiRequest, ApiResponse, ApiToken, LiveStream,
+};
+
+type ApiCall = (ApiRequest, SyncSender<ApiResponse>);
+
+/// client of the live stream
+struct Subscriber {
+    session: i32,
+    /// shared so a broadcast sends without holding the list
+    sender: Arc<Mutex<EspHttpWsDetachedSender>>,
+    /// sent a valid token as first message
+    authorized: bool,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    list: Vec<Subscriber>,
+    /// a client was authorized and waits for its first snapshot
+    joined: bool,
+}
+
+/// HTTP server of the local API and dashboard. Requests are read on the
+/// server task and answered by the main loop, which owns the relays.
+pub struct WebServer<'cfg> {
+    config: &'cfg ApiConfig,
+    _server: EspHttpServer<'static>,
+    rx: Receiver<ApiCall>,
+    subscribers: Arc<Mutex<Subscribers>>,
+    live: LiveStream,
+}
+
+impl<'cfg> WebServer<'cfg> {
+    const MAX_BODY: usize = 512;
+    const MAX_SUBSCRIBERS: usize = 4;
+    /// the main loop polls often, a longer wait means it is stuck
+    const REPLY_TIMEOUT: Duration = Duration::from_secs(15);
+
+    pub fn start(config: &'cfg ApiConfig) -> anyhow::Result<Self> {
+        let mut server = EspHttpServer::new(&Configuration {
+            http_port: config.port,
+            uri_match_wildcard: true,
+            ..Default::default()
+        })?;
+
+        let subscribers = Arc::new(Mutex::new(Subscribers::default()));
+        let ws_subscribers = subscribers.clone();
+        let tokens = config.tokens.clone();
+        // registered before the wildcard handlers, which would match it
+        server.ws_handler("/api/live", move |ws| {
+            let mut subs = ws_subscribers.lock().unwrap();
+            live_session(ws, &mut subs, &tokens)
+        })?;
+
+        let (tx, rx) = mpsc::channel();
+        for method in [Method::Get, Method::Post] {
+            let tx: Sender<ApiCall> = tx.clone();
+            server.fn_handler("/*", method, move |mut req| -> anyhow::Result<()> {
+                let mut request = ApiRequest {
+                    method: match req.method() {
+                        Method::Get => ApiMethod::Get,
+                        Method::Post => ApiMethod::Post,
+                        _ => ApiMethod::Other,
+                    },
+                    path: req.uri().to_owned(),
+                    authorization: req.header("Authorization").map(str::to_owned),
+                    body: Vec::new(),
+                };
+
+                let mut buf = [0u8; 128];
+                let response = loop {
+                    let n = req.read(&mut buf)?;
+                    if n == 0 {
+                        break None;
+                    }
+                    request.body.extend_from_slice(&buf[..n]);
+                    if request.body.len() > Self::MAX_BODY {
+                        break Some(ApiResponse::error(413, "body too large"));
+                    }
+                };
+
+                let response = match response {
+                    Some(response) => response,
+                    None => {
+                        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+                        tx.send((request, reply_tx))
+                            .map_err(|_| Error::msg("api receiver dropped"))?;
+                        reply_rx
+                            .recv_timeout(Self::REPLY_TIMEOUT)
+                            .unwrap_or_else(|_| ApiResponse::error(503, "device busy"))
+                    }
+                };
+
+                let headers = [("Content-Type", response.content_type)];
+                let mut resp = req.into_response(response.status, None, &headers)?;
+                resp.write_all(&response.body)?;
+                Ok(())
+            })?;
+        }
+        info!("http api on port {}", config.port);
+
+        Ok(Self {
+            config,
+            _server: server,
+            rx,
+            subscribers,
+            live: LiveStream::default(),
+        })
+    }
+
+    /// answer pending requests, called from the main loop
+    pub fn serve(&self, backend: &mut dyn ApiBackend) {
+        while let Ok((request, reply)) = self.rx.try_recv() {
+            let response = api::handle(&request, &self.config.tokens, backend);
+            // the server task gave up waiting when the send fails
+            let _ = reply.send(response);
+        }
+    }
+
+    /// Push a snapshot to the live stream clients when it changed. A send
+    /// waits on the server task, which runs the WebSocket handler locking
+    /// the subscribers, so they are not locked while sending.
+    pub fn broadcast(&mut self, backend: &dyn ApiBackend, now: u64) {
+        let senders: Vec<_> = {
+            let mut subs = self.subscribers.lock().unwrap();
+            if subs.joined {
+                subs.joined = false;
+                self.live.resync();
+            }
+            subs.list
+                .iter()
+                .filter(|s| s.authorized)
+                .map(|s| (s.session, s.sender.clone()))
+                .collect()
+        };
+        if senders.is_empty() {
+            return;
+        }
+
+        let Some(message) = self.live.update(backend, now) else {
+            return;
+        };
+        let dropped: Vec<i32> = senders
+            .into_iter()
+            .filter_map(|(session, sender)| {
+                let sent = sender
+                    .lock()
+                    .unwrap()
+                    .send(FrameType::Text(false), message.as_bytes());
+                let err = sent.err()?;
+                warn!("live stream session {} dropped: {}", session, err);
+                Some(session)
+            })
+            .collect();
+        if !dropped.is_empty() {
+            let mut subs = self.subscribers.lock().unwrap();
+            subs.list.retain(|s| !dropped.contains(&s.session));
+        }
+    }
+}
+
+/// WebSocket events of `/api/live`. Browsers cannot set headers on a
+/// WebSocket, so the first message must be the token.
+fn live_session(
+    ws: &mut EspHttpWsConnection,
+    subs: &mut Subscribers,
+    tokens: &[ApiToken],
+) -> Result<(), EspError> {
+    let session = ws.session();
+    if ws.is_new() {
+        if subs.list.len() >= WebServer::MAX_SUBSCRIBERS {
+            return ws.send(FrameType::Close, &[]);
+        }
+        subs.list.push(Subscriber {
+            session,
+            sender: Arc::new(Mutex::new(ws.create_detached_sender()?)),
+            authorized: false,
+        });
+        return Ok(());
+    }
+    if ws.is_closed() {
+        subs.list.retain(|s| s.session != session);
+        return Ok(());
+    }
+
+    let mut buf = [0u8; 128];
+    let (frame, len) = ws.recv(&mut buf)?;
+    let Some(sub) = subs.list.iter_mut().find(|s| s.session == session) else {
+        return Ok(());
+    };
+    if sub.authorized || !matches!(frame, FrameType::Text(_)) {
+        return Ok(());
+    }
+
+    let token = buf
+        .get(..len)
+        .and_then(|b| std::str::from_utf8(b).ok())
+        .and_then(|t| api::find_token(tokens, t));
+    match token {
+        Some(token) => {
+            info!("live stream session {} of {}", session, token.name);
+            sub.authorized = true;
+            subs.joined = true;
+            Ok(())
+        }
+        None => {
+            subs.list.retain(|s| s.session != session);
+            ws.send(FrameType::Close, &[])
+        }
+    }
+}
@@ -0,0 +1,448 @@
+use anyhow::Error;
+use esp_idf_svc::ipv4::{self, ClientSettings, Mask, Subnet};
+use esp_idf_svc::netif::{EspNetif, NetifConfiguration};
+use esp_idf_svc::wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi};
+use log::{info, warn};
+use serde::Deserialize;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::util::device_id;
+
+#[derive(Deserialize, Debug)]
+pub struct WifiConfig {
+    /// known networks, the portal adds to them
+    #[serde(default)]
+    pub networks: Vec<NetworkConfig>,
+    /// failed passes over the networks at boot before the provisioning
+    /// portal starts, 0 never starts it
+    #[serde(default = "WifiConfig::default_provision_after")]
+    pub provision_after: u32,
+    /// button held 5 s to start the provisioning portal
+    #[serde(default)]
+    pub provision_pin: Option<u8>,
+    /// WPA2 password of the portal access point, open when missing
+    #[serde(default)]
+    pub ap_password: Option<String>,
+    /// name sent with DHCP and announced as `{hostname}.local`,
+    /// `pomel-{id}` from the MAC when missing
+    #[serde(default)]
+    hostname: Option<String>,
+    /// announce the hostname and the local API over mDNS
+    #[serde(default = "WifiConfig::default_mdns")]
+    pub mdns: bool,
+}
+
+impl WifiConfig {
+    fn default_provision_after() -> u32 {
+        10
+    }
+
+    fn default_mdns() -> bool {
+        true
+    }
+
+    pub fn hostname(&self) -> anyhow::Result<String> {
+        match &self.hostname {
+            Some(hostname) => Ok(hostname.clone()),
+            None => Ok(format!("pomel-{}", device_id()?)),
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for network in &self.networks {
+            network
+                .validate()
+                .map_err(|e| Error::msg(format!("network {}: {}", network.ssid, e)))?;
+        }
+        if let Some(password) = &self.ap_password {
+            if !(8..=63).contains(&password.len()) {
+                return Err(Error::msg("ap_password must be 8 to 63 bytes"));
+            }
+        }
+        if let Some(hostname) = &self.hostname {
+            let bytes = hostname.as_bytes();
+            if bytes.is_empty()
+                || bytes.len() > 30
+                || bytes.first() == Some(&b'-')
+                || bytes.last() == Some(&b'-')
+                || !bytes
+                    .iter()
+                    .all(|b| b.is_ascii_alphanumeric() || *b == b'-')
+            {
+                return Err(Error::msg(
+                    "hostname must be 1 to 30 letters, digits or inner hyphens",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// station interface with the hostname, and the static address when set
+pub fn sta_netif(hostname: &str, static_ip: Option<&StaticIpConfig>) -> anyhow::Result<EspNetif> {
+    let mut conf = NetifConfiguration::wifi_default_client();
+    if let Some(static_ip) = static_ip {
+        conf.ip_configuration = Some(ipv4::Configuration::Client(
+            ipv4::ClientConfiguration::Fixed(static_ip.settings()?),
+        ));
+    }
+    let mut netif = EspNetif::new_with_conf(&conf)?;
+    netif.set_hostname(hostname)?;
+    Ok(netif)
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct StaticIpConfig {
+    address: Ipv4Addr,
+    gateway: Ipv4Addr,
+    #[serde(default = "StaticIpConfig::default_netmask")]
+    netmask: Ipv4Addr,
+    /// at most two servers, the gateway when empty
+    #[serde(default)]
+    dns: Vec<Ipv4Addr>,
+}
+
+impl StaticIpConfig {
+    fn default_netmask() -> Ipv4Addr {
+        Ipv4Addr::new(255, 255, 255, 0)
+    }
+
+    /// checked settings of the station interface
+    fn settings(&self) -> anyhow::Result<ClientSettings> {
+        let mask = u32::from(self.netmask);
+        let prefix = mask.leading_ones();
+        if mask.checked_shl(prefix).unwrap_or(0) != 0 || !(1..=30).contains(&prefix) {
+            return Err(Error::msg(format!("invalid netmask {}", self.netmask)));
+        }
+        let (address, gateway) = (u32::from(self.address), u32::from(self.gateway));
+        if address & mask != gateway & mask || address == gateway {
+            return Err(Error::msg("gateway must be another address of the subnet"));
+        }
+        if self.dns.len() > 2 {
+            return Err(Error::msg("at most two dns servers"));
+        }
+
+        Ok(ClientSettings {
+            ip: self.address,
+            subnet: Subnet {
+                gateway: self.gateway,
+                mask: Mask(prefix as u8),
+            },
+            dns: Some(self.dns.first().copied().unwrap_or(self.gateway)),
+            secondary_dns: self.dns.get(1).copied(),
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NetworkConfig {
+    pub ssid: String,
+    #[serde(default)]
+    password: String,
+    /// higher is tried first, equal priorities by signal strength
+    #[serde(default)]
+    priority: u8,
+    #[serde(default)]
+    auth: WifiAuth,
+    /// only join this access point, e.g. "aa:bb:cc:dd:ee:ff"
+    #[serde(default)]
+    bssid: Option<String>,
+    #[serde(default)]
+    channel: Option<u8>,
+    /// fixed address on this network instead of DHCP
+    #[serde(default)]
+    static_ip: Option<StaticIpConfig>,
+}
+
+impl NetworkConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.ssid.is_empty() || self.ssid.len() > 32 {
+            return Err(Error::msg("ssid must be 1 to 32 bytes"));
+        }
+        // WPA passphrase of 8 to 63 characters or 64 hex digits
+        match self.auth {
+            WifiAuth::Open if !self.password.is_empty() => {
+                return Err(Error::msg("open network with a password"));
+            }
+            WifiAuth::Wpa2 | WifiAuth::Wpa3 if !(8..=64).contains(&self.password.len()) => {
+                return Err(Error::msg("password must be 8 to 64 bytes"));
+            }
+            _ => {}
+        }
+        if let Some(bssid) = &self.bssid {
+            parse_bssid(bssid).ok_or(Error::msg("bssid must look like aa:bb:cc:dd:ee:ff"))?;
+        }
+        if let Some(channel) = self.channel {
+            if !(1..=13).contains(&channel) {
+                return Err(Error::msg("channel must be 1 to 13"));
+            }
+        }
+        if let Some(static_ip) = &self.static_ip {
+            static_ip
+                .settings()
+                .map_err(|e| Error::msg(format!("static_ip: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    fn client(&self, channel: Option<u8>) -> anyhow::Result<Configuration> {
+        Ok(Configuration::Client(ClientConfiguration {
+            ssid: self
+                .ssid
+                .as_str()
+                .try_into()
+                .map_err(|_| Error::msg("ssid too long"))?,
+            bssid: self.bssid.as_deref().and_then(parse_bssid),
+            auth_method: self.auth.method(),
+            password: self
+                .password
+                .as_str()
+                .try_into()
+                .map_err(|_| Error::msg("password too long"))?,
+            channel: self.channel.or(channel),
+            ..Default::default()
+        }))
+    }
+}
+
+/// weakest security accepted, "wpa2" also joins WPA2/WPA3 networks
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WifiAuth {
+    #[default]
+    Wpa2,
+    Wpa3,
+    Open,
+}
+
+impl WifiAuth {
+    fn method(self) -> AuthMethod {
+        match self {
+            WifiAuth::Wpa2 => AuthMethod::WPA2Personal,
+            WifiAuth::Wpa3 => AuthMethod::WPA3Personal,
+            WifiAuth::Open => AuthMethod::None,
+        }
+    }
+}
+
+fn parse_bssid(s: &str) -> Option<[u8; 6]> {
+    let mut bssid = [0u8; 6];
+    let mut parts = s.split(':');
+    for byte in bssid.iter_mut() {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    match parts.next() {
+        Some(_) => None,
+        None => Some(bssid),
+    }
+}
+
+/// Station connection over the known networks. A pass scans, then tries
+/// the networks in range by priority and signal, and the ones not seen,
+/// e.g. hidden, last. Failed passes wait longer before the next.
+pub struct WifiLink<'cfg> {
+    config: &'cfg WifiConfig,
+    hostname: String,
+    failures: u32,
+    retry_at: Option<Instant>,
+    /// networks left to try in this pass with their scanned signal and
+    /// channel, the next one last
+    pending: Vec<(&'cfg NetworkConfig, Option<(i8, u8)>)>,
+    network: Option<&'cfg NetworkConfig>,
+    /// address of the station interface, DHCP at boot
+    static_ip: Option<&'cfg StaticIpConfig>,
+}
+
+impl<'cfg> WifiLink<'cfg> {
+    const MAX_BACKOFF: Duration = Duration::from_secs(120);
+
+    /// `hostname` as set on the station interface
+    pub fn new(config: &'cfg WifiConfig, hostname: &str) -> Self {
+        Self {
+            config,
+            hostname: hostname.to_owned(),
+            failures: 0,
+            retry_at: None,
+            pending: Vec::new(),
+            network: None,
+            static_ip: None,
+        }
+    }
+
+    /// failed passes since the last connection
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// pause after the failed passes so far, doubling up to `MAX_BACKOFF`
+    pub fn backoff(&self) -> Duration {
+        match self.failures {
+            0 => Duration::ZERO,
+            n => Duration::from_secs(1 << (n - 1).min(7)).min(Self::MAX_BACKOFF),
+        }
+    }
+
+    /// network part of `/status`
+    pub fn report(&self, wifi: &BlockingWifi<EspWifi<'static>>) -> String {
+        let network = match self.network {
+            Some(network) if wifi.is_connected().unwrap_or(false) => network,
+            _ => return format!("Wi-Fi disconnected, {} failed passes", self.failures),
+        };
+        let mode = match network.static_ip {
+            Some(_) => "static",
+            None => "DHCP",
+        };
+
+        let netif = wifi.wifi().sta_netif();
+        let mut lines = vec![format!("Wi-Fi {}", network.ssid)];
+        if let Ok(hostname) = netif.get_hostname() {
+            lines.push(format!("Hostname {}", &*hostname));
+        }
+        match netif.get_ip_info() {
+            Ok(info) => {
+                lines.push(format!(
+                    "IP {}/{} ({}), gateway {}",
+                    info.ip, info.subnet.mask.0, mode, info.subnet.gateway
+                ));
+                let dns: Vec<String> = info
+                    .dns
+                    .into_iter()
+                    .chain(info.secondary_dns)
+                    .map(|d| d.to_string())
+                    .collect();
+                if !dns.is_empty() {
+                    lines.push(format!("DNS {}", dns.join(", ")));
+                }
+            }
+            Err(err) => lines.push(format!("IP unknown: {}", err)),
+        }
+        lines.join("\n")
+    }
+
+    /// Reconnect when the connection dropped and the backoff passed. Each
+    /// call tries one network so the main loop is never held for a pass.
+    pub fn ensure_connected(
+        &mut self,
+        wifi: &mut BlockingWifi<EspWifi<'static>>,
+    ) -> anyhow::Result<()> {
+        if wifi.is_connected()? {
+            return Ok(());
+        }
+        self.network = None;
+        if let Some(retry_at) = self.retry_at {
+            let left = retry_at.saturating_duration_since(Instant::now());
+            if !left.is_zero() {
+                return Err(Error::msg(format!("wifi retry in {}s", left.as_secs())));
+            }
+        }
+        self.try_next(wifi)
+    }
+
+    /// Try the next network of the pass, a new pass starts with a scan.
+    /// Only a pass without any connection counts as a failure.
+    fn try_next(&mut self, wifi: &mut BlockingWifi<EspWifi<'static>>) -> anyhow::Result<()> {
+        if self.pending.is_empty() {
+            if let Err(err) = self.scan(wifi) {
+                return self.pass_failed(err);
+            }
+        }
+        let Some((network, best)) = self.pending.pop() else {
+            return self.pass_failed(Error::msg("no wifi network configured"));
+        };
+
+        match best {
+            Some((rssi, _)) => info!("Connecting wifi {} ({} dBm)", network.ssid, rssi),
+            None => info!("Connecting wifi {}, not seen in scan", network.ssid),
+        }
+        match self.join(wifi, network, best.map(|b| b.1)) {
+            Ok(()) => {
+                self.failures = 0;
+                self.retry_at = None;
+                self.pending.clear();
+                self.network = Some(network);
+                let ip_info = wifi.wifi().sta_netif().get_ip_info();
+                info!("Wifi {} connected: {:?}", network.ssid, ip_info);
+                Ok(())
+            }
+            Err(err) => {
+                warn!("wifi {} failed: {}", network.ssid, err);
+                let _ = wifi.disconnect();
+                match self.pending.is_empty() {
+                    true => self.pass_failed(Error::msg("no known wifi network reachable")),
+                    false => Err(Error::msg(format!(
+                        "wifi {} failed, trying the next",
+                        network.ssid
+                    ))),
+                }
+            }
+        }
+    }
+
+    fn pass_failed(&mut self, err: Error) -> anyhow::Result<()> {
+        self.failures += 1;
+        self.retry_at = Some(Instant::now() + self.backoff());
+        Err(err)
+    }
+
+    /// start a pass with the known networks ordered by the scan
+    fn scan(&mut self, wifi: &mut BlockingWifi<EspWifi<'static>>) -> anyhow::Result<()> {
+        if self.config.networks.is_empty() {
+            return Err(Error::msg("no wifi network configured"));
+        }
+        if !wifi.is_started()? {
+            wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+            wifi.start()?;
+        }
+        let seen = wifi.scan().unwrap_or_else(|err| {
+            warn!("wifi scan error: {}", err);
+            Vec::new()
+        });
+
+        // strongest access point in range of every network
+        let mut candidates: Vec<(&'cfg NetworkConfig, Option<(i8, u8)>)> = self
+            .config
+            .networks
+            .iter()
+            .map(|network| {
+                let bssid = network.bssid.as_deref().and_then(parse_bssid);
+                let best = seen
+                    .iter()
+                    .filter(|ap| {
+                        let ssid: &str = &ap.ssid;
+                        ssid == network.ssid && (bssid.is_none() || bssid == Some(ap.bssid))
+                    })
+                    .map(|ap| (ap.signal_strength, ap.channel))
+                    .max();
+                (network, best)
+            })
+            .collect();
+        candidates.sort_by_key(|(network, best)| {
+            std::cmp::Reverse((best.is_some(), network.priority, best.map(|b| b.0)))
+        });
+        // the best candidate last, taken first
+        candidates.reverse();
+        self.pending = candidates;
+        Ok(())
+    }
+
+    /// Join `network`, first giving the station interface the address of
+    /// that network when it differs from the current one.
+    fn join(
+        &mut self,
+        wifi: &mut BlockingWifi<EspWifi<'static>>,
+        network: &'cfg NetworkConfig,
+        channel: Option<u8>,
+    ) -> anyhow::Result<()> {
+        let static_ip = network.static_ip.as_ref();
+        if static_ip != self.static_ip {
+            let netif = sta_netif(&self.hostname, static_ip)?;
+            wifi.wifi_mut().swap_netif_sta(netif)?;
+            self.static_ip = static_ip;
+        }
+        wifi.set_configuration(&network.client(channel)?)?;
+        wifi.connect()?;
+        wifi.wait_netif_up()?;
+        Ok(())
+    }
+}
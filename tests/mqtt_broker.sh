@@ -0,0 +1,51 @@
+#!/bin/sh
+# Integration test of the MQTT transport against a local broker, e.g.
+# `mosquitto -v`, with a flashed device whose [mqtt] url points at it.
+#
+#   BROKER=192.168.1.10 RELAY=pompa_air tests/mqtt_broker.sh
+#
+# The relay must be off and allowed to run for one minute (min_on at
+# most 60, no interlock). The test waits for the deadline, about 70 s.
+set -eu
+
+BROKER=${BROKER:-localhost}
+PORT=${PORT:-1883}
+PREFIX=${PREFIX:-pomel}
+RELAY=${RELAY:-pompa_air}
+OUT=$(mktemp)
+trap 'rm -f "$OUT"' EXIT
+
+sub() {
+    mosquitto_sub -h "$BROKER" -p "$PORT" "$@"
+}
+
+fail() {
+    echo "FAIL: $*"
+    exit 1
+}
+
+# retained device status
+status=$(sub -t "$PREFIX/status" -C 1 -W 10) || fail "no $PREFIX/status"
+[ "$status" = "online" ] || fail "status is $status"
+echo "ok: device online"
+
+# the command outcome, the state when switched on and at the deadline
+sub -v -q 1 -t "$PREFIX/relay/$RELAY/result" -t "$PREFIX/relay/$RELAY/state" \
+    -W 80 >"$OUT" &
+listener=$!
+sleep 1
+mosquitto_pub -h "$BROKER" -p "$PORT" -q 1 -t "$PREFIX/relay/$RELAY/set" -m "on for 1m"
+wait "$listener" || true
+
+grep -q "^$PREFIX/relay/$RELAY/result " "$OUT" || fail "no command result"
+! grep -q "^$PREFIX/relay/$RELAY/result rejected" "$OUT" ||
+    fail "$(grep "/result " "$OUT")"
+echo "ok: command accepted"
+
+grep -q "^$PREFIX/relay/$RELAY/state .*\"on\":true" "$OUT" ||
+    fail "no state published when switched on"
+echo "ok: state published on start"
+
+grep "^$PREFIX/relay/$RELAY/state " "$OUT" | tail -n 1 | grep -q '"on":false' ||
+    fail "no state published at the deadline"
+echo "ok: state published at the deadline"